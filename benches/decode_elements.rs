@@ -0,0 +1,64 @@
+//! Time `decode_elements` on a file with many large layers
+//!
+//! Run once without and once with the `parallel` feature and compare the times:
+//!
+//! ```text
+//! cargo bench --bench decode_elements
+//! cargo bench --bench decode_elements --features parallel
+//! ```
+//!
+//! The number of layers and their size can be changed with the `SKORA_BENCH_LAYERS` (default 16)
+//! and `SKORA_BENCH_SIZE` (default 4096, giving 4096x4096 layers) environment variables.
+//!
+
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, ImageBuffer, Rgba};
+
+use skora::tiff::read_tiff;
+use skora::{decode_elements, uncompressed_tiff, ConvertOptions};
+
+/// Number of timed runs
+const RUNS: u32 = 5;
+
+/// Read a size from an environment variable
+fn env_size(name: &str, default: usize) -> usize {
+    std::env::var(name).ok().and_then(|val| val.parse().ok()).unwrap_or(default)
+}
+
+fn main() {
+    let layers = env_size("SKORA_BENCH_LAYERS", 16);
+    let size = env_size("SKORA_BENCH_SIZE", 4096) as u32;
+
+    // every layer shares the same uncompressed RGBA image so only one copy is held in memory
+    let layer = ImageBuffer::from_fn(size, size, |x, y| {
+        Rgba([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8, (x ^ y) as u8])
+    });
+    let file = uncompressed_tiff(&DynamicImage::ImageRgba8(layer)).unwrap();
+    let (_, ifds) = read_tiff(&file).unwrap();
+    let ifds = vec![ifds[0].clone(); layers];
+    let images = vec![file.clone(); layers];
+    let options = ConvertOptions::default();
+
+    decode_elements(&ifds, &images, &file, &options).unwrap();
+    let mut total = Duration::default();
+    let mut fastest = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        let (elements, _) = decode_elements(&ifds, &images, &file, &options).unwrap();
+        let elapsed = start.elapsed();
+        assert_eq!(elements.len(), layers);
+        total += elapsed;
+        fastest = fastest.min(elapsed);
+    }
+
+    println!(
+        "{} {}x{} layers ({}), {} runs",
+        layers,
+        size,
+        size,
+        if cfg!(feature = "parallel") { "parallel" } else { "serial" },
+        RUNS
+    );
+    println!("mean {:?}, fastest {:?}", total / RUNS, fastest);
+}
@@ -0,0 +1,61 @@
+//! Time `try_load_ifd_image` reading an uncompressed RGBA layer straight from its strips against
+//! decoding the same layer with the `image` crate's tiff decoder
+//!
+//! Run with `cargo bench --bench load_ifd_image`.  The layer size can be changed with the
+//! `SKORA_BENCH_SIZE` environment variable (default 2048, giving a 2048x2048 layer).
+//!
+
+use std::time::{Duration, Instant};
+
+use image::{DynamicImage, ImageBuffer, Rgba};
+
+use skora::tiff::read_tiff;
+use skora::{try_load_ifd_image, uncompressed_tiff};
+
+/// Number of timed runs of each decoder
+const RUNS: u32 = 20;
+
+/// Run a closure `RUNS` times after a warm up run and return the mean and fastest run
+fn time<F: FnMut()>(mut run: F) -> (Duration, Duration) {
+    run();
+    let mut total = Duration::default();
+    let mut fastest = Duration::MAX;
+    for _ in 0..RUNS {
+        let start = Instant::now();
+        run();
+        let elapsed = start.elapsed();
+        total += elapsed;
+        fastest = fastest.min(elapsed);
+    }
+    (total / RUNS, fastest)
+}
+
+fn main() {
+    let size: u32 = std::env::var("SKORA_BENCH_SIZE")
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(2048);
+
+    // a gradient so the layer isn't a single repeated pixel
+    let layer = ImageBuffer::from_fn(size, size, |x, y| {
+        Rgba([(x % 256) as u8, (y % 256) as u8, ((x + y) % 256) as u8, (x ^ y) as u8])
+    });
+    let file = uncompressed_tiff(&DynamicImage::ImageRgba8(layer)).unwrap();
+    let (_, ifds) = read_tiff(&file).unwrap();
+    let ifd = &ifds[0];
+
+    let (direct_mean, direct_fastest) = time(|| {
+        try_load_ifd_image(ifd, &file, &file).unwrap();
+    });
+    let (decoder_mean, decoder_fastest) = time(|| {
+        image::load_from_memory(&file).unwrap();
+    });
+
+    println!("{}x{} uncompressed RGBA layer, {} runs", size, size, RUNS);
+    println!("read strips directly: mean {:?}, fastest {:?}", direct_mean, direct_fastest);
+    println!("image tiff decoder:   mean {:?}, fastest {:?}", decoder_mean, decoder_fastest);
+    println!(
+        "speedup: {:.1}x",
+        decoder_mean.as_secs_f64() / direct_mean.as_secs_f64()
+    );
+}
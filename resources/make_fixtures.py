@@ -0,0 +1,145 @@
+"""Write the sample Sketchbook tiffs used by tests/round_trip.rs
+
+Each file holds an 8x6 composite, a 4x3 thumbnail, and three layers laid out the way Sketchbook
+saves them: the composite is the main ifd (marked with the Alias marker in the Software tag) and
+the thumbnail and layers (bottom most layer first) are sub-ifds.  Layers are uncompressed BGRA
+with premultiplied alpha stored bottom-up.
+
+    python3 resources/make_fixtures.py
+"""
+
+import os
+import struct
+
+BYTE, ASCII, SHORT, LONG, RATIONAL, LONG8 = 1, 2, 3, 4, 5, 16
+SIZES = {BYTE: 1, ASCII: 1, SHORT: 2, LONG: 4, RATIONAL: 8, LONG8: 8}
+FORMATS = {BYTE: "B", ASCII: "B", SHORT: "H", LONG: "I", LONG8: "Q"}
+
+WIDTH, HEIGHT = 8, 6
+
+
+class Writer:
+    def __init__(self, endian, big_tiff):
+        self.endian = endian
+        self.big_tiff = big_tiff
+        self.buf = bytearray()
+
+    def pack(self, fmt, *values):
+        return struct.pack(self.endian + fmt, *values)
+
+    def align(self):
+        if len(self.buf) % 2:
+            self.buf.append(0)
+
+    def append(self, data):
+        self.align()
+        offset = len(self.buf)
+        self.buf += data
+        return offset
+
+    def encode(self, datatype, values):
+        if datatype == ASCII:
+            return values.encode("latin-1") + b"\0", len(values) + 1
+        if datatype == RATIONAL:
+            return b"".join(self.pack("II", *val) for val in values), len(values)
+        return b"".join(self.pack(FORMATS[datatype], val) for val in values), len(values)
+
+    def write_ifd(self, tags, strip, sub_ifds=()):
+        """Write the strip, any sub-ifds, then the ifd and return the offset of the ifd"""
+        tags = dict(tags)
+        tags[273] = (LONG, [self.append(strip)])
+        tags[279] = (LONG, [len(strip)])
+        if sub_ifds:
+            offsets = [self.write_ifd(*sub_ifd) for sub_ifd in sub_ifds]
+            tags[330] = (LONG8 if self.big_tiff else LONG, offsets)
+
+        inline = 8 if self.big_tiff else 4
+        entries = []
+        for tag in sorted(tags):
+            datatype, values = tags[tag]
+            data, count = self.encode(datatype, values)
+            if len(data) > inline:
+                value = self.pack("Q" if self.big_tiff else "I", self.append(data))
+            else:
+                value = data + bytes(inline - len(data))
+            count = self.pack("Q" if self.big_tiff else "I", count)
+            entries.append(self.pack("HH", tag, datatype) + count + value)
+
+        count = self.pack("Q" if self.big_tiff else "H", len(entries))
+        next_ifd = bytes(inline)
+        return self.append(count + b"".join(entries) + next_ifd)
+
+    def write(self, main_ifd):
+        if self.big_tiff:
+            header = self.pack("HHHQ", 43, 8, 0, 0)
+        else:
+            header = self.pack("HI", 42, 0)
+        self.buf += (b"II" if self.endian == "<" else b"MM") + header
+        first_ifd = self.write_ifd(*main_ifd)
+        if self.big_tiff:
+            self.buf[8:16] = self.pack("Q", first_ifd)
+        else:
+            self.buf[4:8] = self.pack("I", first_ifd)
+        return bytes(self.buf)
+
+
+def image_tags(width, height, extra_sample):
+    return {
+        256: (LONG, [width]),
+        257: (LONG, [height]),
+        258: (SHORT, [8, 8, 8, 8]),
+        259: (SHORT, [1]),
+        262: (SHORT, [2]),
+        277: (SHORT, [4]),
+        278: (LONG, [height]),
+        284: (SHORT, [1]),
+        338: (SHORT, [extra_sample]),
+    }
+
+
+def rgba_strip(width, height, seed):
+    return bytes(
+        value
+        for y in range(height)
+        for x in range(width)
+        for value in ((x * 30 + seed) % 256, (y * 40 + seed) % 256, (seed * 3) % 256, 255)
+    )
+
+
+def layer(width, height, x_pos, y_pos, opacity, seed, first_pixel=None):
+    tags = image_tags(width, height, 1)
+    tags[254] = (LONG, [0])
+    tags[286] = (RATIONAL, [(x_pos, 1)])
+    tags[287] = (RATIONAL, [(y_pos, 1)])
+    tags[50784] = (ASCII, "%s, 0, 1, 0, 0, 0, 0" % opacity)
+    strip = bytearray(rgba_strip(width, height, seed))
+    if first_pixel is not None:
+        strip[0:4] = first_pixel
+    return (tags, bytes(strip))
+
+
+def sketchbook_tiff(endian, big_tiff):
+    composite = image_tags(WIDTH, HEIGHT, 2)
+    composite[254] = (LONG, [0])
+    composite[305] = (ASCII, "Alias MultiLayer TIFF V1.1")
+    composite[50784] = (ASCII, "3, 2, ffffffff, 1")
+
+    thumbnail = image_tags(4, 3, 2)
+    thumbnail[254] = (LONG, [1])
+
+    sub_ifds = [
+        (thumbnail, rgba_strip(4, 3, 7)),
+        layer(8, 6, 0, 0, "1", 11),
+        layer(4, 3, 2, 1, "1", 23),
+        # the first stored pixel (bottom left once flipped) is red 153, green 102, blue 51 premultiplied by an alpha of 170
+        layer(3, 2, 5, 4, "0.5", 37, bytes([34, 68, 102, 170])),
+    ]
+    main_ifd = (composite, rgba_strip(WIDTH, HEIGHT, 3), sub_ifds)
+    return Writer(endian, big_tiff).write(main_ifd)
+
+
+if __name__ == "__main__":
+    directory = os.path.dirname(os.path.abspath(__file__))
+    for name, endian, big_tiff in [("three_layers_le.tiff", "<", False), ("three_layers_bigtiff.tiff", ">", True)]:
+        with open(os.path.join(directory, name), "wb") as file:
+            file.write(sketchbook_tiff(endian, big_tiff))
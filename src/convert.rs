@@ -0,0 +1,2858 @@
+//! Conversion
+//!
+//! Functions to convert Sketchbook tiff files into Open Raster images.  These need the
+//! filesystem and the `image` crate so are only available with the `std` feature.
+//!
+
+use std::error::Error;
+
+use hex::FromHex;
+use image::load_from_memory;
+use image::buffer::ConvertBuffer;
+use image::codecs::png::{CompressionType, FilterType, PngEncoder};
+use image::{DynamicImage, GenericImageView, ImageBuffer, ImageEncoder, Pixel};
+use pretty_hex::simple_hex;
+use log::{info,debug,trace,warn,error};
+
+use std::convert::TryFrom;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::io::prelude::*;
+
+use crate::decode;
+use crate::endian_rw::Endian;
+use crate::error::SkoraError;
+use crate::ora::{self, Element, Group, LayerNode, Ora, WriteOptions};
+use crate::tiff;
+pub use crate::tiff::{classify_ifd, classify_ifds, is_composite_ifd, is_mask_ifd, is_thumbnail_ifd, IfdKind};
+use crate::tiff_tags;
+use crate::tiff_types::{Data, Ifd, Info};
+
+/// How the background layer of the ORA image is filled
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BackgroundMode {
+    /// Use the background color stored in the Alias Layer Metadata of the tiff
+    #[default]
+    FromTiff,
+    /// Leave the background layer fully transparent
+    Transparent,
+    /// Fill the background layer with the given alpha, red, green, blue color
+    Solid([u8; 4]),
+}
+
+/// How color channels are rounded when removing premultiplied alpha from a layer
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Unpremultiply {
+    /// Round to the nearest value.  This is the mathematically correct result and gives the
+    /// smoothest antialiased edges.
+    #[default]
+    Round,
+    /// Truncate toward zero to match the output of earlier versions of this library
+    Truncate,
+    /// Leave the channels as stored.  Used for layers whose ExtraSamples tag (338) says the alpha is
+    /// not premultiplied.
+    Skip,
+}
+
+impl Unpremultiply {
+    /// Remove the alpha scaling from a single channel
+    ///
+    /// # Arguments
+    ///
+    /// * `value` - Premultiplied channel value
+    /// * `alpha` - Alpha of the pixel between 0 and 1
+    /// * `max` - Largest value the channel can hold
+    ///
+    /// # Returns
+    ///
+    /// * The channel value clamped to `max`, or 0 when the pixel is fully transparent
+    pub fn apply(&self, value: f64, alpha: f64, max: f64) -> f64 {
+        if *self == Unpremultiply::Skip {
+            return value.min(max);
+        }
+        if alpha <= 0.0 {
+            return 0.0;
+        }
+        let scaled = value / alpha;
+        let rounded = match self {
+            Unpremultiply::Round => scaled.round(),
+            Unpremultiply::Truncate | Unpremultiply::Skip => scaled.trunc(),
+        };
+        rounded.min(max)
+    }
+}
+
+/// Order of the channels in each pixel of a layer as stored in the tiff
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum ChannelOrder {
+    /// Blue, green, red, alpha, which is how Sketchbook stores layers
+    #[default]
+    Bgra,
+    /// Alpha, red, green, blue
+    Argb,
+}
+
+impl ChannelOrder {
+    /// Index of the red, green, blue, and alpha channels in a stored pixel
+    ///
+    /// # Example
+    /// ```rust
+    /// assert_eq!(ChannelOrder::Bgra.rgba_indices(), [2, 1, 0, 3]);
+    /// ```
+    pub fn rgba_indices(&self) -> [usize; 4] {
+        match self {
+            ChannelOrder::Bgra => [2, 1, 0, 3],
+            ChannelOrder::Argb => [1, 2, 3, 0],
+        }
+    }
+}
+
+/// How the layers are merged before the ORA image is written
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum MergeStrategy {
+    /// Keep every layer
+    #[default]
+    None,
+    /// Replace every layer, including the background, with a single layer of the visible layers flattened together
+    FlattenAll,
+    /// Drop the hidden layers and flatten the visible paint layers into a single layer above the background
+    FlattenHidden,
+}
+
+/// Whether the composite (merged) image is added as a layer of the ORA image
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum CompositeLayer {
+    /// The composite is only written as `mergedimage.png`
+    #[default]
+    Omit,
+    /// The composite is added as a visible top layer
+    Visible,
+    /// The composite is added as a hidden top layer so it can be toggled on
+    Hidden,
+}
+
+/// Options used to control how a Sketchbook Tiff file is converted
+#[derive(Clone, Debug, Default)]
+pub struct ConvertOptions {
+    /// Write each layer out as a separate tiff file, by default in a `layers` directory next to the input file
+    pub export_tiff: bool,
+    /// Directory the exported layer tiffs are written to.  When None they are written to a `layers`
+    /// directory next to the input file.
+    pub layers_dir: Option<PathBuf>,
+    /// File name of each exported layer tiff, where `{stem}` is replaced with the input file stem and
+    /// `{idx}` with the layer number.  When None `DEFAULT_LAYER_FILE_NAME` is used.
+    pub layer_file_name: Option<String>,
+    /// Decode each exported layer tiff and write it back out uncompressed rather than copying the original
+    /// (possibly compressed) strips.  The files are larger but can be read by any tiff reader, even when the
+    /// source used a compression other programs don't support.  The pixels are written as stored in the
+    /// source so layers are still BGRA with premultiplied alpha.
+    pub reencode_tiff: bool,
+    /// How to fill the background layer
+    pub background: BackgroundMode,
+    /// Leave the background layer out of the ORA image so only the actual paint layers are written
+    pub omit_background: bool,
+    /// Re-read each reconstructed layer tiff and log any image data that doesn't match the source.
+    /// Also logs how closely the flattened layers match the composite image.
+    pub verify: bool,
+    /// Log how long each layer took to decode and encode along with a summary at the end of the conversion
+    pub timings: bool,
+    /// Override whether layers are flipped vertically.  When None the layer's Orientation tag is used,
+    /// falling back to flipping as older versions of Sketchbook store layers bottom-up without the tag.
+    pub flip_layers: Option<bool>,
+    /// Fail the conversion if the tiff contains tags that are not in `tiff_tags::known_tags`.  These may be
+    /// metadata from a newer version of Sketchbook that would otherwise be dropped.
+    pub strict: bool,
+    /// How the premultiplied alpha of the layers is removed
+    pub unpremultiply: Unpremultiply,
+    /// Override the order of the channels in the layers.  When None the position of the alpha sample
+    /// is read from the ExtraSamples tag (338), which is BGRA unless it marks the first sample as alpha.
+    pub channel_order: Option<ChannelOrder>,
+    /// Also add the composite image as the top layer of the ORA image
+    pub composite_layer: CompositeLayer,
+    /// Pad every layer with transparency to the size of the canvas so all layers are positioned at 0, 0.
+    /// The ORA file is larger but works with programs that don't handle layer offsets.
+    pub full_canvas_layers: bool,
+    /// Only decode and include the layers with these layer numbers, which count from 0 at the top most
+    /// layer as in the ORA file.  Layers keep their numbers and the composite, background, and thumbnail
+    /// are always included.  When None every layer is included.
+    pub layer_filter: Option<Vec<usize>>,
+    /// Merge the layers into fewer layers.  Merged layers are flattened at 8 bits per channel.
+    pub merge: MergeStrategy,
+    /// Options controlling how the ORA file is written
+    pub write: WriteOptions,
+    /// Png compression settings for the images in the ORA file
+    pub png: PngCompression,
+}
+
+/// Format a time the way the tiff DateTime tag (306) expects, `YYYY:MM:DD HH:MM:SS` in UTC
+///
+/// # Arguments
+///
+/// * `time` - Time to format
+///
+/// # Returns
+///
+/// * The formatted date and time
+pub fn tiff_date_time(time: SystemTime) -> String {
+    let seconds = time.duration_since(UNIX_EPOCH).map_or(0, |val| val.as_secs());
+    let (days, seconds_of_day) = (seconds / 86400, seconds % 86400);
+
+    // convert days since 1970-01-01 to a civil date (proleptic Gregorian calendar)
+    let shifted = days as i64 + 719_468;
+    let era = shifted.div_euclid(146_097);
+    let day_of_era = shifted.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}:{:02}:{:02} {:02}:{:02}:{:02}",
+        year,
+        month,
+        day,
+        seconds_of_day / 3600,
+        seconds_of_day % 3600 / 60,
+        seconds_of_day % 60
+    )
+}
+
+/// File name template used for exported layer tiffs when `ConvertOptions::layer_file_name` is None
+pub const DEFAULT_LAYER_FILE_NAME: &str = "{stem}_layer_{idx}.tiff";
+
+/// Summary of a conversion, useful when diagnosing problem files
+#[derive(Clone, Debug)]
+pub struct ConversionReport {
+    /// Byte order of the tiff file
+    pub endian: Endian,
+    /// True if the tiff file is in big tiff format, False if classic
+    pub big_tiff: bool,
+    /// Number of IFDs (including sub-IFDs) read from the tiff file
+    pub ifd_count: usize,
+    /// Number of layers written to the ora file, including the background layer
+    pub layer_count: usize,
+    /// Canvas width (in pixels)
+    pub width: u32,
+    /// Canvas height (in pixels)
+    pub height: u32,
+    /// Ifds that could not be decoded and were written as transparent placeholders
+    pub dropped_layers: Vec<DroppedLayer>,
+}
+
+/// Ifd that could not be decoded
+///
+/// The conversion keeps going when an image can't be decoded by writing a small transparent
+/// placeholder in its place, so the ora file is incomplete rather than missing entirely.
+#[derive(Clone, Debug)]
+pub struct DroppedLayer {
+    /// Index of the ifd in the list of IFDs read from the tiff file
+    pub ifd_index: usize,
+    /// Layer number the image was written as in the ora file
+    pub layer_number: usize,
+    /// What the ifd holds
+    pub kind: IfdKind,
+    /// Why the image could not be decoded
+    pub reason: String,
+}
+
+/// Convert a Sketchbook Tiff file to an Open Raster file
+///
+/// The Open Raster file is written next to the input file with an `.ora` extension.
+///
+/// # Arguments
+///
+/// * `file_path_string` - String filepath.  Can be either a tiff file or a directory.
+///   If it is a tiff file, just that file is converted.  If it is a directory, then all
+///   tiff files in the directory will be converted.
+/// * `export_tiff` - Also write each layer out as a separate tiff file
+///
+/// # Returns
+///
+/// * Ok or error
+///
+/// # Example
+///
+/// ```rust
+/// use std::error::Error;
+/// use skora;
+/// fn main() -> Result<(), Box<dyn Error>> {
+///     let path_string = "test.tiff";
+///     println!("Processing file {}", path_string);
+///     skora::convert_file(path_string)?;
+/// }
+/// ```
+pub fn convert_file(file_path_string: String, export_tiff: bool) -> Result<String, Box<dyn Error>> {
+    let file_path = Path::new(&file_path_string);
+    let options = ConvertOptions {
+        export_tiff,
+        ..ConvertOptions::default()
+    };
+    convert_file_to(file_path, &file_path.with_extension("ora"), options)?;
+    Ok("done".to_string())
+}
+
+/// Convert a Sketchbook Tiff file to an Open Raster file written to a chosen path
+///
+/// # Arguments
+///
+/// * `input` - Path to the Sketchbook tiff file
+/// * `output` - Path to write the Open Raster file to.  Parent directories are created as needed.
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * Report describing the converted file or error
+///
+/// # Example
+///
+/// ```rust
+/// use std::path::Path;
+/// let options = skora::ConvertOptions::default();
+/// let report = skora::convert_file_to(Path::new("test.tiff"), Path::new("out/test.ora"), options)?;
+/// println!("{:?} endian, big tiff: {}", report.endian, report.big_tiff);
+/// ```
+pub fn convert_file_to(input: &Path, output: &Path, options: ConvertOptions) -> Result<ConversionReport, SkoraError> {
+    let file = fs::read(input)?;
+
+    let (info, ifds, images) = read_layers(&file, &options)?;
+
+    if options.export_tiff {
+        // get file name without path info
+        let layer_stem = input.file_stem().unwrap().to_str().unwrap();
+
+        // get file path and add 'layers' directory to it unless a directory was given
+        let layer_parent = match &options.layers_dir {
+            Some(dir) => dir.clone(),
+            None => input.parent().unwrap().join("layers"),
+        };
+
+        // create the layers directory if it doesn't exist
+        fs::create_dir_all(&layer_parent)?;
+
+        let template = options.layer_file_name.as_deref().unwrap_or(DEFAULT_LAYER_FILE_NAME);
+        let date_time = tiff_date_time(SystemTime::now());
+
+        // number the exported layers the same as the layers in the ora file
+        for (layer_number, idx) in selected_elements(&ifds, &options) {
+            let image_file = &images[idx];
+
+            // create the file path for this layer
+            let layer_path = layer_parent.join(
+                template
+                    .replace("{stem}", layer_stem)
+                    .replace("{idx}", &layer_number.to_string()),
+            );
+
+            debug!("Writing tiff layer to {:?}",layer_path);
+            let layer_tiff = layer_tiff(&ifds[idx], image_file, &file, options.reencode_tiff, &date_time)?;
+            let mut layer_file = std::fs::File::create(layer_path)?;
+            layer_file.write_all(&layer_tiff)?;
+        }
+    }
+
+    let (ora, dropped_layers) = build_ora(&ifds, &images, &file, &options)?;
+
+    // create the output directory if it doesn't exist
+    if let Some(parent) = output.parent() {
+        if !parent.as_os_str().is_empty() {
+            fs::create_dir_all(parent)?;
+        }
+    }
+    ora.write_with_options(fs::File::create(output)?, &options.write)?;
+    Ok(ConversionReport {
+        endian: info.endian,
+        big_tiff: info.big_tiff,
+        ifd_count: ifds.len(),
+        layer_count: ora.flat_layers().len(),
+        width: ora.width,
+        height: ora.height,
+        dropped_layers,
+    })
+}
+
+/// Make the tiff file a layer is exported as
+///
+/// Either way the file is marked as written by this library with Software (305) and DateTime (306) tags.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd data
+/// * `image_file` - Reference to a tiff image for this ifd
+/// * `source` - The bytes of the original tiff image
+/// * `reencode` - Decode the image and write it uncompressed rather than copying the original strips
+/// * `date_time` - Date and time the file is written as `YYYY:MM:DD HH:MM:SS`
+///
+/// # Returns
+///
+/// * The bytes of the tiff file
+fn layer_tiff(ifd: &Ifd, image_file: &[u8], source: &[u8], reencode: bool, date_time: &str) -> Result<Vec<u8>, SkoraError> {
+    if !reencode {
+        return stamped_tiff(ifd, source, date_time);
+    }
+    let reencoded = uncompressed_tiff(&load_ifd_image(ifd, image_file, source))?;
+    let (_info, reencoded_ifds) = tiff::read_tiff(&reencoded)?;
+    let reencoded_ifd = reencoded_ifds.first().ok_or(SkoraError::NoImageData)?;
+    stamped_tiff(reencoded_ifd, &reencoded, date_time)
+}
+
+/// Copy the image of an ifd into its own tiff file with Software (305) and DateTime (306) tags added
+fn stamped_tiff(ifd: &Ifd, source: &[u8], date_time: &str) -> Result<Vec<u8>, SkoraError> {
+    let mut ifd = ifd.clone();
+    tiff::add_provenance_tags(&mut ifd, tiff::SOFTWARE, date_time);
+    let stamped = tiff::get_layers(vec![ifd], source)?;
+    stamped
+        .into_iter()
+        .next()
+        .filter(|image| !image.is_empty())
+        .ok_or(SkoraError::NoImageData)
+}
+
+/// Convert the bytes of a Sketchbook Tiff file to an Open Raster image without writing it to a file
+///
+/// This does everything `convert_file_to` does other than writing the Open Raster file so the image
+/// can be modified before it is written.  As there is no input path `export_tiff` is ignored.
+///
+/// # Arguments
+///
+/// * `file` - The bytes of the Sketchbook tiff file
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * The Open Raster image
+///
+/// # Example
+///
+/// ```rust
+/// let file = std::fs::read("test.tiff")?;
+/// let ora = skora::tiff_to_ora(&file, skora::ConvertOptions::default())?;
+/// ora.write_to_file(Path::new("test.ora"))?;
+/// ```
+pub fn tiff_to_ora(file: &[u8], options: ConvertOptions) -> Result<Ora, SkoraError> {
+    let (_info, ifds, images) = read_layers(file, &options)?;
+    let (ora, _dropped_layers) = build_ora(&ifds, &images, file, &options)?;
+    Ok(ora)
+}
+
+/// Convert several Sketchbook Tiff files into a single Open Raster image
+///
+/// Each file is converted on its own and its layers (including its background layer) are nested under
+/// a group named after the file stem, so `frames/walk_01.tiff` becomes a group named `walk_01`.  The
+/// groups are stacked in the order the paths are given with the first file on top.  The canvas is
+/// sized to fit the largest file and the merged image, thumbnail, and color profile are taken from
+/// the first file.  Layers are renumbered so every layer in the image has a unique layer number.
+///
+/// # Arguments
+///
+/// * `paths` - Paths to the Sketchbook tiff files
+///
+/// # Returns
+///
+/// * The combined Open Raster image
+///
+/// # Example
+///
+/// ```rust
+/// use std::path::Path;
+/// let ora = skora::convert_files_to_ora(&[Path::new("frame_1.tiff"), Path::new("frame_2.tiff")])?;
+/// ora.write_to_file(Path::new("frames.ora"))?;
+/// ```
+pub fn convert_files_to_ora(paths: &[&Path]) -> Result<Ora, SkoraError> {
+    let mut pages: Vec<(String, Ora)> = Vec::new();
+    for path in paths {
+        let file = fs::read(path)?;
+        let name = path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().to_string())
+            .unwrap_or_default();
+        pages.push((name, tiff_to_ora(&file, ConvertOptions::default())?));
+    }
+
+    let mut ora = Ora::new();
+    ora.width = pages.iter().map(|(_, page)| page.width).max().unwrap_or(0);
+    ora.height = pages.iter().map(|(_, page)| page.height).max().unwrap_or(0);
+    if let Some((_, first)) = pages.first() {
+        ora.merged_image = first.merged_image.clone();
+        ora.thumbnail = first.thumbnail.clone();
+        ora.icc_profile = first.icc_profile.clone();
+    }
+
+    let mut layer_count: usize = 0;
+    for (name, page) in &pages {
+        let mut group = Group::new(name);
+        for layer in page.flat_layers() {
+            let mut layer = layer.clone();
+            layer.layer_number = u8::try_from(layer_count).map_err(|_| SkoraError::TooManyLayers)?;
+            layer_count += 1;
+            // y positions are measured from the bottom so keep the layer the same distance from the top
+            layer.y_pos += ora.height - page.height;
+            group.children.push(LayerNode::Layer(layer));
+        }
+        ora.add_group(group);
+    }
+    Ok(ora)
+}
+
+/// Convert the bytes of a Sketchbook Tiff file to the bytes of an Open Raster file
+///
+/// Nothing is read from or written to the filesystem so `export_tiff` is ignored.
+///
+/// # Arguments
+///
+/// * `file` - The bytes of the Sketchbook tiff file
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * The bytes of the Open Raster file
+///
+/// # Example
+///
+/// ```rust
+/// let file = std::fs::read("test.tiff")?;
+/// let ora_bytes = skora::convert_bytes(&file, skora::ConvertOptions::default())?;
+/// ```
+pub fn convert_bytes(file: &[u8], options: ConvertOptions) -> Result<Vec<u8>, SkoraError> {
+    let write_options = options.write.clone();
+    let ora = tiff_to_ora(file, options)?;
+    let mut buf = std::io::Cursor::new(Vec::new());
+    ora.write_with_options(&mut buf, &write_options)?;
+    Ok(buf.into_inner())
+}
+
+/// Convert a Sketchbook Tiff file read from a stream to the bytes of an Open Raster file
+///
+/// The whole stream is read into memory before converting as the tiff ifds can point anywhere in the file.
+///
+/// # Arguments
+///
+/// * `reader` - Stream holding the Sketchbook tiff file
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * The bytes of the Open Raster file
+///
+/// # Example
+///
+/// ```rust
+/// let file = std::fs::File::open("test.tiff")?;
+/// let ora_bytes = skora::convert_reader(file, skora::ConvertOptions::default())?;
+/// ```
+pub fn convert_reader<R: Read>(mut reader: R, options: ConvertOptions) -> Result<Vec<u8>, SkoraError> {
+    let mut file: Vec<u8> = Vec::new();
+    reader.read_to_end(&mut file)?;
+    convert_bytes(&file, options)
+}
+
+/// Convert the bytes of a Sketchbook Tiff file, passing each piece of the Open Raster image to a sink as it is decoded
+///
+/// Layers are decoded one at a time (even with the `parallel` feature) and handed to the sink before the
+/// next one is decoded, so with a sink that doesn't keep them (such as `ZipOraSink`) a file with many
+/// layers converts using roughly one layer's worth of memory.  Options that need every layer at once
+/// are ignored: `composite_layer` is not added, `merge` is not applied, `verify` is skipped, and a
+/// thumbnail is not made from the composite when the thumbnail ifd is missing.  As there is no input
+/// path `export_tiff` is ignored.
+///
+/// # Arguments
+///
+/// * `file` - The bytes of the Sketchbook tiff file
+/// * `options` - Options controlling the conversion
+/// * `sink` - Receiver of the header, each layer, the composite, and the thumbnail
+///
+/// # Returns
+///
+/// * Summary of the conversion
+///
+/// # Example
+///
+/// ```rust
+/// let file = std::fs::read("test.tiff")?;
+/// let mut sink = skora::ora::ZipOraSink::new(std::fs::File::create("test.ora")?, skora::ora::WriteOptions::default());
+/// let report = skora::convert_streaming(&file, skora::ConvertOptions::default(), &mut sink)?;
+/// ```
+pub fn convert_streaming(
+    file: &[u8],
+    options: ConvertOptions,
+    sink: &mut dyn ora::OraSink,
+) -> Result<ConversionReport, SkoraError> {
+    let (info, ifds, images) = read_layers(file, &options)?;
+    let main = tiff::get_main_ifd(&ifds);
+
+    let mut header = Ora::new();
+    if let Some(main) = main {
+        if let Some((width, height)) = declared_canvas_size(main) {
+            header.width = width;
+            header.height = height;
+        }
+        if let Some(Data::Byte(profile)) = main.tags.get(&34675).map(|tag| &tag.data) {
+            header.icc_profile = Some(profile.clone());
+        }
+        header.document_name = main.tags.get(&269).and_then(|tag| tag.data.as_string()).map(String::from);
+        header.description = main.tags.get(&270).and_then(|tag| tag.data.as_string()).map(String::from);
+    }
+    if options.write.embed_source {
+        header.source = Some(file.to_vec());
+    }
+    sink.begin(&header)?;
+
+    let current_layer = main.and_then(|main| current_layer_number(main, order_layers(&ifds).len()));
+    let (mut width, mut height) = (header.width, header.height);
+    let mut layer_count: usize = 0;
+    let mut timings: Vec<LayerTiming> = Vec::new();
+    let mut dropped_layers: Vec<DroppedLayer> = Vec::new();
+
+    for (layer_number, idx) in selected_elements(&ifds, &options) {
+        let decoded = timed_ora_element(layer_number, idx, &ifds, &images[idx], file, &options)?;
+        timings.push(decoded.timing);
+        dropped_layers.extend(decoded.dropped);
+        match decoded.element {
+            Element::Layer(mut layer) => {
+                // the canvas size is only known up front from the tags
+                if options.full_canvas_layers && width > 0 && height > 0 {
+                    layer = expand_layer_to_canvas(&layer, width, height, options.png.layers)?;
+                }
+                layer.selected = current_layer == Some(layer.layer_number as usize);
+                sink.layer(&layer)?;
+                layer_count += 1;
+            }
+            Element::Composite((merged_image, background)) => {
+                width = background.width;
+                height = background.height;
+                match options.omit_background {
+                    true => sink.composite(&merged_image, None)?,
+                    false => {
+                        sink.composite(&merged_image, Some(&background))?;
+                        layer_count += 1;
+                    }
+                }
+            }
+            Element::Thumbnail(thumbnail) => sink.thumbnail(&thumbnail)?,
+        }
+    }
+    sink.finish()?;
+
+    if options.timings {
+        log_timings(&timings);
+    }
+    if !dropped_layers.is_empty() {
+        warn!("{} of {} images could not be decoded", dropped_layers.len(), timings.len());
+    }
+    Ok(ConversionReport {
+        endian: info.endian,
+        big_tiff: info.big_tiff,
+        ifd_count: ifds.len(),
+        layer_count,
+        width,
+        height,
+        dropped_layers,
+    })
+}
+
+/// Decode every image in a Sketchbook Tiff file
+///
+/// Each image is labeled with what kind of ifd it came from and returned in the order the ifds are
+/// stored in the file.  Layers have their channels swapped to RGBA and their alpha scaling removed,
+/// and every image is rotated / flipped per its orientation so it displays the right way up.  This is
+/// useful for inspecting files with unusual layouts as no ifd is dropped or merged.
+///
+/// # Arguments
+///
+/// * `file` - The bytes of the Sketchbook tiff file
+///
+/// # Returns
+///
+/// * The kind and decoded image of every ifd
+///
+/// # Example
+///
+/// ```rust
+/// let file = std::fs::read("test.tiff")?;
+/// for (kind, image) in skora::extract_all(&file)? {
+///     println!("{:?} {}x{}", kind, image.width(), image.height());
+/// }
+/// ```
+pub fn extract_all(file: &[u8]) -> Result<Vec<(IfdKind, image::RgbaImage)>, SkoraError> {
+    let options = ConvertOptions::default();
+    let (_info, ifds, images) = read_layers(file, &options)?;
+
+    let mut output = Vec::with_capacity(ifds.len());
+    for (ifd, image_file) in ifds.iter().zip(images.iter()) {
+        let kind = classify_ifd(ifd);
+        let image = load_ifd_image(ifd, image_file, file);
+        let image = match kind {
+            IfdKind::Layer if decode::expands_to_rgba(ifd) => {
+                apply_orientation(image.into_rgba8(), layer_orientation(ifd, &options))
+            }
+            IfdKind::Layer => {
+                let bits_per_sample = decode::tag_u32s(ifd, 258)
+                    .and_then(|val| val.first().copied())
+                    .unwrap_or(8);
+                let unpremultiply = layer_unpremultiply(ifd, &options);
+                let order = layer_channel_order(ifd, &options);
+                let image = if bits_per_sample == 16 {
+                    DynamicImage::ImageRgba16(reorder_to_rgba16(image, order, unpremultiply)?).into_rgba8()
+                } else {
+                    reorder_to_rgba(image, order, unpremultiply)?
+                };
+                apply_orientation(image, layer_orientation(ifd, &options))
+            }
+            IfdKind::Composite | IfdKind::Thumbnail | IfdKind::Mask => {
+                let orientation = decode::tag_u32s(ifd, 274)
+                    .and_then(|val| val.first().copied())
+                    .unwrap_or(1);
+                apply_orientation(image.into_rgba8(), orientation)
+            }
+        };
+        output.push((kind, image));
+    }
+    Ok(output)
+}
+
+/// Extract the composite (merged) image of a Sketchbook Tiff file as a png
+///
+/// Only the composite ifd is decoded so this is the fastest way to get the picture when the layers
+/// aren't needed.
+///
+/// # Arguments
+///
+/// * `file` - The bytes of the Sketchbook tiff file
+///
+/// # Returns
+///
+/// * The bytes of the png image or an error if the file has no composite image
+///
+/// # Example
+///
+/// ```rust
+/// let file = std::fs::read("test.tiff")?;
+/// std::fs::write("test.png", skora::extract_composite_png(&file)?)?;
+/// ```
+pub fn extract_composite_png(file: &[u8]) -> Result<Vec<u8>, SkoraError> {
+    let (_info, ifds) = tiff::read_tiff(file)?;
+    let idx = main_ifd_index(&ifds).ok_or(SkoraError::NoImageData)?;
+    let ifd = &ifds[idx];
+
+    let images = tiff::get_layers(vec![ifd.clone()], file)?;
+    let image_file = images.first().ok_or(SkoraError::NoImageData)?;
+    let image = try_load_ifd_image(ifd, image_file, file)?;
+
+    let orientation = decode::tag_u32s(ifd, 274)
+        .and_then(|val| val.first().copied())
+        .unwrap_or(1);
+    image_to_buf(apply_orientation(image.into_rgba8(), orientation), PngOptions::default())
+}
+
+/// Convert the bytes of a Sketchbook Tiff file to the bytes of an Open Raster file from async code
+///
+/// Conversion is CPU bound so calling `convert_bytes` directly from an async task stalls the runtime.
+/// This runs `convert_bytes` with `tokio::task::spawn_blocking` instead.  Must be called from within a
+/// tokio runtime.
+///
+/// # Arguments
+///
+/// * `file` - The bytes of the Sketchbook tiff file
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * The bytes of the Open Raster file
+///
+/// # Example
+///
+/// ```rust
+/// let file = tokio::fs::read("test.tiff").await?;
+/// let ora_bytes = skora::convert_bytes_async(file, skora::ConvertOptions::default()).await?;
+/// ```
+#[cfg(feature = "tokio")]
+pub async fn convert_bytes_async(file: Vec<u8>, options: ConvertOptions) -> Result<Vec<u8>, SkoraError> {
+    tokio::task::spawn_blocking(move || convert_bytes(&file, options))
+        .await
+        .map_err(std::io::Error::from)?
+}
+
+/// Read the ifds of a tiff file and reconstruct a tiff image for each of them
+///
+/// # Arguments
+///
+/// * `file` - The bytes of the Sketchbook tiff file
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * Info about the tiff file, the list of IFDs, and the tiff image for each of the IFDs
+fn read_layers(file: &[u8], options: &ConvertOptions) -> Result<(Info, Vec<Ifd>, Vec<Vec<u8>>), SkoraError> {
+    let (info, ifds) = tiff::read_tiff(file)?;
+    if ifds.is_empty() {
+        return Err(SkoraError::NoImageData);
+    }
+
+    info!("File size : {}", info.size);
+    debug!("Header : {:?}", simple_hex(&info.header));
+    debug!("tiff : {}", info.summary());
+    for ifd in &ifds {
+        debug!("  {}", ifd.summary());
+    }
+    trace!("tiff info : {:#?}",info);
+    trace!("tiff ifds : {:#?}",ifds);
+
+    if options.strict {
+        for ifd in &ifds {
+            if let Some(tag) = ifd.tags.keys().find(|tag| !tiff_tags::is_known_tag(**tag)) {
+                return Err(SkoraError::UnknownTag(*tag));
+            }
+        }
+    }
+
+    // layers left out by the layer filter are not copied out into tiff images, their images are left empty
+    let mut wanted = vec![true; ifds.len()];
+    if let Some(filter) = &options.layer_filter {
+        for (layer_number, idx) in order_layers(&ifds).into_iter().enumerate() {
+            wanted[idx] = filter.contains(&layer_number);
+        }
+    }
+    let wanted_ifds: Vec<Ifd> = ifds
+        .iter()
+        .zip(wanted.iter())
+        .filter(|(_, wanted)| **wanted)
+        .map(|(ifd, _)| ifd.clone())
+        .collect();
+    let mut wanted_images = tiff::get_layers(wanted_ifds, file)?.into_iter();
+    let images: Vec<Vec<u8>> = wanted
+        .iter()
+        .map(|wanted| match wanted {
+            true => wanted_images.next().unwrap_or_default(),
+            false => Vec::new(),
+        })
+        .collect();
+
+    if options.verify {
+        for (ifd, image_file) in ifds.iter().zip(images.iter()).filter(|(_, image_file)| !image_file.is_empty()) {
+            if !tiff::verify_layer(ifd, file, image_file) {
+                warn!("Reconstructed tiff for ifd at {} does not match the source", ifd.offset);
+            }
+        }
+    }
+
+    Ok((info, ifds, images))
+}
+
+/// Decode the images of a tiff file and assemble them into an Open Raster image
+///
+/// # Arguments
+///
+/// * `ifds` - A list of IFDs
+/// * `images` - Tiff image for each of the IFDs
+/// * `source` - The bytes of the original tiff image
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * The Open Raster image and the ifds that were replaced with placeholders
+fn build_ora(
+    ifds: &[Ifd],
+    images: &[Vec<u8>],
+    source: &[u8],
+    options: &ConvertOptions,
+) -> Result<(Ora, Vec<DroppedLayer>), SkoraError> {
+    let mut ora = Ora::default();
+
+    let (elements, dropped_layers) = decode_elements(ifds, images, source, options)?;
+    for element in elements {
+        match element {
+            Element::Thumbnail(val) => {
+                ora.thumbnail = val;
+            }
+            Element::Composite(val) => {
+                ora.width = val.1.width;
+                ora.height = val.1.height;
+                ora.merged_image = val.0;
+                if !options.omit_background {
+                    ora.background = Some(val.1);
+                }
+            }
+            Element::Layer(val) => {
+                ora.add_layer(val);
+            }
+        };
+    }
+
+    // a thumbnail that couldn't be decoded is a 10x10 placeholder so make one from the composite instead
+    let was_dropped = |kind: IfdKind| dropped_layers.iter().any(|dropped| dropped.kind == kind);
+    if (ora.thumbnail.is_empty() || was_dropped(IfdKind::Thumbnail))
+        && !ora.merged_image.is_empty()
+        && !was_dropped(IfdKind::Composite)
+    {
+        match thumbnail_from_png(&ora.merged_image, options.png.thumbnail) {
+            Ok(thumbnail) => {
+                debug!("Using a thumbnail made from the composite image");
+                ora.thumbnail = thumbnail;
+            }
+            Err(error) => warn!("Unable to make a thumbnail from the composite image : {}", error),
+        }
+    }
+
+    if let Some(main) = tiff::get_main_ifd(ifds) {
+        reconcile_canvas(&mut ora, main, options)?;
+        check_layer_resolution(&ora, main);
+    }
+
+    if options.full_canvas_layers {
+        for node in ora.layers.iter_mut() {
+            if let LayerNode::Layer(layer) = node {
+                *layer = expand_layer_to_canvas(layer, ora.width, ora.height, options.png.layers)?;
+            }
+        }
+    }
+
+    let layer_count = order_layers(ifds).len();
+    if let Some(current_layer) = tiff::get_main_ifd(ifds).and_then(|main| current_layer_number(main, layer_count)) {
+        for node in ora.layers.iter_mut() {
+            if let LayerNode::Layer(layer) = node {
+                layer.selected = layer.layer_number as usize == current_layer;
+            }
+        }
+    }
+
+    merge_layers(&mut ora, options)?;
+
+    if options.composite_layer != CompositeLayer::Omit && !ora.merged_image.is_empty() {
+        let layer_number = match ora.flat_layers().iter().map(|layer| layer.layer_number).max() {
+            Some(val) => val.checked_add(1).ok_or(SkoraError::TooManyLayers)?,
+            None => 0,
+        };
+        let mut layer = ora::Layer::new(layer_number, ora.merged_image.clone(), 1.0, 0, 0, ora.width, ora.height);
+        layer.name = Some("Composite".to_string());
+        layer.visible = options.composite_layer == CompositeLayer::Visible;
+        ora.layers.insert(0, LayerNode::Layer(layer));
+    }
+
+    // carry the composite's ICC color profile through to the ora
+    if let Some(composite) = tiff::get_main_ifd(ifds) {
+        if let Some(Data::Byte(profile)) = composite.tags.get(&34675).map(|tag| &tag.data) {
+            debug!("ICC profile : {} bytes", profile.len());
+            ora.icc_profile = Some(profile.clone());
+        }
+        ora.document_name = composite.tags.get(&269).and_then(|tag| tag.data.as_string()).map(String::from);
+        ora.description = composite.tags.get(&270).and_then(|tag| tag.data.as_string()).map(String::from);
+    }
+
+    if options.write.embed_source {
+        ora.source = Some(source.to_vec());
+    }
+
+    if options.verify {
+        match ora.composite_mismatch() {
+            Some(mismatch) => info!("Mean difference between the composite and the flattened layers : {:.3}", mismatch),
+            None => warn!("Unable to compare the composite to the flattened layers"),
+        }
+    }
+    Ok((ora, dropped_layers))
+}
+
+/// Merge the layers of an ORA image according to `options.merge`
+///
+/// The merged layer covers the whole canvas, is named `Merged`, and takes the lowest layer number of
+/// the layers it replaces.  Hidden layers are dropped rather than merged.
+///
+/// # Arguments
+///
+/// * `ora` - The image to merge the layers of
+/// * `options` - Options controlling the conversion
+fn merge_layers(ora: &mut Ora, options: &ConvertOptions) -> Result<(), SkoraError> {
+    // the background is only merged when flattening everything
+    let background = match options.merge {
+        MergeStrategy::None => return Ok(()),
+        MergeStrategy::FlattenAll => None,
+        MergeStrategy::FlattenHidden => ora.background.take(),
+    };
+
+    // hidden layers are skipped by flatten so they are dropped along with the merged layers
+    let replaced = ora.flat_layers();
+    let layer_number = match replaced.iter().map(|layer| layer.layer_number).min() {
+        Some(val) => val,
+        None => {
+            ora.background = background;
+            return Ok(());
+        }
+    };
+    debug!("Merging {} layers into one", replaced.len());
+
+    let mut layer = ora::Layer::new(
+        layer_number,
+        image_to_buf(ora.flatten()?, options.png.layers)?,
+        1.0,
+        0,
+        0,
+        ora.width,
+        ora.height,
+    );
+    layer.name = Some("Merged".to_string());
+
+    ora.layers = vec![LayerNode::Layer(layer)];
+    ora.background = background;
+    Ok(())
+}
+
+/// Get the layer number of the layer that was active when the file was saved
+///
+/// The second field of the composite's Alias Layer Metadata tag (50784) is the CurrentLayer, which is
+/// the index of the active layer counting from the bottom.  Layers are numbered from the top in the
+/// ora file so the index is flipped.
+///
+/// # Arguments
+///
+/// * `main` - Reference to the composite ifd
+/// * `layer_count` - Number of layer ifds in the file
+///
+/// # Returns
+///
+/// * Layer number of the active layer or None if it is missing or out of range
+fn current_layer_number(main: &Ifd, layer_count: usize) -> Option<usize> {
+    let alias = main.tags.get(&50784)?.data.as_string()?;
+    let current_layer: usize = alias.split(", ").nth(1)?.trim().parse().ok()?;
+    if current_layer >= layer_count {
+        warn!("CurrentLayer {} is out of range for {} layers", current_layer, layer_count);
+        return None;
+    }
+    Some(layer_count - 1 - current_layer)
+}
+
+/// Get the displayed canvas size declared by the tags of the composite ifd
+///
+/// Orientations 5 through 8 rotate the image a quarter turn so the declared width and height
+/// are swapped.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the composite ifd
+///
+/// # Returns
+///
+/// * Width and height of the canvas or None if the size tags are missing
+pub fn declared_canvas_size(ifd: &Ifd) -> Option<(u32, u32)> {
+    let width = *ifd.tag_u32s(256)?.first()?;
+    let height = *ifd.tag_u32s(257)?.first()?;
+    let orientation = ifd.tag_u32s(274).and_then(|val| val.first().copied()).unwrap_or(1);
+    match orientation {
+        5..=8 => Some((height, width)),
+        _ => Some((width, height)),
+    }
+}
+
+/// Make the canvas size of the ora agree with the size declared in the composite ifd
+///
+/// When there is no decoded composite the declared size is used.  When the decoded composite is the
+/// declared size turned a quarter turn and the composite's Orientation tag (274) says which way the
+/// canvas is turned (5 or 6 clockwise, 7 or 8 counterclockwise) every image in the ora is turned to fit
+/// the declared (oriented) size and the layers are moved to match.  Without an orientation the direction
+/// isn't known so, like any other mismatch, it is only logged as the decoded composite is trusted over
+/// the tags.
+fn reconcile_canvas(ora: &mut Ora, main: &Ifd, options: &ConvertOptions) -> Result<(), SkoraError> {
+    if let Some((width, height)) = declared_canvas_size(main) {
+        let clockwise = match main.tag_u32s(274).and_then(|val| val.first().copied()) {
+            Some(5) | Some(6) => Some(true),
+            Some(7) | Some(8) => Some(false),
+            _ => None,
+        };
+        if ora.width == 0 || ora.height == 0 {
+            ora.width = width;
+            ora.height = height;
+        } else if let Some(clockwise) = clockwise.filter(|_| (ora.width, ora.height) == (height, width) && width != height) {
+            warn!(
+                "Composite is {}x{} but the tags declare {}x{}, rotating the image to fit the declared canvas",
+                ora.width, ora.height, width, height
+            );
+            if !ora.merged_image.is_empty() {
+                ora.merged_image = rotate_png(&ora.merged_image, clockwise, options.png.composite)?;
+            }
+            if !ora.thumbnail.is_empty() {
+                ora.thumbnail = rotate_png(&ora.thumbnail, clockwise, options.png.thumbnail)?;
+            }
+            // positions are measured from the left and bottom of the canvas
+            let (old_width, old_height) = (ora.width, ora.height);
+            for layer in ora.flat_layers_mut() {
+                layer.image = rotate_png(&layer.image, clockwise, options.png.layers)?;
+                let (x_pos, y_pos) = match clockwise {
+                    true => (layer.y_pos, old_width.saturating_sub(layer.x_pos.saturating_add(layer.width))),
+                    false => (old_height.saturating_sub(layer.y_pos.saturating_add(layer.height)), layer.x_pos),
+                };
+                layer.x_pos = x_pos;
+                layer.y_pos = y_pos;
+                std::mem::swap(&mut layer.width, &mut layer.height);
+            }
+            ora.width = width;
+            ora.height = height;
+        } else if (ora.width, ora.height) != (width, height) {
+            warn!(
+                "Composite is {}x{} but the tags declare {}x{}",
+                ora.width, ora.height, width, height
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Turn a png image a quarter turn, keeping 16 bit images 16 bit
+fn rotate_png(png: &[u8], clockwise: bool, options: PngOptions) -> Result<Vec<u8>, SkoraError> {
+    use image::imageops::{rotate270, rotate90};
+    match load_from_memory(png)? {
+        DynamicImage::ImageRgba16(image) => match clockwise {
+            true => image16_to_buf(rotate90(&image), options),
+            false => image16_to_buf(rotate270(&image), options),
+        },
+        image => {
+            let image = image.into_rgba8();
+            match clockwise {
+                true => image_to_buf(rotate90(&image), options),
+                false => image_to_buf(rotate270(&image), options),
+            }
+        }
+    }
+}
+
+/// Pad a layer with transparency so it covers the whole canvas
+///
+/// The layer's pixels are placed where its offset puts them on the canvas (anything hanging off the
+/// canvas is cropped) and the returned layer is positioned at 0, 0.  16 bit layers stay 16 bit.
+///
+/// # Arguments
+///
+/// * `layer` - The layer to expand
+/// * `width` - Width (in pixels) of the canvas
+/// * `height` - Height (in pixels) of the canvas
+/// * `png` - Png settings used to encode the expanded layer
+///
+/// # Returns
+///
+/// * The canvas sized layer
+///
+/// # Example
+/// ```rust
+/// let full = expand_layer_to_canvas(&layer, ora.width, ora.height, PngOptions::default())?;
+/// assert_eq!((full.width, full.height), (ora.width, ora.height));
+/// ```
+pub fn expand_layer_to_canvas(
+    layer: &ora::Layer,
+    width: u32,
+    height: u32,
+    png: PngOptions,
+) -> Result<ora::Layer, SkoraError> {
+    // y positions are measured from the bottom of the canvas to the bottom of the layer
+    let left = layer.x_pos as i64;
+    let top = height as i64 - layer.y_pos as i64 - layer.height as i64;
+
+    let image = load_from_memory(&layer.image)?;
+    let buf = match image {
+        DynamicImage::ImageRgba16(_)
+        | DynamicImage::ImageRgb16(_)
+        | DynamicImage::ImageLuma16(_)
+        | DynamicImage::ImageLumaA16(_) => {
+            image16_to_buf(place_on_canvas(&image.into_rgba16(), width, height, left, top), png)?
+        }
+        _ => image_to_buf(place_on_canvas(&image.into_rgba8(), width, height, left, top), png)?,
+    };
+
+    let mut output = layer.clone();
+    output.image = buf;
+    output.x_pos = 0;
+    output.y_pos = 0;
+    output.width = width;
+    output.height = height;
+    Ok(output)
+}
+
+/// Copy an image onto a transparent canvas with its top left corner at `left`, `top`
+fn place_on_canvas<P>(
+    input: &ImageBuffer<P, Vec<P::Subpixel>>,
+    width: u32,
+    height: u32,
+    left: i64,
+    top: i64,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + 'static,
+    P::Subpixel: 'static,
+{
+    let mut output: ImageBuffer<P, Vec<P::Subpixel>> = ImageBuffer::new(width, height);
+    for (x, y, pixel) in input.enumerate_pixels() {
+        let canvas_x = left + x as i64;
+        let canvas_y = top + y as i64;
+        if canvas_x >= 0 && canvas_y >= 0 && canvas_x < width as i64 && canvas_y < height as i64 {
+            output.put_pixel(canvas_x as u32, canvas_y as u32, *pixel);
+        }
+    }
+    output
+}
+
+/// Log a warning for each layer whose resolution differs from the canvas
+///
+/// Layers are placed at one layer pixel per canvas pixel so these layers will not be at their intended scale.
+fn check_layer_resolution(ora: &Ora, main: &Ifd) {
+    let canvas = (
+        main.tags.get(&282).and_then(|tag| tag.data.as_f64()),
+        main.tags.get(&283).and_then(|tag| tag.data.as_f64()),
+    );
+    for layer in ora.flat_layers() {
+        if let Some(metadata) = &layer.metadata {
+            let differs = |layer_res: Option<f64>, canvas_res: Option<f64>| match (layer_res, canvas_res) {
+                (Some(layer_res), Some(canvas_res)) => (layer_res - canvas_res).abs() > f64::EPSILON,
+                _ => false,
+            };
+            if differs(metadata.x_resolution, canvas.0) || differs(metadata.y_resolution, canvas.1) {
+                warn!(
+                    "Layer {} resolution {:?}x{:?} differs from the canvas {:?}x{:?} and will not be rescaled",
+                    layer.layer_number, metadata.x_resolution, metadata.y_resolution, canvas.0, canvas.1
+                );
+            }
+        }
+    }
+}
+
+/// Decode every ifd into a piece of an ora file
+///
+/// # Arguments
+///
+/// * `ifds` - A list of IFDs
+/// * `images` - Tiff image for each of the IFDs
+/// * `source` - The bytes of the original tiff image
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * Ora elements in the order they should be added to the ora file and the ifds that could not be
+///   decoded (which are included in the elements as placeholders)
+#[cfg(not(feature = "parallel"))]
+pub fn decode_elements(
+    ifds: &[Ifd],
+    images: &[Vec<u8>],
+    source: &[u8],
+    options: &ConvertOptions,
+) -> Result<(Vec<Element>, Vec<DroppedLayer>), SkoraError> {
+    let elements: Vec<DecodedElement> = selected_elements(ifds, options)
+        .into_iter()
+        .map(|(layer_number, idx)| timed_ora_element(layer_number, idx, ifds, &images[idx], source, options))
+        .collect::<Result<_, _>>()?;
+    Ok(finish_timings(elements, options))
+}
+
+/// Decode every ifd into a piece of an ora file, decoding the layers in parallel
+///
+/// The elements are returned in draw order regardless of the order in which decoding finishes.
+///
+/// # Arguments
+///
+/// * `ifds` - A list of IFDs
+/// * `images` - Tiff image for each of the IFDs
+/// * `source` - The bytes of the original tiff image
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * Ora elements in the order they should be added to the ora file and the ifds that could not be
+///   decoded (which are included in the elements as placeholders)
+#[cfg(feature = "parallel")]
+pub fn decode_elements(
+    ifds: &[Ifd],
+    images: &[Vec<u8>],
+    source: &[u8],
+    options: &ConvertOptions,
+) -> Result<(Vec<Element>, Vec<DroppedLayer>), SkoraError> {
+    use rayon::prelude::*;
+
+    let elements: Vec<DecodedElement> = selected_elements(ifds, options)
+        .into_par_iter()
+        .map(|(layer_number, idx)| timed_ora_element(layer_number, idx, ifds, &images[idx], source, options))
+        .collect::<Result<_, _>>()?;
+    Ok(finish_timings(elements, options))
+}
+
+/// Get the draw order of the layers in a tiff file
+///
+/// Sketchbook saves the layers with the bottom most layer first so the layer ifds are returned
+/// in reverse.  The thumbnail, mask, and composite ifds are not layers and are left out, as are
+/// ifds without image data.
+///
+/// # Arguments
+///
+/// * `ifds` - A list of IFDs
+///
+/// # Returns
+///
+/// * Indices into `ifds` of the layer ifds, top most layer first
+///
+/// # Example
+/// ```rust
+/// let (info, ifds) = read_tiff(&file)?;
+/// for idx in skora::order_layers(&ifds) {
+///     println!("{}", ifds[idx].summary());
+/// }
+/// ```
+pub fn order_layers(ifds: &[Ifd]) -> Vec<usize> {
+    classify_ifds(ifds)
+        .into_iter()
+        .enumerate()
+        .filter(|(idx, kind)| *kind == IfdKind::Layer && tiff::has_image_data(&ifds[*idx]))
+        .map(|(idx, _)| idx)
+        .rev()
+        .collect()
+}
+
+/// Pair each ifd with its layer number in the ora file
+///
+/// The layers are numbered from the top down followed by the composite (which becomes the
+/// background layer) and then the thumbnail.  Thumbnails other than the one picked by
+/// `select_thumbnail` are skipped.  The composite is found by its Alias marker wherever it is in the
+/// file and only the first marked ifd is used.
+///
+/// # Arguments
+///
+/// * `ifds` - A list of IFDs
+///
+/// # Returns
+///
+/// * Layer number and index into `ifds` for every ifd, in the order they should be added to the ora file
+fn element_order(ifds: &[Ifd]) -> Vec<(usize, usize)> {
+    let layers = order_layers(ifds);
+    let composite = main_ifd_index(ifds);
+    let thumbnail = select_thumbnail(ifds);
+    layers.into_iter().chain(composite).chain(thumbnail).enumerate().collect()
+}
+
+/// Pair each ifd that should be converted with its layer number in the ora file
+///
+/// This is `element_order` with the layers not in `options.layer_filter` left out.
+///
+/// # Arguments
+///
+/// * `ifds` - A list of IFDs
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * Layer number and index into `ifds` for every ifd to convert, in the order they should be added to the ora file
+fn selected_elements(ifds: &[Ifd], options: &ConvertOptions) -> Vec<(usize, usize)> {
+    let elements = element_order(ifds);
+    match &options.layer_filter {
+        Some(filter) => elements
+            .into_iter()
+            .filter(|(layer_number, idx)| classify_ifd(&ifds[*idx]) != IfdKind::Layer || filter.contains(layer_number))
+            .collect(),
+        None => elements,
+    }
+}
+
+/// Find the position of the composite (main) ifd in a tiff file
+///
+/// The composite is usually the first ifd but this doesn't rely on it.
+///
+/// # Arguments
+///
+/// * `ifds` - A list of IFDs
+///
+/// # Returns
+///
+/// * Index into `ifds` of the composite ifd or None if no ifd has the Alias marker
+pub fn main_ifd_index(ifds: &[Ifd]) -> Option<usize> {
+    let composites: Vec<usize> = (0..ifds.len())
+        .filter(|idx| classify_ifd(&ifds[*idx]) == IfdKind::Composite)
+        .collect();
+    match composites.first() {
+        Some(0) => {}
+        Some(idx) => debug!("Composite ifd is at position {} rather than first", idx),
+        None => warn!("No ifd has the Alias marker so there is no composite image"),
+    }
+    if composites.len() > 1 {
+        warn!("Found {} composite ifds, using the first", composites.len());
+    }
+    composites.first().copied()
+}
+
+/// Largest width or height of the thumbnail in an ora file, per the Open Raster spec
+pub const THUMBNAIL_SIZE: u32 = 256;
+
+/// Make a thumbnail from a png image by scaling it down to fit in `THUMBNAIL_SIZE`
+///
+/// # Arguments
+///
+/// * `png` - The bytes of the png image, usually the merged image
+/// * `options` - Png settings used to encode the thumbnail
+///
+/// # Returns
+///
+/// * The bytes of the thumbnail png
+///
+/// # Example
+/// ```rust
+/// ora.thumbnail = thumbnail_from_png(&ora.merged_image, PngOptions::default())?;
+/// ```
+pub fn thumbnail_from_png(png: &[u8], options: PngOptions) -> Result<Vec<u8>, SkoraError> {
+    let image = load_from_memory(png)?;
+    let image = if image.width().max(image.height()) > THUMBNAIL_SIZE {
+        image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+    } else {
+        image
+    };
+    image_to_buf(image.into_rgba8(), options)
+}
+
+/// Pick the thumbnail to use for the ora file
+///
+/// A file can hold more than one reduced resolution image.  The one whose larger dimension is
+/// closest to `THUMBNAIL_SIZE` without going over is used.  If they are all too big the smallest
+/// one is used (and is scaled down when it is converted).  The others are left out of the ora
+/// file entirely.
+///
+/// # Arguments
+///
+/// * `ifds` - A list of IFDs
+///
+/// # Returns
+///
+/// * Index into `ifds` of the chosen thumbnail or None if there are no thumbnails
+pub fn select_thumbnail(ifds: &[Ifd]) -> Option<usize> {
+    let thumbnails: Vec<usize> = (0..ifds.len())
+        .filter(|idx| classify_ifd(&ifds[*idx]) == IfdKind::Thumbnail)
+        .collect();
+    if thumbnails.len() > 1 {
+        debug!("Found {} thumbnails, using the one closest to {}px", thumbnails.len(), THUMBNAIL_SIZE);
+    }
+    let size = |idx: &usize| {
+        let width = ifds[*idx].tag_u32s(256).and_then(|val| val.first().copied()).unwrap_or(0);
+        let height = ifds[*idx].tag_u32s(257).and_then(|val| val.first().copied()).unwrap_or(0);
+        width.max(height)
+    };
+    let fits = thumbnails.iter().copied().filter(|idx| size(idx) <= THUMBNAIL_SIZE).max_by_key(size);
+    fits.or_else(|| thumbnails.iter().copied().min_by_key(size))
+}
+
+/// Wall clock time taken to convert a single ifd
+#[derive(Clone, Copy, Debug)]
+pub struct LayerTiming {
+    /// Layer number of the ifd
+    pub layer_number: usize,
+    /// Time spent decoding the tiff image
+    pub decode: Duration,
+    /// Time spent processing the pixels and encoding the png image
+    pub encode: Duration,
+}
+
+/// Piece of an ora file along with how it was made
+struct DecodedElement {
+    element: Element,
+    timing: LayerTiming,
+    dropped: Option<DroppedLayer>,
+}
+
+/// Create a piece of an ora file for a tiff ifd, recording how long the decode and encode took and
+/// whether the image had to be replaced with a placeholder
+fn timed_ora_element(
+    layer_number: usize,
+    idx: usize,
+    ifds: &[Ifd],
+    image_file: &[u8],
+    source: &[u8],
+    options: &ConvertOptions,
+) -> Result<DecodedElement, SkoraError> {
+    let ifd = &ifds[idx];
+    let start = Instant::now();
+    let (image, dropped) = match try_load_ifd_image(ifd, image_file, source) {
+        Ok(image) => (image, None),
+        Err(error) => {
+            error!("Unable to decode ifd at {}, using a placeholder : {}", ifd.offset, error);
+            let dropped = DroppedLayer {
+                ifd_index: idx,
+                layer_number,
+                kind: classify_ifd(ifd),
+                reason: error.to_string(),
+            };
+            (placeholder_image(), Some(dropped))
+        }
+    };
+    let decoded = Instant::now();
+    let element = image_to_ora_element(layer_number, ifd, image, options)?;
+    let timing = LayerTiming {
+        layer_number,
+        decode: decoded - start,
+        encode: decoded.elapsed(),
+    };
+    Ok(DecodedElement { element, timing, dropped })
+}
+
+/// Log a summary of the layer timings (if requested) and drop them
+fn finish_timings(elements: Vec<DecodedElement>, options: &ConvertOptions) -> (Vec<Element>, Vec<DroppedLayer>) {
+    if options.timings {
+        let timings: Vec<LayerTiming> = elements.iter().map(|decoded| decoded.timing).collect();
+        log_timings(&timings);
+    }
+    let mut output = Vec::with_capacity(elements.len());
+    let mut dropped_layers = Vec::new();
+    for decoded in elements {
+        output.push(decoded.element);
+        dropped_layers.extend(decoded.dropped);
+    }
+    if !dropped_layers.is_empty() {
+        warn!("{} of {} images could not be decoded", dropped_layers.len(), output.len());
+    }
+    (output, dropped_layers)
+}
+
+/// Log the slowest layer and the total decode and encode time of a conversion
+///
+/// # Arguments
+///
+/// * `timings` - Timing of each layer
+pub fn log_timings(timings: &[LayerTiming]) {
+    for timing in timings {
+        debug!(
+            "Layer {} : decode {:?}, encode {:?}",
+            timing.layer_number, timing.decode, timing.encode
+        );
+    }
+    let total_decode: Duration = timings.iter().map(|timing| timing.decode).sum();
+    let total_encode: Duration = timings.iter().map(|timing| timing.encode).sum();
+    if let Some(slowest) = timings.iter().max_by_key(|timing| timing.decode + timing.encode) {
+        info!(
+            "Slowest layer : {} ({:?}), total decode : {:?}, total encode : {:?}",
+            slowest.layer_number,
+            slowest.decode + slowest.encode,
+            total_decode,
+            total_encode
+        );
+    }
+}
+
+/// Create a piece of an ora file (composite, layer, thumbnail) for the given piece of a tiff file (ifd)
+///
+/// # Arguments
+///
+/// * `layer_number` - Layer number
+/// * `ifd` - Reference to the ifd data
+/// * `image_file` - Reference to a tiff image for this ifd
+/// * `source` - The bytes of the original tiff image
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * Ora element for the given tiff image file directory
+pub fn ifd_to_ora_element(
+    layer_number: usize,
+    ifd: &Ifd,
+    image_file: &[u8],
+    source: &[u8],
+    options: &ConvertOptions,
+) -> Result<ora::Element, SkoraError> {
+    let image = load_ifd_image(ifd, image_file, source);
+    image_to_ora_element(layer_number, ifd, image, options)
+}
+
+/// Create a piece of an ora file (composite, layer, thumbnail) from the decoded image of a tiff ifd
+///
+/// # Arguments
+///
+/// * `layer_number` - Layer number
+/// * `ifd` - Reference to the ifd data
+/// * `image` - The decoded image of the ifd
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * Ora element for the given tiff image file directory
+pub fn image_to_ora_element(
+    layer_number: usize,
+    ifd: &Ifd,
+    image: DynamicImage,
+    options: &ConvertOptions,
+) -> Result<ora::Element, SkoraError> {
+    let kind = classify_ifd(ifd);
+
+    if kind == IfdKind::Thumbnail {
+        trace!("This is a reduced resolution image (thumbnail)");
+        let image = if image.width().max(image.height()) > THUMBNAIL_SIZE {
+            debug!("Scaling {}x{} thumbnail down to fit in {}px", image.width(), image.height(), THUMBNAIL_SIZE);
+            image.thumbnail(THUMBNAIL_SIZE, THUMBNAIL_SIZE)
+        } else {
+            image
+        };
+        Ok(ora::Element::Thumbnail(image_to_buf(image.to_rgba8(), options.png.thumbnail)?))
+    } else {
+        let alias_values: Vec<&str> = match ifd.tags.get(&50784).and_then(|tag| tag.data.as_string()) {
+            Some(alias) => alias.split(", ").collect(),
+            None => {
+                warn!("Ifd at {} has no Alias Layer Metadata, using defaults", ifd.offset);
+                Vec::new()
+            }
+        };
+        // older files may leave out the metadata (or some of its fields) so fall back to defaults
+        let alias_value = |idx: usize, default: &'static str| alias_values.get(idx).copied().unwrap_or(default);
+
+        if kind == IfdKind::Composite {
+            trace!("This is a composite image ifd");
+            let layer_count = alias_value(0, "0");
+            let current_layer = alias_value(1, "0");
+            let background_color = alias_value(2, "ffffffff");
+            let reduced_image_count = alias_value(3, "0");
+            info!("LayerCount: {}, CurrentLayer: {}, BackgroundColor: {}, ReducedImageCount (# thumbnails): {}", layer_count, current_layer,background_color, reduced_image_count);
+
+            let colors = match options.background {
+                // this is ARGB from the tiff tag data per Alias Layer Metadata
+                BackgroundMode::FromTiff => parse_argb(background_color).unwrap_or_else(|| {
+                    warn!("Unable to read the background color {:?}, using white", background_color);
+                    [255, 255, 255, 255]
+                }),
+                BackgroundMode::Transparent => [0, 0, 0, 0],
+                BackgroundMode::Solid(color) => color,
+            };
+
+            // the composite is stored top-down so only rotate it when it has an orientation tag
+            let orientation = decode::tag_u32s(ifd, 274)
+                .and_then(|val| val.first().copied())
+                .unwrap_or(1);
+            let image = DynamicImage::ImageRgba8(apply_orientation(image.into_rgba8(), orientation));
+
+            let width = image.width();
+            let height = image.height();
+            let background = fill_color(image.clone(), colors)?;
+
+            let background = ora::Layer::new(
+                u8::try_from(layer_number).map_err(|_| SkoraError::TooManyLayers)?,
+                image_to_buf(background, options.png.layers)?,
+                1.0,
+                0_u32,
+                0_u32,
+                width,
+                height,
+            );
+
+            Ok(ora::Element::Composite((
+                image_to_buf(image.to_rgba8(), options.png.composite)?,
+                background,
+            )))
+        } else {
+            trace!("This is a layer ifd");
+            let layer_opacity = alias_value(0, "1");
+            let mut metadata = parse_layer_metadata(&alias_values);
+            metadata.x_resolution = ifd.tags.get(&282).and_then(|tag| tag.data.as_f64());
+            metadata.y_resolution = ifd.tags.get(&283).and_then(|tag| tag.data.as_f64());
+            debug!("Layer Opacity: {}, {:?}", layer_opacity, metadata);
+            if metadata.visibility_channel_count > 0 {
+                warn!(
+                    "Layer {} has {} visibility channels which can't be represented in an ora file",
+                    layer_number, metadata.visibility_channel_count
+                );
+            }
+
+            let x_pos = ifd.tags.get(&286).and_then(|tag| tag.data.first_rational()).map_or(0.0, |val| val.0);
+            let y_pos = ifd.tags.get(&287).and_then(|tag| tag.data.first_rational()).map_or(0.0, |val| val.0);
+
+            // 16 bit layers are kept at full precision through to the png
+            let bits_per_sample = decode::tag_u32s(ifd, 258)
+                .and_then(|val| val.first().copied())
+                .unwrap_or(8);
+
+            let orientation = layer_orientation(ifd, options);
+            let unpremultiply = layer_unpremultiply(ifd, options);
+            let order = layer_channel_order(ifd, options);
+
+            let (width, height, png) = if decode::expands_to_rgba(ifd) {
+                // palette and bilevel images are expanded straight to RGBA so there are no channels to swap
+                let better = apply_orientation(image.into_rgba8(), orientation);
+                (better.width(), better.height(), image_to_buf(better, options.png.layers)?)
+            } else if bits_per_sample == 16 {
+                let better = apply_orientation(reorder_to_rgba16(image, order, unpremultiply)?, orientation);
+                (better.width(), better.height(), image16_to_buf(better, options.png.layers)?)
+            } else {
+                let better = apply_orientation(reorder_to_rgba(image, order, unpremultiply)?, orientation);
+                (better.width(), better.height(), image_to_buf(better, options.png.layers)?)
+            };
+
+            let mut layer = ora::Layer::new(
+                u8::try_from(layer_number).map_err(|_| SkoraError::TooManyLayers)?,
+                png,
+                normalize_opacity(layer_opacity),
+                x_pos as u32,
+                y_pos as u32,
+                width,
+                height,
+            );
+            layer.metadata = Some(metadata);
+            Ok(ora::Element::Layer(layer))
+        }
+    }
+}
+
+/// Orientation to apply to a layer ifd
+///
+/// Sketchbook stores layers bottom-up so when there is no orientation tag the layer is treated as
+/// orientation 4 (row 0 at the bottom, column 0 on the left).  `options.flip_layers` overrides the tag.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the layer ifd
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * Value of the Orientation tag (274) to apply
+fn layer_orientation(ifd: &Ifd, options: &ConvertOptions) -> u32 {
+    match options.flip_layers {
+        Some(true) => 4,
+        Some(false) => 1,
+        None => decode::tag_u32s(ifd, 274)
+            .and_then(|val| val.first().copied())
+            .unwrap_or(4),
+    }
+}
+
+/// How to remove the alpha scaling from a layer ifd
+///
+/// ExtraSamples (338) = 1 is premultiplied (associated) alpha, which Sketchbook layers use when
+/// the tag is missing.  Any other value means the channels are stored as is.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the layer ifd
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * `options.unpremultiply` for associated alpha, otherwise `Unpremultiply::Skip`
+fn layer_unpremultiply(ifd: &Ifd, options: &ConvertOptions) -> Unpremultiply {
+    match decode::tag_u32s(ifd, 338).and_then(|val| val.first().copied()) {
+        Some(1) | None => options.unpremultiply,
+        Some(_) => Unpremultiply::Skip,
+    }
+}
+
+/// Order of the channels in a layer ifd
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the layer ifd
+/// * `options` - Options controlling the conversion
+///
+/// # Returns
+///
+/// * `options.channel_order` if set, otherwise ARGB when the alpha is the first sample and BGRA for anything else
+fn layer_channel_order(ifd: &Ifd, options: &ConvertOptions) -> ChannelOrder {
+    if let Some(order) = options.channel_order {
+        return order;
+    }
+    match decode::alpha_sample_index(ifd) {
+        Some(0) => ChannelOrder::Argb,
+        Some(3) | None => ChannelOrder::Bgra,
+        Some(index) => {
+            warn!("Layer at {} has its alpha at sample {}, treating it as BGRA", ifd.offset, index);
+            ChannelOrder::Bgra
+        }
+    }
+}
+
+/// Parse the fields of the Alias Layer Metadata tag (50784) of a layer ifd
+///
+/// Missing fields fall back to a visible, unlocked layer without visibility channels or masks.
+///
+/// # Arguments
+///
+/// * `values` - The comma separated fields of the tag
+///
+/// # Returns
+///
+/// * The layer metadata
+///
+/// # Example
+/// ```rust
+/// let metadata = parse_layer_metadata(&["1.0", "0", "1", "0", "0", "0", "0"]);
+/// assert!(metadata.visible);
+/// ```
+pub fn parse_layer_metadata(values: &[&str]) -> ora::LayerMetadata {
+    let flag = |idx: usize, default: bool| values.get(idx).map_or(default, |val| val.trim() != "0");
+    let count = |idx: usize| values.get(idx).and_then(|val| val.trim().parse::<u32>().ok()).unwrap_or(0);
+
+    let visibility_channel_count = count(5);
+    ora::LayerMetadata {
+        fill_color: values.get(1).map_or(String::from("0"), |val| val.to_string()),
+        fill_argb: parse_argb(values.get(1).copied().unwrap_or("0")),
+        visible: flag(2, true),
+        locked: flag(3, false),
+        name_image_present: flag(4, false),
+        visibility_channel_count,
+        visibility_channels: values
+            .iter()
+            .skip(7)
+            .take(visibility_channel_count as usize)
+            .map(|val| val.to_string())
+            .collect(),
+        mask_layer_count: count(6),
+        x_resolution: None,
+        y_resolution: None,
+    }
+}
+
+/// Parse a hex ARGB color from the Alias Layer Metadata tag (50784)
+///
+/// Leading zeros may be left off (so `0` is fully transparent black) and a `0x` prefix is allowed.
+///
+/// # Arguments
+///
+/// * `hex` - Hex color with up to 8 digits
+///
+/// # Returns
+///
+/// * Alpha, red, green, blue or None if the text is not a hex color
+///
+/// # Example
+/// ```rust
+/// assert_eq!(parse_argb("ff102030"), Some([0xff, 0x10, 0x20, 0x30]));
+/// assert_eq!(parse_argb("0"), Some([0, 0, 0, 0]));
+/// ```
+pub fn parse_argb(hex: &str) -> Option<[u8; 4]> {
+    let hex = hex.trim();
+    let hex = hex.strip_prefix("0x").unwrap_or(hex);
+    if hex.is_empty() || hex.len() > 8 {
+        return None;
+    }
+    <[u8; 4]>::from_hex(format!("{:0>8}", hex)).ok()
+}
+
+/// Load the image stored in an ifd
+///
+/// Uncompressed 8 bit RGBA images are read directly from the strips of the source file, which avoids
+/// running the reconstructed tiff through the `image` crate's tiff decoder.  `benches/load_ifd_image.rs`
+/// times the two paths against each other.  Anything else is decoded from the reconstructed tiff.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd data
+/// * `image_file` - Reference to a tiff image for this ifd
+/// * `source` - The bytes of the original tiff image
+///
+/// # Returns
+///
+/// * The decoded image.  If the image can't be decoded a small placeholder image is returned
+///   so we can keep processing the rest of the layers.
+pub fn load_ifd_image(ifd: &Ifd, image_file: &[u8], source: &[u8]) -> DynamicImage {
+    match try_load_ifd_image(ifd, image_file, source) {
+        Ok(image) => image,
+        Err(error) => {
+            // if we can't load the image then make a small image file so we can keep processing the rest of the layers
+            error!("{}", error);
+            placeholder_image()
+        }
+    }
+}
+
+/// Small transparent image used in place of an image that can't be decoded
+fn placeholder_image() -> DynamicImage {
+    DynamicImage::ImageRgba8(ImageBuffer::new(10, 10))
+}
+
+/// Decode the image data of an ifd
+///
+/// This is the same as `load_ifd_image` but returns an error rather than a placeholder image.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd data
+/// * `image_file` - Reference to a tiff image for this ifd
+/// * `source` - The bytes of the original tiff image
+///
+/// # Returns
+///
+/// * The decoded image or an error if it can't be decoded
+pub fn try_load_ifd_image(ifd: &Ifd, image_file: &[u8], source: &[u8]) -> Result<DynamicImage, SkoraError> {
+    let compression = decode::tag_u32s(ifd, 259)
+        .and_then(|val| val.first().copied())
+        .unwrap_or(1);
+    // palette, bilevel, signed, and float images have to be converted here as the image crate can't read them
+    if compression == 1 || decode::expands_to_rgba(ifd) || decode::sample_format(ifd) != 1 {
+        match decode::decode_layer_pixels(ifd, source) {
+            Ok(raw) => {
+                if let Some(image) = raw.into_dynamic_image() {
+                    return Ok(image);
+                }
+            }
+            Err(error) => debug!("Unable to read strips directly: {}", error),
+        }
+    }
+
+    Ok(load_from_memory(image_file)?)
+}
+
+/// Transform an image so it is displayed correctly based on its Orientation tag (274)
+///
+/// The orientation values are the 8 standard tiff / EXIF orientations, which describe where row 0
+/// and column 0 of the stored image are located on the displayed image.  Unknown values are left as is.
+///
+/// # Arguments
+///
+/// * `input` - Image buffer as stored in the tiff
+/// * `orientation` - Value of the Orientation tag
+///
+/// # Returns
+///
+/// * Image buffer with row 0 at the top and column 0 on the left
+pub fn apply_orientation<P>(
+    input: ImageBuffer<P, Vec<P::Subpixel>>,
+    orientation: u32,
+) -> ImageBuffer<P, Vec<P::Subpixel>>
+where
+    P: Pixel + 'static,
+    P::Subpixel: 'static,
+{
+    use image::imageops;
+
+    match orientation {
+        2 => {
+            let mut output = input;
+            imageops::flip_horizontal_in_place(&mut output);
+            output
+        }
+        3 => imageops::rotate180(&input),
+        4 => {
+            let mut output = input;
+            imageops::flip_vertical_in_place(&mut output);
+            output
+        }
+        5 => {
+            let mut output = imageops::rotate90(&input);
+            imageops::flip_horizontal_in_place(&mut output);
+            output
+        }
+        6 => imageops::rotate90(&input),
+        7 => {
+            let mut output = imageops::rotate270(&input);
+            imageops::flip_horizontal_in_place(&mut output);
+            output
+        }
+        8 => imageops::rotate270(&input),
+        _ => input,
+    }
+}
+
+/// Convert the opacity stored in the layer metadata to the 0.0 to 1.0 range used by ORA
+///
+/// Some versions of Sketchbook store opacity as 0 to 255 rather than 0.0 to 1.0.  Values above 1.0
+/// that are whole numbers, or are 2.0 or more, are treated as being on the 0 to 255 scale.  Other
+/// values slightly above 1.0 are rounding noise on the 0.0 to 1.0 scale.  The result is clamped to
+/// 0.0 to 1.0 and values that are NaN or can't be parsed are treated as fully opaque.
+///
+/// # Arguments
+///
+/// * `value` - Opacity as written in the Alias Layer Metadata
+///
+/// # Returns
+///
+/// * Opacity between 0.0 and 1.0
+pub fn normalize_opacity(value: &str) -> f32 {
+    let opacity = match value.trim().parse::<f32>() {
+        Ok(val) => val,
+        Err(error) => {
+            warn!("Unable to parse layer opacity {:?}: {}", value, error);
+            return 1.0;
+        }
+    };
+    if opacity.is_nan() {
+        return 1.0;
+    }
+    let byte_scale = opacity > 1.0 && (opacity.fract() == 0.0 || opacity >= 2.0);
+    let opacity = if byte_scale { opacity / 255.0 } else { opacity };
+    opacity.clamp(0.0, 1.0)
+}
+
+/// Color type of a png written to the ORA file
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum PngColor {
+    /// Always write RGBA
+    #[default]
+    Rgba,
+    /// Write RGB when every pixel is fully opaque, otherwise RGBA.  Opaque images are about 25% smaller
+    /// and ORA readers handle both.
+    Auto,
+    /// Always write RGB, dropping the alpha channel
+    Rgb,
+}
+
+/// Compression settings used when encoding a png
+#[derive(Clone, Copy, Debug)]
+pub struct PngOptions {
+    /// How hard the encoder works to shrink the image
+    pub compression: CompressionType,
+    /// Filter applied to each row before compression
+    pub filter: FilterType,
+    /// Whether the alpha channel is written
+    pub color: PngColor,
+}
+
+impl Default for PngOptions {
+    /// Create a new instance of PngOptions using the encoder's default settings
+    ///
+    /// # Example
+    /// ```rust
+    /// let png = PngOptions::default();
+    /// ```
+    fn default() -> Self {
+        PngOptions {
+            compression: CompressionType::Default,
+            filter: FilterType::Sub,
+            color: PngColor::default(),
+        }
+    }
+}
+
+/// Png compression settings for each type of image in the ORA file
+///
+/// Large layers dominate the conversion time so `CompressionType::Fast` on layers speeds up iterative
+/// work, while the small thumbnail can use `CompressionType::Best` at little cost.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct PngCompression {
+    /// Settings for the paint layers and the background layer
+    pub layers: PngOptions,
+    /// Settings for the composite (merged) image
+    pub composite: PngOptions,
+    /// Settings for the thumbnail
+    pub thumbnail: PngOptions,
+}
+
+/// Export an Image buffer to a png
+///
+/// # Arguments
+///
+/// * `input` - Image buffer to convert
+/// * `png` - Compression settings for the png
+///
+/// # Returns
+///
+/// * PNG file of the image stored as a vector of u8 bytes
+pub fn image_to_buf(
+    input: ImageBuffer<image::Rgba<u8>, Vec<u8>>,
+    png: PngOptions,
+) -> Result<Vec<u8>, SkoraError> {
+    encode_png(&DynamicImage::ImageRgba8(input), png)
+}
+
+/// Export a 16 bit per channel Image buffer to a png
+///
+/// # Arguments
+///
+/// * `input` - Image buffer to convert
+/// * `png` - Compression settings for the png
+///
+/// # Returns
+///
+/// * 16 bit PNG file of the image stored as a vector of u8 bytes
+pub fn image16_to_buf(
+    input: ImageBuffer<image::Rgba<u16>, Vec<u16>>,
+    png: PngOptions,
+) -> Result<Vec<u8>, SkoraError> {
+    encode_png(&DynamicImage::ImageRgba16(input), png)
+}
+
+/// Encode an image as a png with explicit compression settings
+fn encode_png(image: &DynamicImage, png: PngOptions) -> Result<Vec<u8>, SkoraError> {
+    let drop_alpha = match png.color {
+        PngColor::Rgba => false,
+        PngColor::Rgb => true,
+        PngColor::Auto => is_opaque(image),
+    };
+    let rgb;
+    let image = match (drop_alpha, image) {
+        (true, DynamicImage::ImageRgba8(val)) => {
+            rgb = DynamicImage::ImageRgb8(val.convert());
+            &rgb
+        }
+        (true, DynamicImage::ImageRgba16(val)) => {
+            rgb = DynamicImage::ImageRgb16(val.convert());
+            &rgb
+        }
+        _ => image,
+    };
+
+    let mut buf: Vec<u8> = vec![];
+    PngEncoder::new_with_quality(&mut buf, png.compression, png.filter).write_image(
+        image.as_bytes(),
+        image.width(),
+        image.height(),
+        image.color(),
+    )?;
+    Ok(buf)
+}
+
+/// Check if every pixel of an RGBA image is fully opaque
+///
+/// # Arguments
+///
+/// * `image` - Image to check
+///
+/// # Returns
+///
+/// * True if the image is RGBA with every alpha at its maximum, false otherwise
+pub fn is_opaque(image: &DynamicImage) -> bool {
+    match image {
+        DynamicImage::ImageRgba8(val) => val.pixels().all(|pixel| pixel[3] == u8::MAX),
+        DynamicImage::ImageRgba16(val) => val.pixels().all(|pixel| pixel[3] == u16::MAX),
+        _ => false,
+    }
+}
+
+/// Encode an image as an uncompressed tiff
+///
+/// # Arguments
+///
+/// * `image` - Image to encode
+///
+/// # Returns
+///
+/// * Tiff file of the image stored as a vector of u8 bytes
+pub fn uncompressed_tiff(image: &DynamicImage) -> Result<Vec<u8>, SkoraError> {
+    let mut buf = std::io::Cursor::new(Vec::new());
+    image::codecs::tiff::TiffEncoder::new(&mut buf).encode(
+        image.as_bytes(),
+        image.width(),
+        image.height(),
+        image.color(),
+    )?;
+    Ok(buf.into_inner())
+}
+
+/// Convert 16 bit per channel BGRA image to RGBA
+///
+/// This is the same as `bgra_to_rgba` but keeps the full 16 bits of each channel.
+///
+/// # Arguments
+///
+/// * `input` - Dynamic image to convert
+/// * `mode` - How channel values are rounded when removing the alpha scaling
+///
+/// # Returns
+///
+/// * 16 bit image buffer converted to RGBA
+pub fn bgra_to_rgba16(
+    input: DynamicImage,
+    mode: Unpremultiply,
+) -> Result<ImageBuffer<image::Rgba<u16>, Vec<u16>>, SkoraError> {
+    reorder_to_rgba16(input, ChannelOrder::Bgra, mode)
+}
+
+/// Convert 16 bit per channel image in any channel order to RGBA
+///
+/// This is the same as `reorder_to_rgba` but keeps the full 16 bits of each channel.
+///
+/// # Arguments
+///
+/// * `input` - Dynamic image to convert
+/// * `order` - Order of the channels in the input
+/// * `mode` - How channel values are rounded when removing the alpha scaling
+///
+/// # Returns
+///
+/// * 16 bit image buffer converted to RGBA
+pub fn reorder_to_rgba16(
+    input: DynamicImage,
+    order: ChannelOrder,
+    mode: Unpremultiply,
+) -> Result<ImageBuffer<image::Rgba<u16>, Vec<u16>>, SkoraError> {
+    let [red, green, blue, alpha] = order.rgba_indices();
+    let mut output = input.into_rgba16();
+    output.pixels_mut().for_each(|pixel| {
+        let stored = pixel.0;
+        let scale = stored[alpha] as f64 / 65535.0; // this alpha is now between 0 and 1
+        pixel[0] = mode.apply(stored[red] as f64, scale, 65535.0) as u16;
+        pixel[1] = mode.apply(stored[green] as f64, scale, 65535.0) as u16;
+        pixel[2] = mode.apply(stored[blue] as f64, scale, 65535.0) as u16;
+        pixel[3] = stored[alpha];
+    });
+    Ok(output)
+}
+
+/// Convert BGRA image to RGBA
+///
+/// For whatever reason Sketchbook layers are stored in BGRA while the composite and thumbnail are RGBA.
+/// In addition, the layers are stored with RGB premultiplied by alpha.  
+/// This function swaps the B & R values for each pixel and removes the 'premultiplied alpha' scaling
+/// ie divides each channel by alpha.  Grayscale and RGB images are converted to RGBA first (with an
+/// opaque alpha) so every image is processed 4 channels per pixel.
+///
+/// # Arguments
+///
+/// * `input` - Dynamic image to convert
+/// * `mode` - How channel values are rounded when removing the alpha scaling
+///
+/// # Returns
+///
+/// * Image buffer converted to RGBA
+pub fn bgra_to_rgba(
+    input: DynamicImage,
+    mode: Unpremultiply,
+) -> Result<ImageBuffer<image::Rgba<u8>, Vec<u8>>, SkoraError> {
+    reorder_to_rgba(input, ChannelOrder::Bgra, mode)
+}
+
+/// Convert an image in any channel order to RGBA
+///
+/// The channels of each pixel are moved to RGBA order and the 'premultiplied alpha' scaling is removed
+/// using the alpha from wherever it is stored.  As with `bgra_to_rgba` grayscale and RGB images are
+/// converted to RGBA first.
+///
+/// # Arguments
+///
+/// * `input` - Dynamic image to convert
+/// * `order` - Order of the channels in the input
+/// * `mode` - How channel values are rounded when removing the alpha scaling
+///
+/// # Returns
+///
+/// * Image buffer converted to RGBA
+///
+/// # Example
+/// ```rust
+/// let rgba = reorder_to_rgba(image, ChannelOrder::Argb, Unpremultiply::Round)?;
+/// ```
+pub fn reorder_to_rgba(
+    input: DynamicImage,
+    order: ChannelOrder,
+    mode: Unpremultiply,
+) -> Result<ImageBuffer<image::Rgba<u8>, Vec<u8>>, SkoraError> {
+    let [red, green, blue, alpha] = order.rgba_indices();
+    // into_bytes would give 1 or 3 bytes per pixel for grayscale or RGB images so convert to RGBA first
+    let mut output = input.into_rgba8();
+    output.pixels_mut().for_each(|pixel| {
+        let stored = pixel.0;
+        let scale = stored[alpha] as f64 / 255.0; // this alpha is now between 0 and 1
+        pixel[0] = mode.apply(stored[red] as f64, scale, 255.0) as u8;
+        pixel[1] = mode.apply(stored[green] as f64, scale, 255.0) as u8;
+        pixel[2] = mode.apply(stored[blue] as f64, scale, 255.0) as u8;
+        pixel[3] = stored[alpha];
+    });
+    Ok(output)
+}
+
+/// Fill a dynamic image with a specified color
+///
+/// # Arguments
+///
+/// * `input` - Dynamic image to fill with some specified color
+/// * `color_argb` - Slice denoting the alpha, red, green, blue channels to add as the image color
+///
+/// # Returns
+///
+/// * Image buffer filled with the specified color
+pub fn fill_color(
+    input: DynamicImage,
+    color_argb: [u8; 4],
+) -> Result<ImageBuffer<image::Rgba<u8>, Vec<u8>>, SkoraError> {
+    let (width, height) = input.dimensions();
+    Ok(ImageBuffer::from_pixel(
+        width,
+        height,
+        image::Rgba([color_argb[1], color_argb[2], color_argb[3], color_argb[0]]),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_tiff::{ascii, ifd, rgba_tags, tiff_file, TestIfd};
+    use image::RgbaImage;
+
+    /// Convert a composite ifd with the given background and return the pixel of the background layer
+    fn background_pixel(background: BackgroundMode) -> [u8; 4] {
+        let composite = ifd(vec![
+            (305, ascii(tiff::ALIAS_MARKER_V1_1)),
+            (50784, ascii("1, 0, ff102030, 1")),
+        ]);
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, image::Rgba([1, 2, 3, 255])));
+        let options = ConvertOptions {
+            background,
+            ..ConvertOptions::default()
+        };
+        match image_to_ora_element(1, &composite, image, &options).unwrap() {
+            Element::Composite((_, layer)) => layer.decoded().unwrap().get_pixel(0, 0).0,
+            _ => panic!("composite ifd did not convert to a composite element"),
+        }
+    }
+
+    #[test]
+    fn background_from_tiff() {
+        assert_eq!(background_pixel(BackgroundMode::FromTiff), [0x10, 0x20, 0x30, 0xff]);
+    }
+
+    #[test]
+    fn background_transparent() {
+        assert_eq!(background_pixel(BackgroundMode::Transparent), [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn background_solid() {
+        assert_eq!(background_pixel(BackgroundMode::Solid([0x80, 1, 2, 3])), [1, 2, 3, 0x80]);
+    }
+
+    #[test]
+    fn parse_argb_values() {
+        assert_eq!(parse_argb("ff102030"), Some([0xff, 0x10, 0x20, 0x30]));
+        assert_eq!(parse_argb(" 0x80FFFFFF "), Some([0x80, 0xff, 0xff, 0xff]));
+        // short values are padded with leading zeros
+        assert_eq!(parse_argb("ff"), Some([0, 0, 0, 0xff]));
+        assert_eq!(parse_argb("0"), Some([0, 0, 0, 0]));
+        assert_eq!(parse_argb(""), None);
+        assert_eq!(parse_argb("1ff102030"), None);
+        assert_eq!(parse_argb("white"), None);
+    }
+
+    #[test]
+    fn layer_fill_color() {
+        let fill = |alias: &str| {
+            let tags = vec![(50784, ascii(alias))];
+            let image = DynamicImage::ImageRgba8(RgbaImage::new(1, 1));
+            convert_layer(tags, image, &ConvertOptions::default()).metadata.unwrap()
+        };
+        let metadata = fill("1.0, ff336699, 1, 0, 0, 0, 0");
+        assert_eq!(metadata.fill_color, "ff336699");
+        assert_eq!(metadata.fill_argb, Some([0xff, 0x33, 0x66, 0x99]));
+        assert_eq!(fill("1.0, 6699, 1, 0, 0, 0, 0").fill_argb, Some([0, 0, 0x66, 0x99]));
+        // a fully transparent fill means the layer has no fill
+        assert_eq!(fill("1.0, 0, 1, 0, 0, 0, 0").fill_argb, Some([0, 0, 0, 0]));
+
+        // fill_color takes ARGB and makes an RGBA image of that color the size of the input
+        assert_eq!(
+            fill_color(DynamicImage::ImageRgba8(RgbaImage::new(2, 1)), [0, 1, 2, 3]).unwrap().get_pixel(1, 0).0,
+            [1, 2, 3, 0]
+        );
+    }
+
+    #[test]
+    fn layer_number_past_u8() {
+        let layer = ifd(rgba_tags(2, 2).into_iter().map(|(num, data)| (num as u64, data)).collect());
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(2, 2));
+        let result = image_to_ora_element(256, &layer, image, &ConvertOptions::default());
+        assert!(matches!(result, Err(SkoraError::TooManyLayers)));
+    }
+
+    #[test]
+    fn normalize_opacity_scales() {
+        assert_eq!(normalize_opacity("255"), 1.0);
+        assert_eq!(normalize_opacity("0.5"), 0.5);
+        assert_eq!(normalize_opacity("128"), 128.0 / 255.0);
+        assert_eq!(normalize_opacity(" 0.25 "), 0.25);
+    }
+
+    #[test]
+    fn normalize_opacity_clamps_values_just_above_one() {
+        assert_eq!(normalize_opacity("1.5"), 1.0);
+        assert_eq!(normalize_opacity("1.0000001"), 1.0);
+    }
+
+    #[test]
+    fn normalize_opacity_defaults_to_opaque() {
+        assert_eq!(normalize_opacity("abc"), 1.0);
+        assert_eq!(normalize_opacity("NaN"), 1.0);
+        assert_eq!(normalize_opacity("-3"), 0.0);
+    }
+
+    #[test]
+    fn header_only_tiff_has_no_image_data() {
+        let result = convert_bytes(b"II\x2a\x00\x00\x00\x00\x00", ConvertOptions::default());
+        assert!(matches!(result, Err(SkoraError::NoImageData)));
+    }
+
+    /// Thumbnail ifd with the given size
+    fn thumbnail_ifd(width: u32, height: u32) -> Ifd {
+        ifd(vec![
+            (254, Data::Long(vec![tiff::SUBFILE_REDUCED_RESOLUTION])),
+            (256, Data::Long(vec![width])),
+            (257, Data::Long(vec![height])),
+        ])
+    }
+
+    #[test]
+    fn select_thumbnail_closest_to_size() {
+        let layer = ifd(vec![(256, Data::Long(vec![200])), (257, Data::Long(vec![200]))]);
+        let ifds = vec![thumbnail_ifd(128, 96), layer, thumbnail_ifd(512, 384), thumbnail_ifd(160, 240)];
+        assert_eq!(select_thumbnail(&ifds), Some(3));
+    }
+
+    #[test]
+    fn select_thumbnail_smallest_when_none_fit() {
+        let ifds = vec![thumbnail_ifd(1024, 768), thumbnail_ifd(300, 400), thumbnail_ifd(512, 384)];
+        assert_eq!(select_thumbnail(&ifds), Some(2));
+        assert_eq!(select_thumbnail(&[]), None);
+    }
+
+    /// 1x1 layer ifd with a strip so it counts as having image data
+    fn layer_ifd() -> Ifd {
+        ifd(vec![
+            (256, Data::Long(vec![1])),
+            (257, Data::Long(vec![1])),
+            (273, Data::Long(vec![8])),
+        ])
+    }
+
+    #[test]
+    fn thumbnail_between_layers() {
+        let composite = ifd(vec![(305, ascii(tiff::ALIAS_MARKER_V1_1))]);
+        let ifds = vec![composite, layer_ifd(), thumbnail_ifd(4, 3), layer_ifd()];
+        assert_eq!(main_ifd_index(&ifds), Some(0));
+        assert_eq!(select_thumbnail(&ifds), Some(2));
+        assert_eq!(order_layers(&ifds), vec![3, 1]);
+        assert_eq!(element_order(&ifds), vec![(0, 3), (1, 1), (2, 0), (3, 2)]);
+    }
+
+    #[test]
+    fn composite_after_the_layers() {
+        let composite = ifd(vec![(305, ascii(tiff::ALIAS_MARKER_V1_1))]);
+        let ifds = vec![layer_ifd(), thumbnail_ifd(4, 3), composite, layer_ifd()];
+        assert_eq!(main_ifd_index(&ifds), Some(2));
+        assert!(matches!(tiff::get_main_ifd(&ifds), Some(main) if tiff::is_composite_ifd(main)));
+        assert_eq!(order_layers(&ifds), vec![3, 0]);
+        assert_eq!(element_order(&ifds), vec![(0, 3), (1, 0), (2, 2), (3, 1)]);
+    }
+
+    #[test]
+    fn expand_layer_measures_y_from_the_bottom() {
+        let mut image = RgbaImage::new(50, 50);
+        image.put_pixel(0, 0, image::Rgba([255, 0, 0, 255]));
+        let png = image_to_buf(image, PngOptions::default()).unwrap();
+        let layer = ora::Layer::new(0, png, 1.0, 10, 10, 50, 50);
+
+        let full = expand_layer_to_canvas(&layer, 100, 100, PngOptions::default()).unwrap();
+        assert_eq!((full.x_pos, full.y_pos, full.width, full.height), (0, 0, 100, 100));
+        let canvas = full.decoded().unwrap();
+        assert_eq!(canvas.dimensions(), (100, 100));
+        assert_eq!(canvas.get_pixel(10, 40).0, [255, 0, 0, 255]);
+        assert_eq!(canvas.get_pixel(10, 50).0, [0, 0, 0, 0]);
+    }
+
+    /// 2x1 image with a visible red layer on the left, a hidden green layer on the right, and a blue background
+    fn merge_sample() -> Ora {
+        let png = |width: u32, color: [u8; 4]| {
+            image_to_buf(RgbaImage::from_pixel(width, 1, image::Rgba(color)), PngOptions::default()).unwrap()
+        };
+        let mut ora = Ora::new();
+        ora.set_canvas_size(2, 1);
+        ora.add_layer(ora::Layer::new(0, png(1, [255, 0, 0, 255]), 1.0, 0, 0, 1, 1));
+        let mut hidden = ora::Layer::new(1, png(1, [0, 255, 0, 255]), 1.0, 1, 0, 1, 1);
+        hidden.visible = false;
+        ora.add_layer(hidden);
+        ora.background = Some(ora::Layer::new(2, png(2, [0, 0, 255, 255]), 1.0, 0, 0, 2, 1));
+        ora
+    }
+
+    /// Merge the sample image and return the merged layer
+    fn merged_sample(merge: MergeStrategy) -> (Ora, ora::Layer) {
+        let mut ora = merge_sample();
+        let options = ConvertOptions {
+            merge,
+            ..ConvertOptions::default()
+        };
+        merge_layers(&mut ora, &options).unwrap();
+        assert_eq!(ora.layers.len(), 1);
+        let merged = match &ora.layers[0] {
+            LayerNode::Layer(layer) => layer.clone(),
+            LayerNode::Group(_) => panic!("merged layer is a group"),
+        };
+        assert_eq!(merged.name.as_deref(), Some("Merged"));
+        assert_eq!(merged.layer_number, 0);
+        assert_eq!((merged.x_pos, merged.y_pos, merged.width, merged.height), (0, 0, 2, 1));
+        (ora, merged)
+    }
+
+    #[test]
+    fn merge_flatten_all() {
+        let (ora, merged) = merged_sample(MergeStrategy::FlattenAll);
+        assert!(ora.background.is_none());
+        let pixels = merged.decoded().unwrap();
+        assert_eq!(pixels.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(pixels.get_pixel(1, 0).0, [0, 0, 255, 255]);
+    }
+
+    #[test]
+    fn merge_flatten_hidden() {
+        let (ora, merged) = merged_sample(MergeStrategy::FlattenHidden);
+        assert_eq!(ora.background.as_ref().map(|layer| layer.layer_number), Some(2));
+        let pixels = merged.decoded().unwrap();
+        assert_eq!(pixels.get_pixel(0, 0).0, [255, 0, 0, 255]);
+        assert_eq!(pixels.get_pixel(1, 0).0, [0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn merge_half_opacity_layers() {
+        let png = |color: [u8; 4]| {
+            image_to_buf(RgbaImage::from_pixel(1, 1, image::Rgba(color)), PngOptions::default()).unwrap()
+        };
+        let mut ora = Ora::new();
+        ora.set_canvas_size(1, 1);
+        ora.add_layer(ora::Layer::new(0, png([255, 0, 0, 255]), 0.5, 0, 0, 1, 1));
+        ora.add_layer(ora::Layer::new(1, png([0, 0, 255, 255]), 0.5, 0, 0, 1, 1));
+        let options = ConvertOptions {
+            merge: MergeStrategy::FlattenAll,
+            ..ConvertOptions::default()
+        };
+        merge_layers(&mut ora, &options).unwrap();
+        let merged = match &ora.layers[..] {
+            [LayerNode::Layer(layer)] => layer,
+            _ => panic!("layers were not merged into a single layer"),
+        };
+        // blue covers half the canvas, then red covers half of what's left: alpha 0.75 with red
+        // contributing 0.5 and blue 0.25 of it
+        assert_eq!(merged.opacity, 1.0);
+        assert_eq!(merged.decoded().unwrap().get_pixel(0, 0).0, [170, 0, 85, 191]);
+    }
+
+    /// 2x3 image where each pixel holds its position in the stored rows
+    fn orientation_sample() -> ImageBuffer<image::Luma<u8>, Vec<u8>> {
+        ImageBuffer::from_fn(2, 3, |x, y| image::Luma([(y * 2 + x) as u8]))
+    }
+
+    #[test]
+    fn orientation_top_left() {
+        let output = apply_orientation(orientation_sample(), 1);
+        assert_eq!(output.dimensions(), (2, 3));
+        assert_eq!(output.into_raw(), vec![0, 1, 2, 3, 4, 5]);
+    }
+
+    #[test]
+    fn orientation_bottom_right() {
+        let output = apply_orientation(orientation_sample(), 3);
+        assert_eq!(output.dimensions(), (2, 3));
+        assert_eq!(output.into_raw(), vec![5, 4, 3, 2, 1, 0]);
+    }
+
+    #[test]
+    fn orientation_right_top() {
+        let output = apply_orientation(orientation_sample(), 6);
+        assert_eq!(output.dimensions(), (3, 2));
+        assert_eq!(output.into_raw(), vec![4, 2, 0, 5, 3, 1]);
+    }
+
+    #[test]
+    fn orientation_left_bottom() {
+        let output = apply_orientation(orientation_sample(), 8);
+        assert_eq!(output.dimensions(), (3, 2));
+        assert_eq!(output.into_raw(), vec![1, 3, 5, 0, 2, 4]);
+    }
+
+    /// BGRA pixels premultiplied from the same color at decreasing alpha, ending with an edge pixel
+    /// whose red is larger than its alpha
+    fn premultiplied_gradient() -> DynamicImage {
+        let pixels = [
+            [0, 0, 200, 255],
+            [30, 90, 150, 192],
+            [20, 60, 100, 128],
+            [10, 30, 50, 64],
+            [0, 0, 90, 64],
+        ];
+        let data: Vec<u8> = pixels.iter().flatten().copied().collect();
+        DynamicImage::ImageRgba8(RgbaImage::from_raw(5, 1, data).unwrap())
+    }
+
+    #[test]
+    fn unpremultiply_round() {
+        let output = bgra_to_rgba(premultiplied_gradient(), Unpremultiply::Round).unwrap();
+        let expected: Vec<u8> = [
+            [200, 0, 0, 255],
+            [199, 120, 40, 192],
+            [199, 120, 40, 128],
+            [199, 120, 40, 64],
+            [255, 0, 0, 64],
+        ]
+        .iter()
+        .flatten()
+        .copied()
+        .collect();
+        assert_eq!(output.into_raw(), expected);
+    }
+
+    #[test]
+    fn unpremultiply_truncate() {
+        let output = bgra_to_rgba(premultiplied_gradient(), Unpremultiply::Truncate).unwrap();
+        let expected: Vec<u8> = [
+            [200, 0, 0, 255],
+            [199, 119, 39, 192],
+            [199, 119, 39, 128],
+            [199, 119, 39, 64],
+            [255, 0, 0, 64],
+        ]
+        .iter()
+        .flatten()
+        .copied()
+        .collect();
+        assert_eq!(output.into_raw(), expected);
+    }
+
+    #[test]
+    fn exported_layer_tiffs_are_stamped() {
+        let layer = TestIfd {
+            tags: rgba_tags(2, 2),
+            strips: vec![(0..16).collect()],
+            ..TestIfd::default()
+        };
+        let file = tiff_file(Endian::Little, false, &[layer]);
+        let (_info, ifds) = tiff::read_tiff(&file).unwrap();
+        let images = tiff::get_layers(ifds.clone(), &file).unwrap();
+
+        for reencode in [false, true].iter() {
+            let exported = layer_tiff(&ifds[0], &images[0], &file, *reencode, "2021:06:01 12:00:00").unwrap();
+            let (_info, exported_ifds) = tiff::read_tiff(&exported).unwrap();
+            let text = |tag| tiff::get_tag(&exported_ifds, 0, tag).and_then(|data| data.as_string());
+            assert_eq!(text(305), Some(tiff::SOFTWARE));
+            assert_eq!(text(306), Some("2021:06:01 12:00:00"));
+        }
+    }
+
+    #[test]
+    fn broken_thumbnail_made_from_the_composite() {
+        let mut composite_tags = rgba_tags(8, 6);
+        composite_tags.push((305, ascii(tiff::ALIAS_MARKER_V1_1)));
+        let mut thumbnail_tags = rgba_tags(4, 3);
+        thumbnail_tags.push((254, Data::Long(vec![tiff::SUBFILE_REDUCED_RESOLUTION])));
+        let composite = TestIfd {
+            tags: composite_tags,
+            strips: vec![vec![255; 8 * 6 * 4]],
+            ..TestIfd::default()
+        };
+        let thumbnail = TestIfd {
+            tags: thumbnail_tags,
+            strips: vec![vec![255; 4 * 3 * 4]],
+            ..TestIfd::default()
+        };
+        let file = tiff_file(Endian::Little, false, &[composite, thumbnail]);
+        let (_info, mut ifds) = tiff::read_tiff(&file).unwrap();
+        // move the thumbnail's strip past the end of the file so it can't be decoded
+        ifds[1].tags.get_mut(&273).unwrap().data = Data::Long(vec![file.len() as u32]);
+        let images = tiff::get_layers(ifds.clone(), &file).unwrap();
+
+        let (ora, dropped) = build_ora(&ifds, &images, &file, &ConvertOptions::default()).unwrap();
+        assert!(dropped.iter().any(|layer| layer.ifd_index == 1 && layer.kind == IfdKind::Thumbnail));
+        assert_eq!(ora::png_dimensions(&ora.thumbnail).unwrap(), (8, 6));
+        assert_eq!(load_from_memory(&ora.thumbnail).unwrap().into_rgba8().get_pixel(0, 0).0, [255; 4]);
+    }
+
+    #[test]
+    fn strict_mode_rejects_unknown_private_tags() {
+        let mut tags = rgba_tags(2, 2);
+        tags.push((51234, Data::Short(vec![1])));
+        let layer = TestIfd {
+            tags,
+            strips: vec![vec![0; 16]],
+            ..TestIfd::default()
+        };
+        let file = tiff_file(Endian::Little, false, &[layer]);
+        assert!(!tiff_tags::is_known_tag(51234));
+
+        let strict = ConvertOptions {
+            strict: true,
+            ..ConvertOptions::default()
+        };
+        assert!(matches!(read_layers(&file, &strict), Err(SkoraError::UnknownTag(51234))));
+        assert!(read_layers(&file, &ConvertOptions::default()).is_ok());
+    }
+
+    /// Convert a layer ifd of the image's size with `tags` added to (or replacing) the RGBA tags
+    fn convert_layer(tags: Vec<(u64, Data)>, image: DynamicImage, options: &ConvertOptions) -> ora::Layer {
+        let mut all: Vec<(u64, Data)> = rgba_tags(image.width(), image.height())
+            .into_iter()
+            .map(|(num, data)| (num as u64, data))
+            .collect();
+        all.extend(tags);
+        match image_to_ora_element(0, &ifd(all), image, options).unwrap() {
+            Element::Layer(layer) => layer,
+            _ => panic!("layer ifd did not convert to a layer element"),
+        }
+    }
+
+    #[test]
+    fn layer_16_bit() {
+        // stored BGRA, opaque so removing the premultiplied alpha leaves the values as they are
+        let stored = ImageBuffer::from_pixel(1, 1, image::Rgba([0x0102_u16, 0x0304, 0x0506, 0xFFFF]));
+        let tags = vec![(258, Data::Short(vec![16; 4]))];
+        let layer = convert_layer(tags, DynamicImage::ImageRgba16(stored), &ConvertOptions::default());
+        match load_from_memory(&layer.image).unwrap() {
+            DynamicImage::ImageRgba16(png) => assert_eq!(png.get_pixel(0, 0).0, [0x0506, 0x0304, 0x0102, 0xFFFF]),
+            _ => panic!("16 bit layer was not written as a 16 bit png"),
+        }
+    }
+
+    #[test]
+    fn grayscale_composite_and_rgb_layer() {
+        let composite = ifd(vec![(305, ascii(tiff::ALIAS_MARKER_V1_1))]);
+        let gray = DynamicImage::ImageLuma8(ImageBuffer::from_pixel(2, 2, image::Luma([77])));
+        match image_to_ora_element(1, &composite, gray, &ConvertOptions::default()).unwrap() {
+            Element::Composite((merged, background)) => {
+                let merged = load_from_memory(&merged).unwrap().into_rgba8();
+                assert_eq!(merged.dimensions(), (2, 2));
+                assert_eq!(merged.get_pixel(1, 1).0, [77, 77, 77, 255]);
+                assert_eq!(background.decoded().unwrap().dimensions(), (2, 2));
+            }
+            _ => panic!("composite ifd did not convert to a composite element"),
+        }
+
+        // stored BGR like the layers with alpha, and opaque without an alpha sample
+        let rgb = DynamicImage::ImageRgb8(ImageBuffer::from_pixel(2, 2, image::Rgb([10, 20, 30])));
+        let tags = vec![(258, Data::Short(vec![8; 3])), (277, Data::Short(vec![3]))];
+        let layer = convert_layer(tags, rgb, &ConvertOptions::default());
+        assert_eq!((layer.width, layer.height), (2, 2));
+        assert_eq!(layer.decoded().unwrap().get_pixel(1, 1).0, [30, 20, 10, 255]);
+    }
+
+    #[test]
+    fn empty_position_tags() {
+        let tags = vec![(286, Data::Rational(Vec::new())), (287, Data::Rational(Vec::new()))];
+        let image = DynamicImage::ImageRgba8(RgbaImage::new(2, 2));
+        let layer = convert_layer(tags, image, &ConvertOptions::default());
+        assert_eq!((layer.x_pos, layer.y_pos), (0, 0));
+    }
+
+    #[test]
+    fn opaque_layer_written_as_rgb() {
+        let mut options = ConvertOptions::default();
+        options.png.layers.color = PngColor::Auto;
+        let layer = |alpha: u8| {
+            let stored = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, image::Rgba([0, 0, alpha, alpha])));
+            load_from_memory(&convert_layer(Vec::new(), stored, &options).image).unwrap()
+        };
+        match layer(255) {
+            DynamicImage::ImageRgb8(png) => assert_eq!(png.get_pixel(0, 0).0, [255, 0, 0]),
+            _ => panic!("opaque layer was not written as RGB"),
+        }
+        assert!(matches!(layer(128), DynamicImage::ImageRgba8(_)));
+        // RGBA is written by default even when the layer is opaque
+        let stored = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, image::Rgba([0, 0, 255, 255])));
+        let png = convert_layer(Vec::new(), stored, &ConvertOptions::default()).image;
+        assert!(matches!(load_from_memory(&png).unwrap(), DynamicImage::ImageRgba8(_)));
+    }
+
+    #[test]
+    fn argb_layer() {
+        let stored = || DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, image::Rgba([255, 10, 20, 30])));
+        let options = ConvertOptions {
+            channel_order: Some(ChannelOrder::Argb),
+            ..ConvertOptions::default()
+        };
+        let layer = convert_layer(Vec::new(), stored(), &options);
+        assert_eq!(layer.decoded().unwrap().get_pixel(0, 0).0, [10, 20, 30, 255]);
+
+        // without the option the order comes from an ExtraSamples tag marking the first sample as alpha
+        let tags = vec![(338, Data::Short(vec![2, 0, 0, 0]))];
+        let layer = convert_layer(tags, stored(), &ConvertOptions::default());
+        assert_eq!(layer.decoded().unwrap().get_pixel(0, 0).0, [10, 20, 30, 255]);
+
+        let half = DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, image::Rgba([128, 10, 20, 30])));
+        let output = reorder_to_rgba(half, ChannelOrder::Argb, Unpremultiply::Skip).unwrap();
+        assert_eq!(output.get_pixel(0, 0).0, [10, 20, 30, 128]);
+    }
+
+    #[test]
+    fn unassociated_alpha_is_not_unpremultiplied() {
+        // stored BGRA at half alpha
+        let stored = || DynamicImage::ImageRgba8(RgbaImage::from_pixel(1, 1, image::Rgba([25, 50, 100, 128])));
+        let pixel = |extra_samples: u16| {
+            let tags = vec![(338, Data::Short(vec![extra_samples]))];
+            let layer = convert_layer(tags, stored(), &ConvertOptions::default());
+            layer.decoded().unwrap().get_pixel(0, 0).0
+        };
+        assert_eq!(pixel(2), [100, 50, 25, 128]);
+        // associated alpha is divided out, which brightens the channels
+        assert!(pixel(1)[0] > 100);
+    }
+
+    #[test]
+    fn layer_without_alias_metadata() {
+        let layer_ifd = ifd(rgba_tags(2, 2).into_iter().map(|(num, data)| (num as u64, data)).collect());
+        let image = DynamicImage::ImageRgba8(RgbaImage::from_pixel(2, 2, image::Rgba([0, 0, 255, 255])));
+        let layer = match image_to_ora_element(0, &layer_ifd, image, &ConvertOptions::default()).unwrap() {
+            Element::Layer(layer) => layer,
+            _ => panic!("layer ifd did not convert to a layer element"),
+        };
+        assert_eq!(layer.opacity, 1.0);
+        assert!(layer.visible);
+        let metadata = layer.metadata.unwrap();
+        assert!(metadata.visible);
+        assert!(!metadata.locked);
+        assert_eq!(metadata.mask_layer_count, 0);
+        assert_eq!(layer.decoded().unwrap().get_pixel(0, 0).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    fn layer_metadata_with_high_bytes() {
+        // 0xe9 is é in Latin-1 but not valid UTF-8 on its own
+        let raw = b"0.5, ff102030, 0, 1, 0, 2, 0, caf\xe9, \xe9t\xe9".to_vec();
+        let count = raw.len() as u64;
+        let alias = match Data::new(raw, crate::tiff_types::DataType::Ascii, Endian::Little, count) {
+            Data::Ascii(val) => val,
+            _ => panic!("ascii data did not decode to a string"),
+        };
+        let values: Vec<&str> = alias.split(", ").collect();
+        assert_eq!(values.len(), 9);
+        let metadata = parse_layer_metadata(&values);
+        assert_eq!(metadata.fill_argb, Some([0xff, 0x10, 0x20, 0x30]));
+        assert!(!metadata.visible);
+        assert!(metadata.locked);
+        assert_eq!(metadata.visibility_channel_count, 2);
+        assert_eq!(metadata.visibility_channels, vec!["caf\u{e9}", "\u{e9}t\u{e9}"]);
+        assert_eq!(metadata.mask_layer_count, 0);
+    }
+
+    /// 3x2 canvas with a red pixel in the top left corner of the composite and of a 1x1 layer, reconciled
+    /// against a composite ifd with the given ImageWidth, ImageLength, and orientation
+    fn reconciled_sample(width: u32, height: u32, orientation: Option<u32>) -> Ora {
+        let mut tags = vec![
+            (256, Data::Long(vec![width])),
+            (257, Data::Long(vec![height])),
+            (305, ascii(tiff::ALIAS_MARKER_V1_1)),
+        ];
+        tags.extend(orientation.map(|val| (274, Data::Short(vec![val as u16]))));
+        let main = ifd(tags);
+
+        let mut composite = RgbaImage::from_pixel(3, 2, image::Rgba([10, 20, 30, 255]));
+        composite.put_pixel(0, 0, image::Rgba([255, 0, 0, 255]));
+        let red = RgbaImage::from_pixel(1, 1, image::Rgba([255, 0, 0, 255]));
+        let mut ora = Ora::new();
+        ora.width = 3;
+        ora.height = 2;
+        ora.merged_image = image_to_buf(composite, PngOptions::default()).unwrap();
+        ora.background = Some(ora::Layer::new(1, ora.merged_image.clone(), 1.0, 0, 0, 3, 2));
+        // y is measured from the bottom so a 1 pixel layer at y 1 is on the top row
+        ora.add_layer(ora::Layer::new(0, image_to_buf(red, PngOptions::default()).unwrap(), 1.0, 0, 1, 1, 1));
+
+        reconcile_canvas(&mut ora, &main, &ConvertOptions::default()).unwrap();
+        ora
+    }
+
+    /// Check the canvas, images, and layer position of a reconciled sample against where the red pixel should be
+    fn check_reconciled(ora: &Ora, size: (u32, u32), position: (u32, u32), red: (u32, u32)) {
+        assert_eq!((ora.width, ora.height), size);
+        let merged = load_from_memory(&ora.merged_image).unwrap().into_rgba8();
+        assert_eq!(merged.dimensions(), size);
+        assert_eq!(merged.get_pixel(red.0, red.1).0, [255, 0, 0, 255]);
+        let background = ora.background.as_ref().unwrap();
+        assert_eq!((background.width, background.height), size);
+        assert_eq!(background.decoded().unwrap().get_pixel(red.0, red.1).0, [255, 0, 0, 255]);
+        let layer = ora.flat_layers()[0];
+        assert_eq!((layer.x_pos, layer.y_pos, layer.width, layer.height), (position.0, position.1, 1, 1));
+        // only the layer's red pixel covers the background, so the flattened image matches the composite
+        let flattened = ora.flatten().unwrap();
+        assert_eq!(flattened.into_raw(), merged.into_raw());
+    }
+
+    #[test]
+    fn swapped_canvas_rotated_clockwise() {
+        let ora = reconciled_sample(3, 2, Some(6));
+        check_reconciled(&ora, (2, 3), (1, 2), (1, 0));
+    }
+
+    #[test]
+    fn swapped_canvas_rotated_counterclockwise() {
+        let ora = reconciled_sample(3, 2, Some(8));
+        check_reconciled(&ora, (2, 3), (0, 0), (0, 2));
+    }
+
+    #[test]
+    fn swapped_canvas_without_orientation_is_not_rotated() {
+        // the tags declare 2x3 but without an orientation there's no way to tell which way to turn
+        let ora = reconciled_sample(2, 3, None);
+        check_reconciled(&ora, (3, 2), (0, 1), (0, 0));
+    }
+}
@@ -0,0 +1,705 @@
+//! Layer Pixel Decoding
+//!
+//! Functions to read the raw pixel samples of an ifd directly out of the
+//! strips of the source tiff rather than handing a reconstructed tiff to
+//! the `image` crate.
+//!
+
+use image::{DynamicImage, RgbaImage};
+
+use crate::endian_rw::{order_read, Endian};
+use crate::error::SkoraError;
+use crate::tiff_types::Ifd;
+
+/// Raw (uncompressed) pixel samples read from an ifd
+#[derive(Clone, Debug)]
+pub struct RawImage {
+    /// Width (in pixels) of the image
+    pub width: u32,
+    /// Height (in pixels) of the image
+    pub height: u32,
+    /// Number of samples (channels) in each pixel
+    pub samples_per_pixel: u16,
+    /// Number of bits in each sample
+    pub bits_per_sample: u16,
+    /// Pixel samples stored row by row, top row first
+    pub data: Vec<u8>,
+}
+
+impl RawImage {
+    /// Convert the samples into an image
+    ///
+    /// # Returns
+    ///
+    /// * The image or None if the samples are not 8 bit RGBA
+    pub fn into_dynamic_image(self) -> Option<DynamicImage> {
+        if self.bits_per_sample != 8 || self.samples_per_pixel != 4 {
+            return None;
+        }
+        RgbaImage::from_raw(self.width, self.height, self.data).map(DynamicImage::ImageRgba8)
+    }
+}
+
+/// Read the values of a numeric tag as a vector of u32
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd containing the tag
+/// * `tag` - Integer tag value
+///
+/// # Returns
+///
+/// * The tag values or None if the tag is not present or is not an integer type
+pub fn tag_u32s(ifd: &Ifd, tag: u64) -> Option<Vec<u32>> {
+    ifd.tag_u32s(tag)
+}
+
+/// Read the first value of a numeric tag, falling back to a default when it is missing
+fn tag_u32_or(ifd: &Ifd, tag: u64, default: u32) -> u32 {
+    tag_u32s(ifd, tag)
+        .and_then(|val| val.first().copied())
+        .unwrap_or(default)
+}
+
+/// Decode the pixels stored in the strips of an ifd
+///
+/// The strips are decompressed and, if the Predictor tag (317) indicates horizontal
+/// differencing, the differencing is reversed so the returned samples are the actual
+/// pixel values.  Images stored as separate planes (PlanarConfiguration = 2) are
+/// interleaved so the samples of each pixel are always packed together.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd to decode
+/// * `source` - The bytes of the original tiff image
+///
+/// # Returns
+///
+/// * Raw image holding the decoded samples
+pub fn decode_layer_pixels(ifd: &Ifd, source: &[u8]) -> Result<RawImage, SkoraError> {
+    let width = tag_u32_or(ifd, 256, 0);
+    let height = tag_u32_or(ifd, 257, 0);
+    let bits_per_sample = tag_u32_or(ifd, 258, 1) as u16;
+    let samples_per_pixel = tag_u32_or(ifd, 277, 1) as u16;
+    let compression = tag_u32_or(ifd, 259, 1);
+    let predictor = tag_u32_or(ifd, 317, 1);
+    let rows_per_strip = tag_u32_or(ifd, 278, height);
+
+    if width == 0 || height == 0 {
+        return Err(SkoraError::InvalidTiff("Image has no width or height".to_string()));
+    }
+
+    let offsets = tag_u32s(ifd, 273).ok_or_else(|| SkoraError::InvalidTiff("Image has no strip offsets".to_string()))?;
+    let byte_counts = tag_u32s(ifd, 279).ok_or_else(|| SkoraError::InvalidTiff("Image has no strip byte counts".to_string()))?;
+    if offsets.len() != byte_counts.len() {
+        return Err(SkoraError::InvalidTiff("Strip offsets and byte counts do not correspond".to_string()));
+    }
+
+    // With PlanarConfiguration (284) = 2 each sample is stored in its own plane, one after the other
+    let planar = tag_u32_or(ifd, 284, 1) == 2;
+    let (plane_count, plane_samples) = match planar {
+        true => (samples_per_pixel as usize, 1_u16),
+        false => (1, samples_per_pixel),
+    };
+    if offsets.len() % plane_count != 0 {
+        return Err(SkoraError::InvalidTiff("Strip count is not a multiple of the number of planes".to_string()));
+    }
+    let strips_per_plane = offsets.len() / plane_count;
+
+    let row_bytes = (width as usize * plane_samples as usize * bits_per_sample as usize + 7) / 8;
+    let plane_bytes = row_bytes * height as usize;
+
+    let mut planes: Vec<Vec<u8>> = Vec::with_capacity(plane_count);
+    for plane_idx in 0..plane_count {
+        let mut data: Vec<u8> = Vec::with_capacity(plane_bytes);
+        for strip_idx in 0..strips_per_plane {
+            let offset = offsets[plane_idx * strips_per_plane + strip_idx];
+            let length = byte_counts[plane_idx * strips_per_plane + strip_idx];
+            let start = offset as usize;
+            // checked so a huge offset and byte count can't wrap around on 32 bit targets
+            let end = match start.checked_add(length as usize) {
+                Some(end) if end <= source.len() => end,
+                _ => return Err(SkoraError::InvalidTiff(format!("Strip {} extends past the end of the file", strip_idx))),
+            };
+
+            let mut strip = decompress_strip(compression, &source[start..end])?;
+
+            // rows are never split across strips so the predictor can be reversed strip by strip
+            let strip_rows = std::cmp::min(
+                rows_per_strip as usize,
+                (height as usize).saturating_sub(strip_idx * rows_per_strip as usize),
+            );
+            strip.resize(strip_rows * row_bytes, 0);
+
+            match predictor {
+                1 => {}
+                2 => undo_horizontal_differencing(&mut strip, row_bytes, plane_samples, bits_per_sample)?,
+                _ => return Err(SkoraError::InvalidTiff(format!("Unsupported predictor {}", predictor))),
+            }
+
+            data.append(&mut strip);
+        }
+        data.resize(plane_bytes, 0);
+        planes.push(data);
+    }
+
+    let data = match planar {
+        true => interleave_planes(&planes, bits_per_sample)?,
+        false => planes.remove(0),
+    };
+
+    let raw = RawImage {
+        width,
+        height,
+        samples_per_pixel,
+        bits_per_sample,
+        data,
+    };
+
+    if is_palette(ifd) {
+        let color_map = tag_u32s(ifd, 320).ok_or_else(|| SkoraError::InvalidTiff("Palette image has no color map".to_string()))?;
+        return expand_palette(&raw, &color_map);
+    }
+    if is_bilevel(ifd) {
+        return expand_bilevel(&raw, tag_u32_or(ifd, 262, 1) == 0);
+    }
+    match sample_format(ifd) {
+        1 => Ok(raw),
+        2 => signed_to_u8(&raw, ifd.endian),
+        3 => float_to_u8(&raw, ifd.endian),
+        format => Err(SkoraError::InvalidTiff(format!("Unsupported sample format {}", format))),
+    }
+}
+
+/// Get how the samples of an ifd are interpreted from the SampleFormat tag (339)
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd
+///
+/// # Returns
+///
+/// * 1 for unsigned integers (the default when there is no tag), 2 for signed integers, 3 for floats
+pub fn sample_format(ifd: &Ifd) -> u32 {
+    tag_u32_or(ifd, 339, 1)
+}
+
+/// Get the position of the alpha sample in each pixel from the SamplesPerPixel (277) and ExtraSamples (338) tags
+///
+/// Extra samples follow the color samples, so the alpha sample is the first extra sample marked as
+/// associated (1) or unassociated (2) alpha, counted from the end of the color samples.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd
+///
+/// # Returns
+///
+/// * Index of the alpha sample or None if the tags don't mark one
+pub fn alpha_sample_index(ifd: &Ifd) -> Option<usize> {
+    let samples_per_pixel = tag_u32_or(ifd, 277, 1) as usize;
+    let extra_samples = tag_u32s(ifd, 338)?;
+    let color_samples = samples_per_pixel.checked_sub(extra_samples.len())?;
+    extra_samples
+        .iter()
+        .position(|val| *val == 1 || *val == 2)
+        .map(|position| color_samples + position)
+}
+
+/// Convert signed integer samples to 8 bit unsigned samples
+///
+/// The samples are offset so the most negative value becomes 0.
+///
+/// # Arguments
+///
+/// * `raw` - Raw image holding 8 or 16 bit signed samples
+/// * `endian` - Byte order of the samples
+///
+/// # Returns
+///
+/// * Raw image holding 8 bit unsigned samples
+pub fn signed_to_u8(raw: &RawImage, endian: Endian) -> Result<RawImage, SkoraError> {
+    let data: Vec<u8> = match raw.bits_per_sample {
+        8 => raw.data.iter().map(|val| (*val as i8 as i16 + 128) as u8).collect(),
+        16 => raw
+            .data
+            .chunks_exact(2)
+            .map(|val| ((order_read(endian, val, 2) as u16 as i16 as i32 + 32768) >> 8) as u8)
+            .collect(),
+        bits => return Err(SkoraError::InvalidTiff(format!("Signed samples must be 8 or 16 bits, not {}", bits))),
+    };
+    Ok(RawImage {
+        width: raw.width,
+        height: raw.height,
+        samples_per_pixel: raw.samples_per_pixel,
+        bits_per_sample: 8,
+        data,
+    })
+}
+
+/// Convert floating point samples to 8 bit unsigned samples
+///
+/// Values are expected to be between 0.0 and 1.0.  Anything outside that range is clamped
+/// rather than tone mapped.
+///
+/// # Arguments
+///
+/// * `raw` - Raw image holding 32 bit float samples
+/// * `endian` - Byte order of the samples
+///
+/// # Returns
+///
+/// * Raw image holding 8 bit unsigned samples
+pub fn float_to_u8(raw: &RawImage, endian: Endian) -> Result<RawImage, SkoraError> {
+    if raw.bits_per_sample != 32 {
+        return Err(SkoraError::InvalidTiff(format!("Float samples must be 32 bits, not {}", raw.bits_per_sample)));
+    }
+    let data: Vec<u8> = raw
+        .data
+        .chunks_exact(4)
+        .map(|val| {
+            let value = f32::from_bits(order_read(endian, val, 4) as u32);
+            match value.is_nan() {
+                true => 0,
+                false => (value.clamp(0.0, 1.0) * 255.0).round() as u8,
+            }
+        })
+        .collect();
+    Ok(RawImage {
+        width: raw.width,
+        height: raw.height,
+        samples_per_pixel: raw.samples_per_pixel,
+        bits_per_sample: 8,
+        data,
+    })
+}
+
+/// Check if the pixels of an ifd are indices into a color map (PhotometricInterpretation = 3)
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd
+///
+/// # Returns
+///
+/// * True if the ifd is a palette color image
+pub fn is_palette(ifd: &Ifd) -> bool {
+    tag_u32_or(ifd, 262, 2) == 3
+}
+
+/// Check if the samples of an ifd are single bits (BitsPerSample = 1) that aren't palette indices
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd
+///
+/// # Returns
+///
+/// * True if the ifd is a bilevel image such as a line art mask
+pub fn is_bilevel(ifd: &Ifd) -> bool {
+    tag_u32_or(ifd, 258, 8) == 1 && !is_palette(ifd)
+}
+
+/// Check if the pixels of an ifd are expanded to 8 bit RGBA when they are decoded
+///
+/// The channels of these images are already in RGBA order and not premultiplied so they must not
+/// be reordered like the BGRA samples of a Sketchbook layer.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd
+///
+/// # Returns
+///
+/// * True if the ifd is a palette color or bilevel image
+pub fn expands_to_rgba(ifd: &Ifd) -> bool {
+    is_palette(ifd) || is_bilevel(ifd)
+}
+
+/// Expand the 1 bit samples of a bilevel image into 8 bit RGBA pixels
+///
+/// Each bit becomes 0 or 255.  One sample is treated as gray, two as gray and alpha, three as RGB,
+/// and four as RGBA.  Pixels without an alpha sample are opaque.
+///
+/// # Arguments
+///
+/// * `raw` - Raw image holding 1 bit samples packed from the most significant bit of each byte
+/// * `white_is_zero` - True if a 0 bit is white (PhotometricInterpretation = 0)
+///
+/// # Returns
+///
+/// * Raw image holding 8 bit RGBA samples
+pub fn expand_bilevel(raw: &RawImage, white_is_zero: bool) -> Result<RawImage, SkoraError> {
+    let samples = raw.samples_per_pixel as usize;
+    if raw.bits_per_sample != 1 || !(1..=4).contains(&samples) {
+        return Err(SkoraError::InvalidTiff(format!(
+            "Bilevel images must have one to four 1 bit samples, not {} {} bit samples",
+            samples, raw.bits_per_sample
+        )));
+    }
+
+    let width = raw.width as usize;
+    let row_bytes = (width * samples + 7) / 8;
+    let mut data: Vec<u8> = Vec::with_capacity(width * raw.height as usize * 4);
+    let mut pixel = [0_u8; 4];
+    for row in raw.data.chunks(row_bytes).take(raw.height as usize) {
+        for column in 0..width {
+            for (sample, value) in pixel.iter_mut().take(samples).enumerate() {
+                let bit = column * samples + sample;
+                let set = row.get(bit / 8).map_or(false, |byte| byte & (0x80 >> (bit % 8)) != 0);
+                *value = if set { 255 } else { 0 };
+            }
+            // only the color samples are inverted, a set alpha bit is always opaque
+            if white_is_zero {
+                let colors = if samples % 2 == 0 { samples - 1 } else { samples };
+                pixel.iter_mut().take(colors).for_each(|value| *value = 255 - *value);
+            }
+            match samples {
+                1 => data.extend_from_slice(&[pixel[0], pixel[0], pixel[0], 255]),
+                2 => data.extend_from_slice(&[pixel[0], pixel[0], pixel[0], pixel[1]]),
+                3 => data.extend_from_slice(&[pixel[0], pixel[1], pixel[2], 255]),
+                _ => data.extend_from_slice(&pixel),
+            }
+        }
+    }
+
+    Ok(RawImage {
+        width: raw.width,
+        height: raw.height,
+        samples_per_pixel: 4,
+        bits_per_sample: 8,
+        data,
+    })
+}
+
+/// Expand the indices of a palette color image into 8 bit RGBA pixels
+///
+/// The ColorMap tag (320) holds all of the red values, then all of the green values, then all of
+/// the blue values with one 16 bit value per possible index.  Palette images have no alpha so every
+/// pixel is opaque.
+///
+/// # Arguments
+///
+/// * `raw` - Raw image holding one index per pixel
+/// * `color_map` - Values of the ColorMap tag
+///
+/// # Returns
+///
+/// * Raw image holding 8 bit RGBA samples
+pub fn expand_palette(raw: &RawImage, color_map: &[u32]) -> Result<RawImage, SkoraError> {
+    let bits = raw.bits_per_sample as usize;
+    if raw.samples_per_pixel != 1 || !matches!(bits, 1 | 2 | 4 | 8) {
+        return Err(SkoraError::InvalidTiff(format!(
+            "Palette images must have a single 1, 2, 4 or 8 bit sample, not {} {} bit samples",
+            raw.samples_per_pixel, bits
+        )));
+    }
+    let colors = 1_usize << bits;
+    if color_map.len() < colors * 3 {
+        return Err(SkoraError::InvalidTiff(format!("Color map has {} entries but needs {}", color_map.len(), colors * 3)));
+    }
+
+    let width = raw.width as usize;
+    let row_bytes = (width * bits + 7) / 8;
+    let mask = (colors - 1) as u8;
+    let mut data: Vec<u8> = Vec::with_capacity(width * raw.height as usize * 4);
+    for row in raw.data.chunks(row_bytes).take(raw.height as usize) {
+        for column in 0..width {
+            // indices are packed from the most significant bit of each byte
+            let bit = column * bits;
+            let index = match row.get(bit / 8) {
+                Some(byte) => ((byte >> (8 - bits - bit % 8)) & mask) as usize,
+                None => 0,
+            };
+            data.push((color_map[index] >> 8) as u8);
+            data.push((color_map[colors + index] >> 8) as u8);
+            data.push((color_map[colors * 2 + index] >> 8) as u8);
+            data.push(255);
+        }
+    }
+
+    Ok(RawImage {
+        width: raw.width,
+        height: raw.height,
+        samples_per_pixel: 4,
+        bits_per_sample: 8,
+        data,
+    })
+}
+
+/// Interleave separately stored sample planes into a single buffer of pixels
+///
+/// # Arguments
+///
+/// * `planes` - One buffer of samples per channel, in channel order
+/// * `bits_per_sample` - Number of bits in each sample
+///
+/// # Returns
+///
+/// * Buffer with the samples of each pixel packed together
+pub fn interleave_planes(planes: &[Vec<u8>], bits_per_sample: u16) -> Result<Vec<u8>, SkoraError> {
+    if bits_per_sample == 0 || bits_per_sample % 8 != 0 {
+        return Err(SkoraError::InvalidTiff(format!(
+            "Separate planes are only supported for byte aligned samples, not {} bits",
+            bits_per_sample
+        )));
+    }
+    let sample_bytes = (bits_per_sample / 8) as usize;
+    let plane_len = planes.iter().map(|plane| plane.len()).min().unwrap_or(0);
+
+    let mut output: Vec<u8> = Vec::with_capacity(plane_len * planes.len());
+    for start in (0..plane_len).step_by(sample_bytes) {
+        for plane in planes {
+            output.extend_from_slice(&plane[start..start + sample_bytes]);
+        }
+    }
+    Ok(output)
+}
+
+/// Decompress the bytes of a single strip
+///
+/// # Arguments
+///
+/// * `compression` - Value of the Compression tag (259)
+/// * `strip` - The compressed strip bytes
+///
+/// # Returns
+///
+/// * The uncompressed strip bytes
+pub fn decompress_strip(compression: u32, strip: &[u8]) -> Result<Vec<u8>, SkoraError> {
+    match compression {
+        // No compression
+        1 => Ok(strip.to_vec()),
+        // LZW
+        5 => {
+            let mut decoder = weezl::decode::Decoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8);
+            decoder
+                .decode(strip)
+                .map_err(|error| SkoraError::InvalidTiff(format!("Unable to decompress LZW strip: {}", error)))
+        }
+        _ => Err(SkoraError::InvalidTiff(format!("Unsupported compression {}", compression))),
+    }
+}
+
+/// Reverse horizontal differencing (Predictor = 2) in place
+///
+/// Each sample after the first pixel in a row is stored as the difference from the
+/// same sample in the previous pixel.  Adding the previous pixel back restores the values.
+///
+/// # Arguments
+///
+/// * `data` - Uncompressed strip data
+/// * `row_bytes` - Number of bytes in each row
+/// * `samples_per_pixel` - Number of samples in each pixel
+/// * `bits_per_sample` - Number of bits in each sample
+pub fn undo_horizontal_differencing(
+    data: &mut [u8],
+    row_bytes: usize,
+    samples_per_pixel: u16,
+    bits_per_sample: u16,
+) -> Result<(), SkoraError> {
+    if bits_per_sample != 8 {
+        return Err(SkoraError::InvalidTiff(format!(
+            "Horizontal differencing is only supported for 8 bit samples, not {}",
+            bits_per_sample
+        )));
+    }
+    let stride = samples_per_pixel as usize;
+    data.chunks_mut(row_bytes).for_each(|row| {
+        for idx in stride..row.len() {
+            row[idx] = row[idx].wrapping_add(row[idx - stride]);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_tiff::ifd;
+    use crate::tiff_types::Data;
+
+    /// Ifd of an image stored in strips at the given (offset, length) positions along with any other tags
+    fn strip_ifd(width: u32, height: u32, strips: &[(u32, u32)], tags: Vec<(u64, Data)>) -> Ifd {
+        let mut all = vec![
+            (256, Data::Long(vec![width])),
+            (257, Data::Long(vec![height])),
+            (273, Data::Long(strips.iter().map(|strip| strip.0).collect())),
+            (279, Data::Long(strips.iter().map(|strip| strip.1).collect())),
+        ];
+        all.extend(tags);
+        ifd(all)
+    }
+
+    #[test]
+    fn lzw_strip_with_horizontal_differencing() {
+        let pixels: Vec<u8> = vec![10, 20, 30, 255, 15, 25, 35, 255, 0, 0, 0, 0, 255, 1, 2, 3];
+        // each sample after the first pixel of a row is the difference from the pixel before it
+        let differenced: Vec<u8> = vec![10, 20, 30, 255, 5, 5, 5, 0, 0, 0, 0, 0, 255, 1, 2, 3];
+        let strip = weezl::encode::Encoder::with_tiff_size_switch(weezl::BitOrder::Msb, 8)
+            .encode(&differenced)
+            .unwrap();
+
+        let layer = strip_ifd(
+            2,
+            2,
+            &[(0, strip.len() as u32)],
+            vec![
+                (258, Data::Short(vec![8; 4])),
+                (259, Data::Short(vec![5])),
+                (277, Data::Short(vec![4])),
+                (317, Data::Short(vec![2])),
+            ],
+        );
+        let raw = decode_layer_pixels(&layer, &strip).unwrap();
+        assert_eq!((raw.width, raw.height, raw.samples_per_pixel, raw.bits_per_sample), (2, 2, 4, 8));
+        assert_eq!(raw.data, pixels);
+    }
+
+    #[test]
+    fn decode_palette_layer() {
+        // 16 color map: all the reds, then the greens, then the blues, each as a 16 bit value
+        let mut color_map = vec![0_u16; 16 * 3];
+        color_map[5] = 0x1234;
+        color_map[16 + 5] = 0xab00;
+        color_map[32 + 5] = 0xffff;
+        color_map[15] = 0xff00;
+        // 4 bit indices 0, 5, and 15 packed from the most significant bit
+        let source = vec![0x05, 0xf0];
+        let layer = strip_ifd(
+            3,
+            1,
+            &[(0, 2)],
+            vec![
+                (258, Data::Short(vec![4])),
+                (262, Data::Short(vec![3])),
+                (277, Data::Short(vec![1])),
+                (320, Data::Short(color_map)),
+            ],
+        );
+        let raw = decode_layer_pixels(&layer, &source).unwrap();
+        assert_eq!((raw.width, raw.height, raw.samples_per_pixel, raw.bits_per_sample), (3, 1, 4, 8));
+        assert_eq!(raw.data, vec![0, 0, 0, 255, 0x12, 0xab, 0xff, 255, 0xff, 0, 0, 255]);
+    }
+
+    #[test]
+    fn decode_float_layer() {
+        let samples: Vec<f32> = vec![1.0, 0.5, 0.0, 1.0, 2.0, -1.0, f32::NAN, 0.25];
+        let source: Vec<u8> = samples.iter().flat_map(|val| val.to_le_bytes().to_vec()).collect();
+        let layer = strip_ifd(
+            2,
+            1,
+            &[(0, source.len() as u32)],
+            vec![
+                (258, Data::Short(vec![32; 4])),
+                (277, Data::Short(vec![4])),
+                (339, Data::Short(vec![3; 4])),
+            ],
+        );
+        let raw = decode_layer_pixels(&layer, &source).unwrap();
+        assert_eq!((raw.width, raw.height, raw.samples_per_pixel, raw.bits_per_sample), (2, 1, 4, 8));
+        // out of range values are clamped and NaN becomes 0
+        assert_eq!(raw.data, vec![255, 128, 0, 255, 255, 0, 0, 64]);
+    }
+
+    /// 3x2 image of 1 bit samples where each row is a single byte
+    fn bilevel_raw(samples_per_pixel: u16, rows: [u8; 2]) -> RawImage {
+        RawImage {
+            width: 3,
+            height: 2,
+            samples_per_pixel,
+            bits_per_sample: 1,
+            data: rows.to_vec(),
+        }
+    }
+
+    /// Gray value of each pixel of an RGBA image
+    fn grays(raw: &RawImage) -> Vec<u8> {
+        raw.data.chunks_exact(4).map(|pixel| pixel[0]).collect()
+    }
+
+    #[test]
+    fn interleave_separate_planes() {
+        let planes = vec![vec![1, 2, 3], vec![4, 5, 6], vec![7, 8, 9]];
+        assert_eq!(interleave_planes(&planes, 8).unwrap(), vec![1, 4, 7, 2, 5, 8, 3, 6, 9]);
+        // 16 bit samples are moved as pairs of bytes
+        let planes = vec![vec![1, 2, 3, 4], vec![5, 6, 7, 8]];
+        assert_eq!(interleave_planes(&planes, 16).unwrap(), vec![1, 2, 5, 6, 3, 4, 7, 8]);
+        assert!(interleave_planes(&planes, 4).is_err());
+    }
+
+    #[test]
+    fn planar_layer() {
+        // 2x1 RGBA image with each channel in its own strip
+        let source: Vec<u8> = vec![10, 11, 20, 21, 30, 31, 40, 41];
+        let strips: Vec<(u32, u32)> = (0..4).map(|plane| (plane * 2, 2)).collect();
+        let layer = strip_ifd(
+            2,
+            1,
+            &strips,
+            vec![
+                (258, Data::Short(vec![8; 4])),
+                (277, Data::Short(vec![4])),
+                (284, Data::Short(vec![2])),
+            ],
+        );
+        let raw = decode_layer_pixels(&layer, &source).unwrap();
+        assert_eq!(raw.data, vec![10, 20, 30, 40, 11, 21, 31, 41]);
+    }
+
+    #[test]
+    fn strip_past_the_end_of_the_file() {
+        let tags = vec![(258, Data::Short(vec![8])), (277, Data::Short(vec![1]))];
+        let layer = strip_ifd(2, 1, &[(u32::MAX, u32::MAX)], tags.clone());
+        assert!(matches!(decode_layer_pixels(&layer, &[0; 4]), Err(SkoraError::InvalidTiff(_))));
+        let layer = strip_ifd(2, 1, &[(3, 2)], tags);
+        assert!(matches!(decode_layer_pixels(&layer, &[0; 4]), Err(SkoraError::InvalidTiff(_))));
+    }
+
+    #[test]
+    fn bilevel_black_is_zero() {
+        let rgba = expand_bilevel(&bilevel_raw(1, [0b1010_0000, 0b0100_0000]), false).unwrap();
+        assert_eq!((rgba.samples_per_pixel, rgba.bits_per_sample), (4, 8));
+        assert_eq!(grays(&rgba), vec![255, 0, 255, 0, 255, 0]);
+        assert!(rgba.data.chunks_exact(4).all(|pixel| pixel[3] == 255));
+    }
+
+    #[test]
+    fn bilevel_white_is_zero() {
+        let rgba = expand_bilevel(&bilevel_raw(1, [0b1010_0000, 0b0100_0000]), true).unwrap();
+        assert_eq!(grays(&rgba), vec![0, 255, 0, 255, 0, 255]);
+    }
+
+    #[test]
+    fn bilevel_alpha_is_not_inverted() {
+        // gray then alpha for each pixel: (1, 1), (0, 1), (0, 0) then (1, 0), (1, 1), (0, 1)
+        let rgba = expand_bilevel(&bilevel_raw(2, [0b1101_0000, 0b1011_0100]), true).unwrap();
+        assert_eq!(rgba.data[..12], [0, 0, 0, 255, 255, 255, 255, 255, 255, 255, 255, 0]);
+        assert_eq!(rgba.data[12..], [0, 0, 0, 0, 0, 0, 0, 255, 255, 255, 255, 255]);
+    }
+
+    #[test]
+    fn bilevel_needs_1_bit_samples() {
+        let mut raw = bilevel_raw(1, [0, 0]);
+        raw.bits_per_sample = 8;
+        assert!(expand_bilevel(&raw, false).is_err());
+        assert!(expand_bilevel(&bilevel_raw(5, [0, 0]), false).is_err());
+    }
+
+    #[test]
+    fn decode_bilevel_layer() {
+        let layer = ifd(vec![
+            (256, Data::Long(vec![3])),
+            (257, Data::Long(vec![2])),
+            (258, Data::Short(vec![1])),
+            (262, Data::Short(vec![1])),
+            (273, Data::Long(vec![0])),
+            (277, Data::Short(vec![1])),
+            (279, Data::Long(vec![2])),
+        ]);
+        assert!(expands_to_rgba(&layer));
+        let rgba = decode_layer_pixels(&layer, &[0b1010_0000, 0b0100_0000]).unwrap();
+        assert_eq!((rgba.width, rgba.height), (3, 2));
+        assert_eq!(grays(&rgba), vec![255, 0, 255, 0, 255, 0]);
+    }
+}
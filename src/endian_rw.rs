@@ -4,8 +4,12 @@
 //! byte sizes in little or big endian order.
 //!
 
+use alloc::format;
+use alloc::vec::Vec;
 use byteorder::{BigEndian, ByteOrder, LittleEndian};
 
+use crate::error::SkoraError;
+
 /// Byte order of the data
 #[derive(Debug, Clone, Copy)]
 pub enum Endian {
@@ -14,44 +18,159 @@ pub enum Endian {
 }
 
 /// Read an n byte integer from a buffer based on the endian order specified
+///
+/// `size` must be between 1 and 8 and no larger than the buffer.  Use `try_order_read` when
+/// the size or buffer length are not known to be valid.
 pub fn order_read(endian: Endian, buffer: &[u8], size: usize) -> u64 {
+    debug_assert!((1..=8).contains(&size), "order_read size must be 1 to 8 bytes, not {}", size);
     match endian {
         Endian::Big => BigEndian::read_uint(buffer, size),
         Endian::Little => LittleEndian::read_uint(buffer, size),
     }
 }
 
-/// Write an 8 byte value to a buffer based on the endian order specified
-pub fn order_write_8(_endian: Endian, buffer: &mut Vec<u8>, data: u8) {
-    buffer.push(data);
+/// Read an n byte integer from a buffer based on the endian order specified, checking the size first
+///
+/// # Arguments
+///
+/// * `endian` - Byte order of the data
+/// * `buffer` - Bytes to read from
+/// * `size` - Number of bytes in the integer (1 to 8)
+///
+/// # Returns
+///
+/// * The integer or an error if the size is out of range or the buffer is too short
+///
+/// # Example
+/// ```rust
+/// let value = try_order_read(Endian::Little, &[1, 0], 2)?;
+/// assert_eq!(value, 1);
+/// ```
+pub fn try_order_read(endian: Endian, buffer: &[u8], size: usize) -> Result<u64, SkoraError> {
+    if !(1..=8).contains(&size) {
+        return Err(SkoraError::InvalidTiff(format!("Can't read a {} byte integer", size)));
+    }
+    if buffer.len() < size {
+        return Err(SkoraError::InvalidTiff(format!(
+            "Can't read a {} byte integer from {} bytes",
+            size,
+            buffer.len()
+        )));
+    }
+    Ok(order_read(endian, buffer, size))
 }
 
-/// Write an 16 byte value to a buffer based on the endian order specified
-pub fn order_write_16(endian: Endian, buffer: &mut Vec<u8>, data: u16) {
-    let mut new_bytes = [0; 2];
-    match endian {
-        Endian::Big => BigEndian::write_u16(&mut new_bytes, data),
-        Endian::Little => LittleEndian::write_u16(&mut new_bytes, data),
+/// Integer that can be written to a buffer in either byte order
+pub trait ByteOrderWritable: Copy {
+    /// Append the bytes of the value to a buffer in the given byte order
+    fn write_ordered(self, endian: Endian, buffer: &mut Vec<u8>);
+}
+
+macro_rules! impl_byte_order_writable {
+    ($($type:ty),*) => {
+        $(
+            impl ByteOrderWritable for $type {
+                fn write_ordered(self, endian: Endian, buffer: &mut Vec<u8>) {
+                    match endian {
+                        Endian::Big => buffer.extend_from_slice(&self.to_be_bytes()),
+                        Endian::Little => buffer.extend_from_slice(&self.to_le_bytes()),
+                    }
+                }
+            }
+        )*
     };
-    buffer.append(&mut new_bytes.to_vec());
 }
 
-/// Write an 32 byte value to a buffer based on the endian order specified
+impl_byte_order_writable!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+/// Write an integer to a buffer based on the endian order specified
+///
+/// The number of bytes written is the size of the integer type.
+///
+/// # Arguments
+///
+/// * `endian` - Byte order of the data
+/// * `buffer` - Buffer to append the bytes to
+/// * `data` - Value to write
+///
+/// # Example
+/// ```rust
+/// let mut buffer = Vec::new();
+/// order_write(Endian::Big, &mut buffer, 0x2A_u16);
+/// assert_eq!(buffer, [0x00, 0x2A]);
+/// ```
+pub fn order_write<T: ByteOrderWritable>(endian: Endian, buffer: &mut Vec<u8>, data: T) {
+    data.write_ordered(endian, buffer);
+}
+
+/// Write a 1 byte (8 bit) value to a buffer based on the endian order specified
+pub fn order_write_8(endian: Endian, buffer: &mut Vec<u8>, data: u8) {
+    order_write(endian, buffer, data);
+}
+
+/// Write a 2 byte (16 bit) value to a buffer based on the endian order specified
+pub fn order_write_16(endian: Endian, buffer: &mut Vec<u8>, data: u16) {
+    order_write(endian, buffer, data);
+}
+
+/// Write a 4 byte (32 bit) value to a buffer based on the endian order specified
 pub fn order_write_32(endian: Endian, buffer: &mut Vec<u8>, data: u32) {
-    let mut new_bytes = [0; 4];
-    match endian {
-        Endian::Big => BigEndian::write_u32(&mut new_bytes, data),
-        Endian::Little => LittleEndian::write_u32(&mut new_bytes, data),
-    };
-    buffer.append(&mut new_bytes.to_vec());
+    order_write(endian, buffer, data);
 }
 
-/// Write an 64 byte value to a buffer based on the endian order specified
+/// Write an 8 byte (64 bit) value to a buffer based on the endian order specified
 pub fn order_write_64(endian: Endian, buffer: &mut Vec<u8>, data: u64) {
-    let mut new_bytes = [0; 8];
-    match endian {
-        Endian::Big => BigEndian::write_u64(&mut new_bytes, data),
-        Endian::Little => LittleEndian::write_u64(&mut new_bytes, data),
-    };
-    buffer.append(&mut new_bytes.to_vec());
+    order_write(endian, buffer, data);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Write a value in both byte orders and read it back
+    fn round_trip<T: ByteOrderWritable>(data: T, size: usize) -> (Vec<u8>, u64, u64) {
+        let mut big = Vec::new();
+        let mut little = Vec::new();
+        order_write(Endian::Big, &mut big, data);
+        order_write(Endian::Little, &mut little, data);
+        assert_eq!(big.len(), size);
+        assert_eq!(little.len(), size);
+        let from_big = order_read(Endian::Big, &big, size);
+        let from_little = order_read(Endian::Little, &little, size);
+        (big, from_big, from_little)
+    }
+
+    #[test]
+    fn order_write_round_trip() {
+        assert_eq!(round_trip(0xAB_u8, 1), (vec![0xAB], 0xAB, 0xAB));
+        assert_eq!(round_trip(0x1234_u16, 2), (vec![0x12, 0x34], 0x1234, 0x1234));
+        assert_eq!(round_trip(0x1234_5678_u32, 4), (vec![0x12, 0x34, 0x56, 0x78], 0x1234_5678, 0x1234_5678));
+        let (big, from_big, from_little) = round_trip(0x0102_0304_0506_0708_u64, 8);
+        assert_eq!(big, vec![1, 2, 3, 4, 5, 6, 7, 8]);
+        assert_eq!((from_big, from_little), (0x0102_0304_0506_0708, 0x0102_0304_0506_0708));
+    }
+
+    #[test]
+    fn order_write_signed() {
+        let (big, from_big, from_little) = round_trip(-2_i16, 2);
+        assert_eq!(big, vec![0xFF, 0xFE]);
+        assert_eq!((from_big, from_little), (0xFFFE, 0xFFFE));
+    }
+
+    #[test]
+    fn sized_writers_match_order_write() {
+        let mut sized = Vec::new();
+        order_write_8(Endian::Little, &mut sized, 1);
+        order_write_16(Endian::Little, &mut sized, 2);
+        order_write_32(Endian::Little, &mut sized, 3);
+        order_write_64(Endian::Little, &mut sized, 4);
+        let mut generic = Vec::new();
+        order_write(Endian::Little, &mut generic, 1_u8);
+        order_write(Endian::Little, &mut generic, 2_u16);
+        order_write(Endian::Little, &mut generic, 3_u32);
+        order_write(Endian::Little, &mut generic, 4_u64);
+        assert_eq!(sized, generic);
+        assert_eq!(sized.len(), 15);
+    }
 }
@@ -0,0 +1,139 @@
+//! Errors
+//!
+//! Error type returned by the functions in this library.
+//!
+
+use alloc::boxed::Box;
+use alloc::string::String;
+use core::fmt;
+
+/// Errors that can occur while reading tiff files or creating open raster files
+#[derive(Debug)]
+pub enum SkoraError {
+    /// The tiff data is not valid
+    InvalidTiff(String),
+    /// The tiff does not contain any image data (no IFDs)
+    NoImageData,
+    /// Image data that should be a png is not valid
+    InvalidPng(String),
+    /// The tiff contains a tag that is not known (only returned in strict mode)
+    UnknownTag(u64),
+    /// There are more layers than can be numbered in an ORA image
+    TooManyLayers,
+    /// An image or file is too large for the format being written or for this platform
+    TooLarge(String),
+    /// An ifd's tag count is zero or too large for its entries to fit in the file, which usually means
+    /// the ifd offset points at something other than an ifd
+    CorruptTagCount {
+        /// Offset of the ifd in the file
+        offset: usize,
+        /// Tag count read from the start of the ifd
+        count: u64,
+    },
+    /// Parsing an ifd failed, with the location of the ifd (and tag) that caused the error
+    ParseAt {
+        /// Offset of the ifd in the file
+        ifd_offset: usize,
+        /// Tag being read when the error occurred or None if it happened outside of a tag
+        tag: Option<u64>,
+        /// The error that occurred
+        source: Box<SkoraError>,
+    },
+    /// Reading or writing a file failed
+    #[cfg(feature = "std")]
+    Io(std::io::Error),
+    /// Decoding or encoding an image failed
+    #[cfg(feature = "std")]
+    Image(image::ImageError),
+    /// Reading or writing an ORA (zip) archive failed
+    #[cfg(feature = "std")]
+    Zip(zip::result::ZipError),
+}
+
+impl fmt::Display for SkoraError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SkoraError::InvalidTiff(val) => write!(f, "Invalid tiff: {}", val),
+            SkoraError::NoImageData => write!(f, "The tiff does not contain any image data"),
+            SkoraError::InvalidPng(val) => write!(f, "Invalid png: {}", val),
+            SkoraError::UnknownTag(val) => write!(f, "Unknown tag: {}", val),
+            SkoraError::TooManyLayers => write!(f, "Too many layers to number in an ora image"),
+            SkoraError::TooLarge(val) => write!(f, "Too large: {}", val),
+            SkoraError::CorruptTagCount { offset, count } => {
+                write!(f, "Ifd at {} has an implausible tag count of {}", offset, count)
+            }
+            SkoraError::ParseAt { ifd_offset, tag: Some(tag), source } => {
+                write!(f, "Tag {} in ifd at offset {:#X}: {}", tag, ifd_offset, source)
+            }
+            SkoraError::ParseAt { ifd_offset, tag: None, source } => {
+                write!(f, "Ifd at offset {:#X}: {}", ifd_offset, source)
+            }
+            #[cfg(feature = "std")]
+            SkoraError::Io(val) => write!(f, "IO error: {}", val),
+            #[cfg(feature = "std")]
+            SkoraError::Image(val) => write!(f, "Image error: {}", val),
+            #[cfg(feature = "std")]
+            SkoraError::Zip(val) => write!(f, "Zip error: {}", val),
+        }
+    }
+}
+
+impl SkoraError {
+    /// Attach the location of the ifd (and tag) being parsed to an error
+    ///
+    /// Errors that already have a location are returned unchanged so the innermost (most specific)
+    /// location is kept as the error propagates out of nested sub-ifds.
+    ///
+    /// # Arguments
+    ///
+    /// * `ifd_offset` - Offset of the ifd in the file
+    /// * `tag` - Tag being read or None if the error happened outside of a tag
+    ///
+    /// # Returns
+    ///
+    /// * The error wrapped in `SkoraError::ParseAt`
+    pub fn parse_at(self, ifd_offset: usize, tag: Option<u64>) -> SkoraError {
+        match self {
+            SkoraError::ParseAt { .. } => self,
+            _ => SkoraError::ParseAt {
+                ifd_offset,
+                tag,
+                source: Box::new(self),
+            },
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl std::error::Error for SkoraError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            SkoraError::Io(val) => Some(val),
+            SkoraError::Image(val) => Some(val),
+            SkoraError::Zip(val) => Some(val),
+            SkoraError::ParseAt { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<std::io::Error> for SkoraError {
+    fn from(error: std::io::Error) -> Self {
+        SkoraError::Io(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<image::ImageError> for SkoraError {
+    fn from(error: image::ImageError) -> Self {
+        SkoraError::Image(error)
+    }
+}
+
+#[cfg(feature = "std")]
+impl From<zip::result::ZipError> for SkoraError {
+    fn from(error: zip::result::ZipError) -> Self {
+        SkoraError::Zip(error)
+    }
+}
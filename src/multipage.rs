@@ -0,0 +1,206 @@
+//! Multi-page Tiff File Format
+//!
+//! Functions to write an open raster image as a conventional multi-page tiff with one page per layer,
+//! which tools such as ImageMagick open as a list of pages.  Unlike a Sketchbook tiff the pages are
+//! chained top level ifds (rather than sub-ifds) with no private Alias tags.  Each page is the size of
+//! the canvas with the layer drawn where it sits on the canvas, stored as a single uncompressed strip
+//! of 8 bit RGBA with unassociated alpha.
+//!
+
+use std::convert::TryFrom;
+use std::path::Path;
+
+use image::RgbaImage;
+
+use crate::endian_rw::{order_write, Endian};
+use crate::error::SkoraError;
+use crate::ora::{Layer, Ora};
+use crate::tiff::{SOFTWARE, SUBFILE_PAGE};
+use crate::tiff_types::Data;
+
+/// Byte order of the written file
+const ENDIAN: Endian = Endian::Little;
+
+impl Ora {
+    /// Write the image to a multi-page tiff file with one page per layer
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Path of the file to write
+    ///
+    /// # Example
+    /// ```rust
+    /// let ora = skora::tiff_to_ora(&file, skora::ConvertOptions::default())?;
+    /// ora.write_multipage_tiff(Path::new("pages.tiff"))?;
+    /// ```
+    pub fn write_multipage_tiff(&self, path: &Path) -> Result<(), SkoraError> {
+        std::fs::write(path, self.to_multipage_tiff()?)?;
+        Ok(())
+    }
+
+    /// Write the image to a multi-page tiff file in memory
+    ///
+    /// Pages are written from the bottom layer (the background, if there is one) up, and each page is
+    /// named after its layer with the PageName tag (285).  Hidden layers are written like any other.
+    ///
+    /// # Returns
+    ///
+    /// * The bytes of the tiff file or an error if it would be larger than a classic tiff can hold (4GB)
+    ///
+    /// # Example
+    /// ```rust
+    /// let bytes = ora.to_multipage_tiff()?;
+    /// ```
+    pub fn to_multipage_tiff(&self) -> Result<Vec<u8>, SkoraError> {
+        let layers: Vec<&Layer> = self.flat_layers().into_iter().rev().collect();
+        let page_count = u16::try_from(layers.len()).map_err(|_| SkoraError::TooManyLayers)?;
+
+        let mut buf: Vec<u8> = b"II".to_vec();
+        order_write(ENDIAN, &mut buf, 42_u16);
+        // position of the pointer to the next ifd, which is filled in as each page is written
+        let mut next_pointer = buf.len();
+        order_write(ENDIAN, &mut buf, 0_u32);
+
+        for (page, layer) in layers.iter().enumerate() {
+            let pixels = self.layer_on_canvas(layer)?;
+            let strip_offset = offset_u32(buf.len())?;
+            buf.extend_from_slice(pixels.as_raw());
+            let strip_length = offset_u32(pixels.as_raw().len())?;
+
+            let entries: Vec<(u16, Data)> = vec![
+                (254, Data::Long(vec![SUBFILE_PAGE])),
+                (256, Data::Long(vec![self.width])),
+                (257, Data::Long(vec![self.height])),
+                (258, Data::Short(vec![8; 4])),
+                // no compression
+                (259, Data::Short(vec![1])),
+                // RGB
+                (262, Data::Short(vec![2])),
+                (273, Data::Long(vec![strip_offset])),
+                (277, Data::Short(vec![4])),
+                (278, Data::Long(vec![self.height])),
+                (279, Data::Long(vec![strip_length])),
+                // chunky (interleaved) samples
+                (284, Data::Short(vec![1])),
+                (285, ascii(&layer.display_name())),
+                (297, Data::Short(vec![page as u16, page_count])),
+                (305, ascii(SOFTWARE)),
+                // unassociated alpha
+                (338, Data::Short(vec![2])),
+            ];
+
+            let ifd = offset_u32(buf.len())?;
+            buf[next_pointer..next_pointer + 4].copy_from_slice(&ifd.to_le_bytes());
+            next_pointer = write_ifd(&mut buf, &entries)?;
+        }
+        Ok(buf)
+    }
+
+    /// Draw a layer onto a transparent image the size of the canvas (anything hanging off the canvas is cropped)
+    fn layer_on_canvas(&self, layer: &Layer) -> Result<RgbaImage, SkoraError> {
+        let pixels = layer.decoded()?;
+        let mut canvas = RgbaImage::new(self.width, self.height);
+        // ora y positions are measured from the bottom, tiff rows from the top
+        let left = layer.x_pos as i64;
+        let top = self.height as i64 - layer.y_pos as i64 - layer.height as i64;
+        for (x, y, pixel) in pixels.enumerate_pixels() {
+            let canvas_x = left + x as i64;
+            let canvas_y = top + y as i64;
+            if canvas_x >= 0 && canvas_y >= 0 && canvas_x < self.width as i64 && canvas_y < self.height as i64 {
+                canvas.put_pixel(canvas_x as u32, canvas_y as u32, *pixel);
+            }
+        }
+        Ok(canvas)
+    }
+}
+
+/// Write an ifd at the end of the buffer, with any values too large to fit in an entry written after it
+///
+/// # Returns
+///
+/// * Position of the (zero) pointer to the next ifd
+fn write_ifd(buf: &mut Vec<u8>, entries: &[(u16, Data)]) -> Result<usize, SkoraError> {
+    let start = buf.len();
+    // tag count, 12 byte entries, then the next ifd pointer
+    let next_pointer = start + 2 + entries.len() * 12;
+    let mut values: Vec<u8> = Vec::new();
+
+    order_write(ENDIAN, buf, entries.len() as u16);
+    for (tag, data) in entries {
+        let datatype = data.datatype();
+        let bytes = data.to_vec_u8(ENDIAN);
+        order_write(ENDIAN, buf, *tag);
+        order_write(ENDIAN, buf, datatype.type_tiff_id() as u16);
+        order_write(ENDIAN, buf, bytes.len() as u32 / datatype.element_size_in_bytes() as u32);
+        match bytes.len() <= 4 {
+            true => {
+                buf.extend_from_slice(&bytes);
+                buf.extend(std::iter::repeat(0).take(4 - bytes.len()));
+            }
+            false => {
+                // values start on a word boundary
+                if values.len() % 2 == 1 {
+                    values.push(0);
+                }
+                order_write(ENDIAN, buf, offset_u32(next_pointer + 4 + values.len())?);
+                values.extend_from_slice(&bytes);
+            }
+        }
+    }
+    order_write(ENDIAN, buf, 0_u32);
+    buf.extend_from_slice(&values);
+    // the next ifd also has to start on a word boundary
+    if buf.len() % 2 == 1 {
+        buf.push(0);
+    }
+    Ok(next_pointer)
+}
+
+/// Make a null terminated Ascii tag value, replacing characters that tiff's 8 bit Ascii can't hold
+fn ascii(text: &str) -> Data {
+    let mut value: String = text
+        .chars()
+        .map(|character| if (character as u32) < 256 { character } else { '?' })
+        .collect();
+    value.push('\0');
+    Data::Ascii(value)
+}
+
+/// Convert a position in the file to an offset, which is limited to 4GB in a classic tiff
+fn offset_u32(position: usize) -> Result<u32, SkoraError> {
+    u32::try_from(position).map_err(|_| SkoraError::TooLarge("Image is too large for a classic tiff file (4GB)".to_string()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::{image_to_buf, PngOptions};
+    use crate::tiff;
+
+    #[test]
+    fn one_top_level_ifd_per_layer() {
+        let mut ora = Ora::new();
+        ora.set_canvas_size(3, 2);
+        for (idx, name) in ["top", "middle"].iter().enumerate() {
+            let png = image_to_buf(RgbaImage::new(1, 1), PngOptions::default()).unwrap();
+            let mut layer = Layer::from_png_bytes(2 - idx as u8, png, 1.0, 0, 0).unwrap();
+            layer.name = Some(name.to_string());
+            ora.add_layer(layer);
+        }
+        let png = image_to_buf(RgbaImage::new(3, 2), PngOptions::default()).unwrap();
+        ora.background = Some(Layer::from_png_bytes(0, png, 1.0, 0, 0).unwrap());
+
+        let file = ora.to_multipage_tiff().unwrap();
+        let (info, ifds) = tiff::read_tiff(&file).unwrap();
+        assert_eq!(info.ifd_count, 3);
+        assert!(ifds.iter().all(|ifd| !ifd.tags.contains_key(&330)));
+
+        let names: Vec<&str> = ifds.iter().map(|ifd| ifd.tags[&285].data.as_string().unwrap()).collect();
+        assert_eq!(names, vec!["Paint Layer 0", "middle", "top"]);
+        for (page, ifd) in ifds.iter().enumerate() {
+            assert_eq!(ifd.tag_u32s(297), Some(vec![page as u32, 3]));
+            assert_eq!(ifd.tag_u32s(256), Some(vec![3]));
+            assert_eq!(ifd.tag_u32s(257), Some(vec![2]));
+        }
+    }
+}
@@ -4,9 +4,16 @@
 //! <https://www.openraster.org>
 //!
 
-use std::io::Write;
+use std::convert::TryFrom;
+use std::io::{Cursor, Read, Seek, Write};
 use std::path::Path;
-use zip::{result::ZipResult, ZipWriter};
+use flate2::write::ZlibEncoder;
+use flate2::Compression;
+use image::{DynamicImage, ImageBuffer, RgbaImage};
+use zip::{result::{ZipError, ZipResult}, write::FileOptions, ZipArchive, ZipWriter};
+
+use crate::error::SkoraError;
+use crate::xml::{self, XmlElement};
 
 /// A piece of data in an open raster image
 pub enum Element {
@@ -23,7 +30,7 @@ pub enum Element {
 pub struct Layer {
     /// Order in which this layer should be placed in the ORA image
     pub layer_number: u8,
-    /// Layer image data (png file)
+    /// Layer image data (png file).  Anything other than a png is rejected when the image is written.
     pub image: Vec<u8>,
     /// Layer opacity
     pub opacity: f32,
@@ -35,6 +42,48 @@ pub struct Layer {
     pub width: u32,
     /// Height (in pixels)  of the layer
     pub height: u32,
+    /// Name shown for the layer.  When None the layer is named `Paint Layer {layer_number}`.
+    pub name: Option<String>,
+    /// Whether the layer is shown
+    pub visible: bool,
+    /// Whether this is the active layer, written as `selected="true"` which Krita uses to pick the
+    /// layer that is active when the file is opened
+    pub selected: bool,
+    /// Sketchbook properties of the layer that ORA has no way to represent
+    pub metadata: Option<LayerMetadata>,
+}
+
+/// Sketchbook layer properties read from the Alias Layer Metadata tag (50784) of a layer ifd
+///
+/// The tag is a comma separated list.  The first seven fields are the opacity, fill color, visible flag,
+/// locked flag, name image present flag, visibility channel count, and mask layer count.  When the
+/// visibility channel count is non-zero Sketchbook writes that many more fields after the first seven.
+/// Their format is not documented so they are kept as the raw text.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct LayerMetadata {
+    /// Fill color of the layer (hex ARGB) as stored
+    pub fill_color: String,
+    /// Fill color of the layer as alpha, red, green, blue, or None if it can't be read.  A fully transparent
+    /// fill (stored as 0) means the layer has no fill.  The fill is not drawn into the layer image, which
+    /// is converted exactly as stored, and ORA has no way to represent it, so it is only kept here.
+    pub fill_argb: Option<[u8; 4]>,
+    /// Whether the layer is visible in Sketchbook
+    pub visible: bool,
+    /// Whether the layer is locked in Sketchbook
+    pub locked: bool,
+    /// Whether an image of the layer name is stored
+    pub name_image_present: bool,
+    /// Number of visibility channels
+    pub visibility_channel_count: u32,
+    /// Raw value of each visibility channel, in the order stored
+    pub visibility_channels: Vec<String>,
+    /// Number of mask layers
+    pub mask_layer_count: u32,
+    /// Horizontal resolution of the layer from the XResolution tag (282).  ORA places every layer at one
+    /// layer pixel per canvas pixel so a layer with a different resolution than the canvas needs rescaling.
+    pub x_resolution: Option<f64>,
+    /// Vertical resolution of the layer from the YResolution tag (283)
+    pub y_resolution: Option<f64>,
 }
 
 impl Layer {
@@ -61,6 +110,193 @@ impl Layer {
             y_pos,
             width,
             height,
+            name: None,
+            visible: true,
+            selected: false,
+            metadata: None,
+        }
+    }
+
+    /// Create a new layer object from a png image, taking the width and height from the png
+    ///
+    /// # Arguments
+    ///
+    /// * `layer_number` - Number of the layer used for its file name in the ORA image
+    /// * `png` - The bytes of the png image
+    /// * `opacity` - Opacity of the layer (0.0 to 1.0)
+    /// * `x_pos` - Horizontal position of the layer
+    /// * `y_pos` - Vertical position of the layer
+    ///
+    /// # Returns
+    ///
+    /// * The layer or an error if the png header can't be read
+    ///
+    /// # Example
+    /// ```rust
+    /// let layer = Layer::from_png_bytes(1, std::fs::read("ink.png")?, 1.0, 0, 0)?;
+    /// ```
+    pub fn from_png_bytes(
+        layer_number: u8,
+        png: Vec<u8>,
+        opacity: f32,
+        x_pos: u32,
+        y_pos: u32,
+    ) -> Result<Layer, SkoraError> {
+        let (width, height) = png_dimensions(&png)?;
+        Ok(Layer::new(layer_number, png, opacity, x_pos, y_pos, width, height))
+    }
+
+    /// Name shown for the layer, which is `Paint Layer {layer_number}` when it has no name
+    ///
+    /// # Example
+    /// ```rust
+    /// let layer = Layer::new(3, image, 1.0, 0, 0, 100, 100);
+    /// assert_eq!(layer.display_name(), "Paint Layer 3");
+    /// ```
+    pub fn display_name(&self) -> String {
+        match &self.name {
+            Some(name) => name.clone(),
+            None => format!("Paint Layer {}", self.layer_number),
+        }
+    }
+
+    /// Decode the layer's png image into raw RGBA pixels
+    ///
+    /// # Example
+    /// ```rust
+    /// let pixels = layer.decoded()?;
+    /// let top_left = pixels.get_pixel(0, 0);
+    /// ```
+    pub fn decoded(&self) -> Result<RgbaImage, SkoraError> {
+        Ok(image::load_from_memory(&self.image)?.into_rgba8())
+    }
+}
+
+/// Group of layers that is written as a nested stack in the ORA image
+#[derive(Clone, Debug)]
+pub struct Group {
+    /// Name of the group
+    pub name: String,
+    /// Layers and groups inside this group, top most first
+    pub children: Vec<LayerNode>,
+}
+
+impl Group {
+    /// Create a new empty group
+    ///
+    /// # Example
+    /// ```rust
+    /// let group = Group::new("Sketch");
+    /// ```
+    pub fn new(name: &str) -> Group {
+        Group {
+            name: name.to_string(),
+            children: Vec::new(),
+        }
+    }
+}
+
+/// Node in the tree of layers that make up an ORA image
+///
+/// Sketchbook does not document how layer groups are stored and no grouping marker has been
+/// identified in either the ifd ordering or the Alias Layer Metadata, so converted files place every
+/// layer at the root of the stack.  Groups can still be built by hand and are written as nested stacks.
+#[derive(Clone, Debug)]
+pub enum LayerNode {
+    /// Single layer
+    Layer(Layer),
+    /// Group of layers
+    Group(Group),
+}
+
+impl LayerNode {
+    /// Collect references to every layer in this node, depth first
+    fn collect_layers<'a>(&'a self, output: &mut Vec<&'a Layer>) {
+        match self {
+            LayerNode::Layer(layer) => output.push(layer),
+            LayerNode::Group(group) => {
+                for child in &group.children {
+                    child.collect_layers(output);
+                }
+            }
+        }
+    }
+
+    /// Collect mutable references to every layer in this node, depth first
+    fn collect_layers_mut<'a>(&'a mut self, output: &mut Vec<&'a mut Layer>) {
+        match self {
+            LayerNode::Layer(layer) => output.push(layer),
+            LayerNode::Group(group) => {
+                for child in group.children.iter_mut() {
+                    child.collect_layers_mut(output);
+                }
+            }
+        }
+    }
+}
+
+/// How a layer is combined with the layers below it
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum BlendMode {
+    /// Layer is drawn over the layers below it (svg:src-over)
+    Normal,
+}
+
+impl BlendMode {
+    /// Blend a single pixel onto another
+    ///
+    /// # Arguments
+    ///
+    /// * `bottom` - Red, green, blue, alpha of the pixel below (0.0 to 1.0, not premultiplied)
+    /// * `top` - Red, green, blue, alpha of the pixel being drawn (0.0 to 1.0, not premultiplied)
+    /// * `opacity` - Opacity of the layer being drawn
+    ///
+    /// # Returns
+    ///
+    /// * Red, green, blue, alpha of the resulting pixel
+    pub fn blend(&self, bottom: [f32; 4], top: [f32; 4], opacity: f32) -> [f32; 4] {
+        match self {
+            BlendMode::Normal => {
+                let top_alpha = top[3] * opacity;
+                let alpha = top_alpha + bottom[3] * (1.0 - top_alpha);
+                if alpha <= 0.0 {
+                    return [0.0; 4];
+                }
+                let channel = |top_value: f32, bottom_value: f32| {
+                    (top_value * top_alpha + bottom_value * bottom[3] * (1.0 - top_alpha)) / alpha
+                };
+                [
+                    channel(top[0], bottom[0]),
+                    channel(top[1], bottom[1]),
+                    channel(top[2], bottom[2]),
+                    alpha,
+                ]
+            }
+        }
+    }
+}
+
+/// Options used to control how an open raster image is written
+#[derive(Clone, Debug)]
+pub struct WriteOptions {
+    /// Write `Thumbnails/thumbnail.png`.  Most ORA readers expect a thumbnail so this is on by default.
+    pub write_thumbnail: bool,
+    /// Store the original file (`Ora::source`) in the archive as `skora/original.tiff` so it can be
+    /// recovered with `Ora::read_embedded_source`.  ORA readers ignore entries they don't know about.
+    pub embed_source: bool,
+}
+
+impl Default for WriteOptions {
+    /// Create a new instance of WriteOptions with default values
+    ///
+    /// # Example
+    /// ```rust
+    /// let options = WriteOptions::default();
+    /// ```
+    fn default() -> Self {
+        WriteOptions {
+            write_thumbnail: true,
+            embed_source: false,
         }
     }
 }
@@ -70,10 +306,23 @@ impl Layer {
 pub struct Ora {
     /// The thumbnail image data (png format)
     pub thumbnail: Vec<u8>,
-    /// Vector of layers that comprise the full image
-    pub layers: Vec<Layer>,
+    /// Tree of layers and groups that comprise the full image
+    pub layers: Vec<LayerNode>,
+    /// Background layer drawn below all other layers
+    pub background: Option<Layer>,
+    /// ICC color profile of the image.  This is embedded in `mergedimage.png` when the file is written.
+    /// ORA has no way to attach a profile to the image as a whole so the layer pngs are left untagged
+    /// and are assumed by readers to be in the same color space as the merged image.
+    pub icc_profile: Option<Vec<u8>>,
+    /// Title of the artwork from the DocumentName tag (269).  This is written to stack.xml as the name of the root stack.
+    pub document_name: Option<String>,
+    /// Notes about the artwork from the ImageDescription tag (270).  ORA has no field for this so it is not written to the file.
+    pub description: Option<String>,
     /// Merged (resultant) image
     pub merged_image: Vec<u8>,
+    /// Bytes of the file the image was converted from.  This is only kept when `WriteOptions::embed_source`
+    /// is set for the conversion and is written to the archive when it is set for writing.
+    pub source: Option<Vec<u8>>,
     /// Image width (in pixels)
     pub width: u32,
     /// Image width (in pixels)
@@ -103,7 +352,12 @@ impl Ora {
         Ora {
             thumbnail: Vec::new(),
             layers: Vec::new(),
+            background: None,
+            icc_profile: None,
+            document_name: None,
+            description: None,
             merged_image: Vec::new(),
+            source: None,
             width: 0,
             height: 0,
         }
@@ -118,13 +372,356 @@ impl Ora {
     /// # Example
     /// ```rust
     /// let mut ora = Ora::default();
-    /// let image : Vec<u8> = vec![1, 2, 3, 4]; // this needs to be an actual png image
+    /// let image: Vec<u8> = std::fs::read("ink.png")?; // layer images must be png, which is checked when the file is written
     /// let layer = Layer::new(1, image, 0.5, 0, 0, 100, 100);
     /// ora.add_layer(layer);
     /// 
     /// ```
     pub fn add_layer(&mut self, layer: Layer) {
-        self.layers.push(layer);
+        self.layers.push(LayerNode::Layer(layer));
+    }
+
+    /// Add a layer on top of the image from a png file
+    ///
+    /// The layer is given the next unused layer number and must fit inside the canvas, so the canvas
+    /// size needs to be set first.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path of the png file
+    /// * `opacity` - Opacity of the layer (0.0 to 1.0)
+    /// * `x_pos` - Horizontal position of the layer
+    /// * `y_pos` - Vertical position of the layer (measured from the bottom of the canvas)
+    ///
+    /// # Returns
+    ///
+    /// * Layer number of the new layer or an error if the png can't be read or doesn't fit the canvas
+    ///
+    /// # Example
+    /// ```rust
+    /// let mut ora = skora::tiff_to_ora(&file, skora::ConvertOptions::default())?;
+    /// ora.add_layer_from_file(Path::new("signature.png"), 1.0, 10, 10)?;
+    /// ora.write_to_file(Path::new("signed.ora"))?;
+    /// ```
+    pub fn add_layer_from_file(&mut self, path: &Path, opacity: f32, x_pos: u32, y_pos: u32) -> Result<u8, SkoraError> {
+        let layer_number = match self.flat_layers().iter().map(|layer| layer.layer_number).max() {
+            Some(val) => val.checked_add(1).ok_or(SkoraError::TooManyLayers)?,
+            None => 0,
+        };
+        let layer = Layer::from_png_bytes(layer_number, std::fs::read(path)?, opacity, x_pos, y_pos)?;
+
+        let fits = |position: u32, size: u32, canvas: u32| position as u64 + size as u64 <= canvas as u64;
+        if !fits(x_pos, layer.width, self.width) || !fits(y_pos, layer.height, self.height) {
+            return Err(SkoraError::TooLarge(format!(
+                "{}x{} layer at {}, {} doesn't fit the {}x{} canvas",
+                layer.width, layer.height, x_pos, y_pos, self.width, self.height
+            )));
+        }
+
+        self.layers.insert(0, LayerNode::Layer(layer));
+        Ok(layer_number)
+    }
+
+    /// Add a group of layers to the image
+    ///
+    /// # Arguments
+    ///
+    /// `group` - Group to add to the image
+    ///
+    /// # Example
+    /// ```rust
+    /// let mut ora = Ora::default();
+    /// let mut group = Group::new("Ink");
+    /// group.children.push(LayerNode::Layer(layer));
+    /// ora.add_group(group);
+    /// ```
+    pub fn add_group(&mut self, group: Group) {
+        self.layers.push(LayerNode::Group(group));
+    }
+
+    /// Change the size of the canvas, keeping every layer the same distance from the top left corner
+    ///
+    /// The canvas doesn't have to match the size of any layer, so this can be used to add a margin around
+    /// the layers or to crop them.  Layer y positions are measured from the bottom of the canvas so they
+    /// are shifted by the change in height.  A layer can't be placed below the bottom of the canvas, so when
+    /// the canvas shrinks enough for a layer to hang off the bottom it is moved up to the bottom edge.
+    /// The merged image and thumbnail are left as they are, use `flatten` to make a merged image that
+    /// matches the new canvas.
+    ///
+    /// # Arguments
+    ///
+    /// * `width` - New width (in pixels) of the canvas
+    /// * `height` - New height (in pixels) of the canvas
+    ///
+    /// # Example
+    /// ```rust
+    /// let mut ora = Ora::new();
+    /// ora.set_canvas_size(100, 100);
+    /// ora.add_layer(Layer::from_png_bytes(0, std::fs::read("ink.png")?, 1.0, 0, 0)?);
+    /// // add a 50 pixel margin on the right and bottom
+    /// ora.set_canvas_size(150, 150);
+    /// ```
+    pub fn set_canvas_size(&mut self, width: u32, height: u32) {
+        let shift = height as i64 - self.height as i64;
+        for layer in self.flat_layers_mut() {
+            layer.y_pos = (layer.y_pos as i64 + shift).clamp(0, u32::MAX as i64) as u32;
+        }
+        self.width = width;
+        self.height = height;
+    }
+
+    /// Get every layer in the image as a flat list, ignoring any grouping
+    ///
+    /// Layers are listed top most first with the background layer (if there is one) last.
+    ///
+    /// # Example
+    /// ```rust
+    /// let ora = Ora::default();
+    /// let layers = ora.flat_layers();
+    /// ```
+    pub fn flat_layers(&self) -> Vec<&Layer> {
+        let mut output = Vec::new();
+        for node in &self.layers {
+            node.collect_layers(&mut output);
+        }
+        if let Some(background) = &self.background {
+            output.push(background);
+        }
+        output
+    }
+
+    /// Get mutable references to every layer in the image as a flat list, ignoring any grouping
+    ///
+    /// Layers are listed in the same order as `flat_layers`.
+    ///
+    /// # Example
+    /// ```rust
+    /// for layer in ora.flat_layers_mut() {
+    ///     layer.visible = true;
+    /// }
+    /// ```
+    pub fn flat_layers_mut(&mut self) -> Vec<&mut Layer> {
+        let mut output = Vec::new();
+        for node in self.layers.iter_mut() {
+            node.collect_layers_mut(&mut output);
+        }
+        if let Some(background) = &mut self.background {
+            output.push(background);
+        }
+        output
+    }
+
+    /// Number the layers contiguously from the bottom up, keeping their draw order
+    ///
+    /// Removing layers (for example hidden or masked ones) leaves gaps in the layer numbers, which
+    /// name the `data/layerN.png` files.  After renumbering the bottom layer (the background, if there
+    /// is one) is 0 and the top most layer is the highest number.  Layers without a name are named from
+    /// their layer number so their default name changes with it.
+    ///
+    /// # Returns
+    ///
+    /// * Ok or an error if there are more layers than can be numbered
+    ///
+    /// # Example
+    /// ```rust
+    /// ora.layers.retain(|node| !matches!(node, LayerNode::Layer(layer) if !layer.visible));
+    /// ora.renumber_layers()?;
+    /// ```
+    pub fn renumber_layers(&mut self) -> Result<(), SkoraError> {
+        for (idx, layer) in self.flat_layers_mut().into_iter().rev().enumerate() {
+            layer.layer_number = u8::try_from(idx).map_err(|_| SkoraError::TooManyLayers)?;
+        }
+        Ok(())
+    }
+
+    /// Composite all the layers into a single image
+    ///
+    /// Layers are drawn from the bottom up (starting with the background layer) using their
+    /// opacity and position.  Hidden layers are skipped and all layers are currently drawn with the normal blend mode.
+    ///
+    /// # Example
+    /// ```rust
+    /// let flattened = ora.flatten()?;
+    /// ```
+    pub fn flatten(&self) -> Result<RgbaImage, SkoraError> {
+        let mut canvas: Vec<[f32; 4]> = vec![[0.0; 4]; self.width as usize * self.height as usize];
+
+        for layer in self.flat_layers().iter().rev().filter(|layer| layer.visible) {
+            let pixels = layer.decoded()?;
+            let left = layer.x_pos as i64;
+            let top = self.height as i64 - layer.y_pos as i64 - layer.height as i64;
+
+            for (x, y, pixel) in pixels.enumerate_pixels() {
+                let canvas_x = left + x as i64;
+                let canvas_y = top + y as i64;
+                if canvas_x < 0 || canvas_y < 0 || canvas_x >= self.width as i64 || canvas_y >= self.height as i64 {
+                    continue;
+                }
+                let idx = canvas_y as usize * self.width as usize + canvas_x as usize;
+                let src = [
+                    pixel[0] as f32 / 255.0,
+                    pixel[1] as f32 / 255.0,
+                    pixel[2] as f32 / 255.0,
+                    pixel[3] as f32 / 255.0,
+                ];
+                canvas[idx] = BlendMode::Normal.blend(canvas[idx], src, layer.opacity);
+            }
+        }
+
+        let buf: Vec<u8> = canvas
+            .iter()
+            .flat_map(|pixel| pixel.iter().map(|val| (val * 255.0).round() as u8))
+            .collect();
+        RgbaImage::from_raw(self.width, self.height, buf).ok_or_else(|| {
+            SkoraError::TooLarge(format!("Canvas of {}x{} is too large to flatten", self.width, self.height))
+        })
+    }
+
+    /// Compare the stored merged image against a freshly flattened copy of the layers
+    ///
+    /// If the layers were reconstructed correctly the two images should be (nearly) identical, so a
+    /// large value indicates a problem with the conversion.
+    ///
+    /// # Returns
+    ///
+    /// * Mean absolute difference of each channel of each pixel (0 to 255) or None if the merged
+    ///   image is missing, can't be decoded, or is a different size than the canvas
+    ///
+    /// # Example
+    /// ```rust
+    /// if let Some(mismatch) = ora.composite_mismatch() {
+    ///     println!("Mean difference from the Sketchbook composite: {}", mismatch);
+    /// }
+    /// ```
+    pub fn composite_mismatch(&self) -> Option<f64> {
+        if self.merged_image.is_empty() {
+            return None;
+        }
+        let merged = image::load_from_memory(&self.merged_image).ok()?.into_rgba8();
+        let flattened = self.flatten().ok()?;
+        if merged.dimensions() != flattened.dimensions() || merged.is_empty() {
+            return None;
+        }
+
+        let total: u64 = merged
+            .iter()
+            .zip(flattened.iter())
+            .map(|(a, b)| (*a as i64 - *b as i64).unsigned_abs())
+            .sum();
+        Some(total as f64 / merged.len() as f64)
+    }
+
+    /// Build the stack.xml element for a single layer
+    fn layer_xml(&self, layer: &Layer) -> XmlElement {
+        let name = layer.display_name();
+        let visibility = match layer.visible {
+            true => "visible",
+            false => "hidden",
+        };
+        XmlElement::new("layer")
+            .attr("src", format!("data/layer{}.png", layer.layer_number))
+            .attr("opacity", xml_opacity(layer.opacity))
+            .attr("x", layer.x_pos)
+            // layers can hang off the top of the canvas so the position is allowed to go negative
+            .attr("y", self.height as i64 - layer.y_pos as i64 - layer.height as i64)
+            .attr("name", name)
+            .attr("visibility", visibility)
+            .attr("composite-op", "svg:src-over")
+            .attr_opt("selected", if layer.selected { Some("true") } else { None })
+    }
+
+    /// Build the stack.xml elements for a list of nodes, recursing into groups
+    fn nodes_xml(&self, nodes: &[LayerNode]) -> Vec<XmlElement> {
+        nodes
+            .iter()
+            .map(|node| match node {
+                LayerNode::Layer(layer) => self.layer_xml(layer),
+                LayerNode::Group(group) => {
+                    let mut stack = XmlElement::new("stack").attr("name", &group.name);
+                    stack.children = self.nodes_xml(&group.children);
+                    stack
+                }
+            })
+            .collect()
+    }
+
+    /// Build the stack.xml document describing the layers of the image
+    ///
+    /// # Example
+    /// ```rust
+    /// let ora = Ora::new();
+    /// println!("{}", ora.stack_xml());
+    /// ```
+    pub fn stack_xml(&self) -> String {
+        let mut stack = XmlElement::new("stack").attr_opt("name", self.document_name.as_ref());
+        stack.children = self.nodes_xml(&self.layers);
+
+        // write background layer after all the other layers
+        if let Some(background) = &self.background {
+            stack.push(self.layer_xml(background));
+        }
+
+        let resolution = 100;
+        let mut image = XmlElement::new("image")
+            .attr("version", "0.0.3")
+            .attr("w", self.width)
+            .attr("h", self.height)
+            .attr("xres", resolution)
+            .attr("yres", resolution);
+        image.push(stack);
+        xml::document(&image)
+    }
+
+    /// Read the original file stored in an ora file written with `WriteOptions::embed_source`
+    ///
+    /// # Arguments
+    ///
+    /// `reader` - The ora file
+    ///
+    /// # Returns
+    ///
+    /// * The bytes of the original file, None if the ora file doesn't have one, or an error if the zip can't be read
+    ///
+    /// # Example
+    /// ```rust
+    /// let file = std::fs::File::open("image.ora")?;
+    /// if let Some(tiff) = Ora::read_embedded_source(file)? {
+    ///     std::fs::write("image.tiff", tiff)?;
+    /// }
+    /// ```
+    pub fn read_embedded_source<R: Read + Seek>(reader: R) -> ZipResult<Option<Vec<u8>>> {
+        let mut archive = ZipArchive::new(reader)?;
+        let mut entry = match archive.by_name(SOURCE_ENTRY) {
+            Ok(entry) => entry,
+            Err(ZipError::FileNotFound) => return Ok(None),
+            Err(error) => return Err(error),
+        };
+        let mut source: Vec<u8> = Vec::with_capacity(entry.size() as usize);
+        entry.read_to_end(&mut source)?;
+        Ok(Some(source))
+    }
+
+    /// Check if the image data is large enough that the ora file needs zip64 records
+    ///
+    /// Without zip64 an archive is limited to 4GB, which a high resolution 16 bit image with many
+    /// layers can pass.
+    ///
+    /// # Example
+    /// ```rust
+    /// let ora = Ora::new();
+    /// assert!(!ora.needs_zip64());
+    /// ```
+    pub fn needs_zip64(&self) -> bool {
+        let total: u64 = self
+            .flat_layers()
+            .iter()
+            .map(|layer| layer.image.len() as u64)
+            .sum::<u64>()
+            + self.merged_image.len() as u64
+            + self.thumbnail.len() as u64
+            + self.source.as_ref().map_or(0, |source| source.len() as u64);
+        // leave room for stack.xml, the blank pngs, and the zip headers
+        total >= u32::MAX as u64 - (64 * 1024 * 1024)
     }
 
     /// Write the image to a file
@@ -141,69 +738,814 @@ impl Ora {
     /// ora.write_to_file(&file_path)?;
     /// ```
     pub fn write_to_file(&self, path: &Path) -> ZipResult<()> {
-        let file = std::fs::File::create(&path).unwrap();
+        let file = std::fs::File::create(&path)?;
+        self.write_to(file)
+    }
+
+    /// Write the image to a buffer in memory
+    ///
+    /// # Returns
+    ///
+    /// * The bytes of the ora file
+    ///
+    /// # Example
+    /// ```rust
+    /// let mut ora = Ora::new();
+    /// let bytes = ora.to_bytes()?;
+    /// ```
+    pub fn to_bytes(&self) -> ZipResult<Vec<u8>> {
+        let mut buf = Cursor::new(Vec::new());
+        self.write_to(&mut buf)?;
+        Ok(buf.into_inner())
+    }
+
+    /// Write the image to any seekable writer
+    ///
+    /// # Arguments
+    ///
+    /// `writer` - Where to write the ora file
+    ///
+    /// # Example
+    /// ```rust
+    /// let mut ora = Ora::new();
+    /// let mut buf = std::io::Cursor::new(Vec::new());
+    /// ora.write_to(&mut buf)?;
+    /// ```
+    pub fn write_to<W: Write + Seek>(&self, writer: W) -> ZipResult<()> {
+        self.write_with_options(writer, &WriteOptions::default())
+    }
+
+    /// Write the image to any seekable writer with options controlling what is written
+    ///
+    /// The layer, merged, and thumbnail images are checked to be png images before they are written and
+    /// an error is returned if any is not.
+    ///
+    /// # Arguments
+    ///
+    /// `writer` - Where to write the ora file
+    /// `options` - Options controlling how the file is written
+    ///
+    /// # Example
+    /// ```rust
+    /// let options = WriteOptions { write_thumbnail: false, ..WriteOptions::default() };
+    /// let file = std::fs::File::create("image.ora")?;
+    /// ora.write_with_options(file, &options)?;
+    /// ```
+    pub fn write_with_options<W: Write + Seek>(&self, writer: W, options: &WriteOptions) -> ZipResult<()> {
+        let mut zip = ZipWriter::new(writer);
 
-        let mut zip = ZipWriter::new(file);
+        // zip64 records are only needed (and only written) when the archive could pass the 4GB limit
+        let file_options = FileOptions::default().large_file(self.needs_zip64());
 
-        zip.start_file("mimetype", Default::default())?;
+        zip.start_file("mimetype", mimetype_options())?;
         zip.write_all(b"image/openraster")?;
 
-        let mut layers_xml = String::new();
+        let xml = self.stack_xml();
+
+        zip.start_file("stack.xml", file_options)?;
+        zip.write_all(xml.as_bytes())?;
+
+        write_merged_image(&mut zip, &self.merged_image, self, file_options)?;
+
+        zip.add_directory("data/", file_options)?;
+        for layer in self.flat_layers() {
+            write_layer_png(&mut zip, layer, file_options)?;
+        }
+
+        if options.write_thumbnail {
+            write_thumbnail(&mut zip, &self.thumbnail, self.width, self.height, file_options)?;
+        }
+
+        if let (true, Some(source)) = (options.embed_source, &self.source) {
+            write_source(&mut zip, source, file_options)?;
+        }
+
+        zip.finish()?;
+        Ok(())
+    }
+}
+
+/// Write `mergedimage.png`, embedding the ICC profile of the canvas when it has one
+///
+/// An ora without any image data (no composite or layers) still needs valid pngs to be readable so
+/// a missing merged image is written as a blank image the size of the canvas.
+fn write_merged_image<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    merged_image: &[u8],
+    canvas: &Ora,
+    file_options: FileOptions,
+) -> ZipResult<()> {
+    if !merged_image.is_empty() {
+        check_png_entry("mergedimage.png", merged_image)?;
+    }
+    zip.start_file("mergedimage.png", file_options)?;
+    match merged_image.is_empty() {
+        true => zip.write_all(&blank_png(canvas.width, canvas.height)?)?,
+        false => match &canvas.icc_profile {
+            Some(profile) => zip.write_all(
+                &embed_icc_profile(merged_image, profile)
+                    .map_err(|error| ZipError::Io(std::io::Error::other(error)))?,
+            )?,
+            None => zip.write_all(merged_image)?,
+        },
+    }
+    Ok(())
+}
+
+/// Write the png image of a layer to `data/layer{layer_number}.png`
+fn write_layer_png<W: Write + Seek>(zip: &mut ZipWriter<W>, layer: &Layer, file_options: FileOptions) -> ZipResult<()> {
+    let name = format!("data/layer{:?}.png", layer.layer_number);
+    check_png_entry(&name, &layer.image)?;
+    zip.start_file(name, file_options)?;
+    zip.write_all(&layer.image)?;
+    Ok(())
+}
+
+/// Write `Thumbnails/thumbnail.png`, using a blank image (256x256 max) when there is no thumbnail
+fn write_thumbnail<W: Write + Seek>(
+    zip: &mut ZipWriter<W>,
+    thumbnail: &[u8],
+    width: u32,
+    height: u32,
+    file_options: FileOptions,
+) -> ZipResult<()> {
+    if !thumbnail.is_empty() {
+        check_png_entry("Thumbnails/thumbnail.png", thumbnail)?;
+    }
+    zip.add_directory("Thumbnails/", file_options)?;
+    zip.start_file("Thumbnails/thumbnail.png", file_options)?;
+    match thumbnail.is_empty() {
+        true => zip.write_all(&blank_png(width.min(256), height.min(256))?)?,
+        false => zip.write_all(thumbnail)?,
+    }
+    Ok(())
+}
+
+/// Check that image data about to be written to the archive is a png
+///
+/// Only the signature and IHDR chunk are checked (not the whole image) so anything else, such as a
+/// jpeg or raw pixels, is caught before it makes a file that readers like Krita can't open.
+fn check_png_entry(name: &str, png: &[u8]) -> ZipResult<()> {
+    check_png_header(png).map_err(|error| {
+        ZipError::Io(std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!("{} is not a png image : {}", name, error),
+        ))
+    })
+}
+
+/// Problem found in an ora file by `validate`
+#[derive(Clone, Debug, PartialEq)]
+pub enum ValidationIssue {
+    /// The file can't be opened as a zip archive
+    NotZip(String),
+    /// The mimetype entry is missing, is not the first entry, is compressed, or doesn't hold `image/openraster`
+    Mimetype(String),
+    /// stack.xml is missing, is not well formed, or doesn't describe an image
+    StackXml(String),
+    /// An entry the spec requires is missing
+    MissingEntry(String),
+    /// A png in the archive can't be read or is the wrong size
+    Image {
+        /// Name of the entry
+        entry: String,
+        /// What is wrong with it
+        reason: String,
+    },
+}
+
+impl std::fmt::Display for ValidationIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ValidationIssue::NotZip(reason) => write!(f, "Not a zip archive: {}", reason),
+            ValidationIssue::Mimetype(reason) => write!(f, "mimetype: {}", reason),
+            ValidationIssue::StackXml(reason) => write!(f, "stack.xml: {}", reason),
+            ValidationIssue::MissingEntry(entry) => write!(f, "{} is missing", entry),
+            ValidationIssue::Image { entry, reason } => write!(f, "{}: {}", entry, reason),
+        }
+    }
+}
+
+/// Check an ora file against the OpenRaster spec
+///
+/// The checks are that the mimetype entry is first, stored uncompressed, and holds `image/openraster`;
+/// stack.xml is well formed with an image size and a root stack; every layer `src` is a png in the
+/// archive; and `mergedimage.png` (which must be the size of the image) and `Thumbnails/thumbnail.png`
+/// (which must be no larger than 256x256) are present.  Only the png headers are read, not the pixels.
+///
+/// # Arguments
+///
+/// * `bytes` - The bytes of the ora file
+///
+/// # Returns
+///
+/// * Ok or every problem found
+///
+/// # Example
+/// ```rust
+/// let bytes = ora.to_bytes()?;
+/// if let Err(issues) = skora::ora::validate(&bytes) {
+///     for issue in issues {
+///         println!("{}", issue);
+///     }
+/// }
+/// ```
+pub fn validate(bytes: &[u8]) -> Result<(), Vec<ValidationIssue>> {
+    let mut archive = ZipArchive::new(Cursor::new(bytes)).map_err(|error| vec![ValidationIssue::NotZip(error.to_string())])?;
+    let mut issues: Vec<ValidationIssue> = Vec::new();
+
+    match archive.by_index(0) {
+        Ok(mut entry) if entry.name() == "mimetype" => {
+            if entry.compression() != zip::CompressionMethod::Stored {
+                issues.push(ValidationIssue::Mimetype("The entry is compressed".to_string()));
+            }
+            let mut mimetype = String::new();
+            if entry.read_to_string(&mut mimetype).is_err() || mimetype != "image/openraster" {
+                issues.push(ValidationIssue::Mimetype(format!("Expected image/openraster but found {:?}", mimetype)));
+            }
+        }
+        Ok(entry) => issues.push(ValidationIssue::Mimetype(format!("The first entry is {} rather than mimetype", entry.name()))),
+        Err(error) => issues.push(ValidationIssue::Mimetype(error.to_string())),
+    }
 
-        for layer in &self.layers {
-            if layer.layer_number > 0 {
-                let layer_info = format!(
-                    include_str!("ora_layer.xml"),
-                    layer_number = layer.layer_number,
-                    opacity = layer.opacity,
-                    x_pos = layer.x_pos,
-                    y_pos = self.height - layer.y_pos - layer.height,
-                );
+    let mut stack_xml = String::new();
+    let image = match archive.by_name("stack.xml") {
+        Ok(mut entry) => match entry.read_to_string(&mut stack_xml) {
+            Ok(_) => xml::parse(&stack_xml).map_err(ValidationIssue::StackXml),
+            Err(error) => Err(ValidationIssue::StackXml(error.to_string())),
+        },
+        Err(_) => Err(ValidationIssue::MissingEntry("stack.xml".to_string())),
+    };
+    let image = match image {
+        Ok(image) => Some(image),
+        Err(issue) => {
+            issues.push(issue);
+            None
+        }
+    };
 
-                layers_xml.push_str(&layer_info);
-                layers_xml.push('\n');
+    let mut canvas: Option<(u32, u32)> = None;
+    if let Some(image) = &image {
+        let size = |name: &str| image.get_attr(name).and_then(|value| value.parse::<u32>().ok()).filter(|value| *value > 0);
+        match (image.name.as_str(), size("w"), size("h")) {
+            ("image", Some(width), Some(height)) => canvas = Some((width, height)),
+            ("image", _, _) => issues.push(ValidationIssue::StackXml("The image has no valid w and h".to_string())),
+            (name, _, _) => issues.push(ValidationIssue::StackXml(format!("The root element is {} rather than image", name))),
+        }
+        match image.children.as_slice() {
+            [stack] if stack.name == "stack" => {
+                let mut sources: Vec<&str> = Vec::new();
+                collect_sources(stack, &mut sources, &mut issues);
+                for source in sources {
+                    if let Err(issue) = check_png_entry_size(&mut archive, source, None) {
+                        issues.push(issue);
+                    }
+                }
             }
+            _ => issues.push(ValidationIssue::StackXml("The image must hold exactly one stack".to_string())),
         }
+    }
 
-        // write background layer after all the other layers
-        let layer_info = format!(
-            include_str!("ora_layer.xml"),
-            layer_number = &self.layers[0].layer_number,
-            opacity = &self.layers[0].opacity,
-            x_pos = &self.layers[0].x_pos,
-            y_pos = self.height - self.layers[0].y_pos - self.layers[0].height,
-        );
-        layers_xml.push_str(&layer_info);
-        layers_xml.push('\n');
-
-        let xml = format!(
-            include_str!("ora_stack.xml"),
-            width = self.width,
-            height = self.height,
-            resolution = 100,
-            layers = layers_xml,
-        );
-
-        zip.start_file("stack.xml", Default::default())?;
-        zip.write_all(xml.as_bytes())?;
+    if let Err(issue) = check_png_entry_size(&mut archive, "mergedimage.png", canvas) {
+        issues.push(issue);
+    }
+    match check_png_entry_size(&mut archive, "Thumbnails/thumbnail.png", None) {
+        Ok((width, height)) if width > 256 || height > 256 => issues.push(ValidationIssue::Image {
+            entry: "Thumbnails/thumbnail.png".to_string(),
+            reason: format!("{}x{} is larger than 256x256", width, height),
+        }),
+        Ok(_) => {}
+        Err(issue) => issues.push(issue),
+    }
 
-        zip.start_file("mergedimage.png", Default::default())?;
-        zip.write_all(&self.merged_image)?;
+    match issues.is_empty() {
+        true => Ok(()),
+        false => Err(issues),
+    }
+}
 
-        zip.add_directory("data/", Default::default())?;
-        for layer in &self.layers {
-            zip.start_file(
-                format!("data/layer{:?}.png", layer.layer_number),
-                Default::default(),
-            )?;
-            zip.write_all(&layer.image)?;
+/// Collect the `src` of every layer in a stack (and the stacks nested in it)
+fn collect_sources<'a>(stack: &'a XmlElement, sources: &mut Vec<&'a str>, issues: &mut Vec<ValidationIssue>) {
+    for child in &stack.children {
+        match child.name.as_str() {
+            "stack" => collect_sources(child, sources, issues),
+            "layer" => match child.get_attr("src") {
+                Some(source) => sources.push(source),
+                None => issues.push(ValidationIssue::StackXml("A layer has no src".to_string())),
+            },
+            _ => {}
         }
+    }
+}
 
-        zip.add_directory("Thumbnails/", Default::default())?;
-        zip.start_file("Thumbnails/thumbnail.png", Default::default())?;
-        zip.write_all(&self.thumbnail)?;
+/// Read the size of a png entry in the archive, checking it against an expected size when one is given
+fn check_png_entry_size<R: Read + Seek>(
+    archive: &mut ZipArchive<R>,
+    name: &str,
+    expected: Option<(u32, u32)>,
+) -> Result<(u32, u32), ValidationIssue> {
+    let invalid = |reason: String| ValidationIssue::Image {
+        entry: name.to_string(),
+        reason,
+    };
+    let entry = archive
+        .by_name(name)
+        .map_err(|_| ValidationIssue::MissingEntry(name.to_string()))?;
+    let mut header: Vec<u8> = Vec::with_capacity(IHDR_END);
+    entry
+        .take(IHDR_END as u64)
+        .read_to_end(&mut header)
+        .map_err(|error| invalid(error.to_string()))?;
+    let size = png_dimensions(&header).map_err(|error| invalid(error.to_string()))?;
+    match expected {
+        Some(expected) if expected != size => Err(invalid(format!(
+            "{}x{} doesn't match the {}x{} image",
+            size.0, size.1, expected.0, expected.1
+        ))),
+        _ => Ok(size),
+    }
+}
 
-        zip.finish()?;
+/// Name of the archive entry holding the original file
+const SOURCE_ENTRY: &str = "skora/original.tiff";
+
+/// Write the original file to `skora/original.tiff`
+fn write_source<W: Write + Seek>(zip: &mut ZipWriter<W>, source: &[u8], file_options: FileOptions) -> ZipResult<()> {
+    zip.add_directory("skora/", file_options)?;
+    zip.start_file(SOURCE_ENTRY, file_options)?;
+    zip.write_all(source)?;
+    Ok(())
+}
+
+/// Receiver for the pieces of an open raster image as they are decoded
+///
+/// Used by `convert_streaming` so each layer can be written out (or otherwise processed) and dropped
+/// before the next one is decoded instead of holding the whole image in memory.  `begin` is called
+/// first and `finish` last.  In between `layer` is called for each layer (top most first) followed by
+/// `composite` and `thumbnail`, each of which is only called if the tiff file has that image.
+pub trait OraSink {
+    /// Start the image
+    ///
+    /// `header` holds the canvas size, ICC profile, document name, description, and (when it is to be
+    /// embedded) the original file.  It has no layers or image data and the canvas size is replaced by the composite's size if they differ.
+    fn begin(&mut self, header: &Ora) -> Result<(), SkoraError>;
+    /// Receive a single layer
+    fn layer(&mut self, layer: &Layer) -> Result<(), SkoraError>;
+    /// Receive the merged image (png format) and the background layer (None when the background is omitted)
+    fn composite(&mut self, merged_image: &[u8], background: Option<&Layer>) -> Result<(), SkoraError>;
+    /// Receive the thumbnail image (png format)
+    fn thumbnail(&mut self, thumbnail: &[u8]) -> Result<(), SkoraError>;
+    /// End the image
+    fn finish(&mut self) -> Result<(), SkoraError>;
+}
+
+/// Sink that writes an open raster file as the pieces arrive
+///
+/// Layer pngs are written to the zip as soon as they are received and only their position, opacity,
+/// and name are kept (to write stack.xml at the end), so the peak memory is roughly one layer.  As the
+/// size of the archive isn't known up front every entry is written with zip64 records.
+pub struct ZipOraSink<W: Write + Seek> {
+    /// Zip archive being written
+    zip: ZipWriter<W>,
+    /// Options controlling what is written
+    options: WriteOptions,
+    /// Image properties and every layer received so far (without its image data)
+    stack: Ora,
+    /// Whether `mergedimage.png` has been written
+    merged_written: bool,
+    /// Whether `Thumbnails/thumbnail.png` has been written
+    thumbnail_written: bool,
+}
+
+impl<W: Write + Seek> ZipOraSink<W> {
+    /// Create a new sink that writes an open raster file
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Where to write the ora file
+    /// * `options` - Options controlling how the file is written
+    ///
+    /// # Example
+    /// ```rust
+    /// let file = std::fs::File::create("image.ora")?;
+    /// let mut sink = ZipOraSink::new(file, WriteOptions::default());
+    /// ```
+    pub fn new(writer: W, options: WriteOptions) -> ZipOraSink<W> {
+        ZipOraSink {
+            zip: ZipWriter::new(writer),
+            options,
+            stack: Ora::new(),
+            merged_written: false,
+            thumbnail_written: false,
+        }
+    }
+
+    /// Options for each file in the archive
+    fn file_options(&self) -> FileOptions {
+        FileOptions::default().large_file(true)
+    }
+}
+
+impl<W: Write + Seek> OraSink for ZipOraSink<W> {
+    fn begin(&mut self, header: &Ora) -> Result<(), SkoraError> {
+        self.stack.width = header.width;
+        self.stack.height = header.height;
+        self.stack.icc_profile = header.icc_profile.clone();
+        self.stack.document_name = header.document_name.clone();
+        self.stack.description = header.description.clone();
+
+        let file_options = self.file_options();
+        self.zip.start_file("mimetype", mimetype_options())?;
+        self.zip.write_all(b"image/openraster")?;
+        if let (true, Some(source)) = (self.options.embed_source, &header.source) {
+            write_source(&mut self.zip, source, file_options)?;
+        }
+        self.zip.add_directory("data/", file_options)?;
+        Ok(())
+    }
+
+    fn layer(&mut self, layer: &Layer) -> Result<(), SkoraError> {
+        let file_options = self.file_options();
+        write_layer_png(&mut self.zip, layer, file_options)?;
+        self.stack.add_layer(without_image(layer));
+        Ok(())
+    }
+
+    fn composite(&mut self, merged_image: &[u8], background: Option<&Layer>) -> Result<(), SkoraError> {
+        if let Some(background) = background {
+            self.stack.width = background.width;
+            self.stack.height = background.height;
+            let file_options = self.file_options();
+            write_layer_png(&mut self.zip, background, file_options)?;
+            self.stack.background = Some(without_image(background));
+        }
+
+        let file_options = self.file_options();
+        write_merged_image(&mut self.zip, merged_image, &self.stack, file_options)?;
+        self.merged_written = true;
+        Ok(())
+    }
+
+    fn thumbnail(&mut self, thumbnail: &[u8]) -> Result<(), SkoraError> {
+        if self.options.write_thumbnail && !self.thumbnail_written {
+            let file_options = self.file_options();
+            write_thumbnail(&mut self.zip, thumbnail, self.stack.width, self.stack.height, file_options)?;
+            self.thumbnail_written = true;
+        }
+        Ok(())
+    }
+
+    fn finish(&mut self) -> Result<(), SkoraError> {
+        let file_options = self.file_options();
+        if !self.merged_written {
+            write_merged_image(&mut self.zip, &[], &self.stack, file_options)?;
+            self.merged_written = true;
+        }
+        if self.options.write_thumbnail && !self.thumbnail_written {
+            write_thumbnail(&mut self.zip, &[], self.stack.width, self.stack.height, file_options)?;
+            self.thumbnail_written = true;
+        }
+
+        self.zip.start_file("stack.xml", file_options)?;
+        self.zip.write_all(self.stack.stack_xml().as_bytes())?;
+        self.zip.finish()?;
         Ok(())
     }
 }
+
+/// Options for the mimetype entry
+///
+/// The spec requires it to be stored uncompressed (so the file type can be read at a fixed offset from
+/// the start of the archive), which also rules out zip64 records as it is always tiny.
+fn mimetype_options() -> FileOptions {
+    FileOptions::default().compression_method(zip::CompressionMethod::Stored)
+}
+
+/// Copy everything about a layer other than its image data
+fn without_image(layer: &Layer) -> Layer {
+    Layer {
+        layer_number: layer.layer_number,
+        image: Vec::new(),
+        opacity: layer.opacity,
+        x_pos: layer.x_pos,
+        y_pos: layer.y_pos,
+        width: layer.width,
+        height: layer.height,
+        name: layer.name.clone(),
+        visible: layer.visible,
+        selected: layer.selected,
+        metadata: layer.metadata.clone(),
+    }
+}
+
+/// Embed an ICC color profile in a png image by adding an iCCP chunk
+///
+/// # Arguments
+///
+/// * `png` - The bytes of the png image
+/// * `profile` - The bytes of the ICC profile
+///
+/// # Returns
+///
+/// * The bytes of the png image including the profile
+pub fn embed_icc_profile(png: &[u8], profile: &[u8]) -> Result<Vec<u8>, SkoraError> {
+    check_png_header(png)?;
+
+    // iCCP data is the profile name, a null separator, the compression method (0 = zlib), then the compressed profile
+    let mut data: Vec<u8> = b"ICC Profile\0\0".to_vec();
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    encoder.write_all(profile)?;
+    data.append(&mut encoder.finish()?);
+
+    let mut chunk: Vec<u8> = Vec::with_capacity(data.len() + 12);
+    chunk.extend_from_slice(&(data.len() as u32).to_be_bytes());
+    chunk.extend_from_slice(b"iCCP");
+    chunk.extend_from_slice(&data);
+    chunk.extend_from_slice(&crc32fast::hash(&chunk[4..]).to_be_bytes());
+
+    let mut output: Vec<u8> = Vec::with_capacity(png.len() + chunk.len());
+    output.extend_from_slice(&png[..IHDR_END]);
+    output.append(&mut chunk);
+    output.extend_from_slice(&png[IHDR_END..]);
+    Ok(output)
+}
+
+/// 8 byte signature at the start of every png
+const PNG_SIGNATURE: [u8; 8] = [0x89, 0x50, 0x4E, 0x47, 0x0D, 0x0A, 0x1A, 0x0A];
+/// End of the IHDR chunk, which follows the signature (4 byte length, 4 byte type, 13 bytes of data, 4 byte crc)
+const IHDR_END: usize = 8 + 4 + 4 + 13 + 4;
+
+/// Check that data starts with a png signature followed by an IHDR chunk
+fn check_png_header(png: &[u8]) -> Result<(), SkoraError> {
+    if png.len() < IHDR_END || png[0..8] != PNG_SIGNATURE || &png[12..16] != b"IHDR" {
+        return Err(SkoraError::InvalidPng("Missing png signature or IHDR chunk".to_string()));
+    }
+    Ok(())
+}
+
+/// Read the width and height of a png image from its IHDR chunk
+///
+/// # Arguments
+///
+/// * `png` - The bytes of the png image
+///
+/// # Returns
+///
+/// * Width and height (in pixels) of the image
+///
+/// # Example
+/// ```rust
+/// let (width, height) = png_dimensions(&layer.image)?;
+/// ```
+pub fn png_dimensions(png: &[u8]) -> Result<(u32, u32), SkoraError> {
+    check_png_header(png)?;
+    let width = u32::from_be_bytes([png[16], png[17], png[18], png[19]]);
+    let height = u32::from_be_bytes([png[20], png[21], png[22], png[23]]);
+    if width == 0 || height == 0 {
+        return Err(SkoraError::InvalidPng(format!("Image size {}x{} is empty", width, height)));
+    }
+    Ok((width, height))
+}
+
+/// Format an opacity for stack.xml
+///
+/// Opacity is written with fixed precision so the output doesn't depend on how the float happens to be
+/// formatted.  Values that are not finite (which would write `NaN` or `inf` into the xml) are written as
+/// fully opaque and anything else is clamped to the 0.0 to 1.0 range ORA expects.
+fn xml_opacity(opacity: f32) -> String {
+    let opacity = match opacity.is_finite() {
+        true => opacity.clamp(0.0, 1.0),
+        false => 1.0,
+    };
+    format!("{:.6}", opacity)
+}
+
+/// Create a fully transparent png image
+///
+/// # Arguments
+///
+/// * `width` - Width (in pixels) of the image.  A width of 0 is written as 1.
+/// * `height` - Height (in pixels) of the image.  A height of 0 is written as 1.
+///
+/// # Returns
+///
+/// * PNG file of the image stored as a vector of u8 bytes
+fn blank_png(width: u32, height: u32) -> ZipResult<Vec<u8>> {
+    let mut buf: Vec<u8> = vec![];
+    let img = DynamicImage::ImageRgba8(ImageBuffer::new(width.max(1), height.max(1)));
+    img.write_to(&mut buf, image::ImageOutputFormat::Png)
+        .map_err(|error| ZipError::Io(std::io::Error::other(error)))?;
+    Ok(buf)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Read an entry of an ora file
+    fn read_entry(bytes: &[u8], name: &str) -> Vec<u8> {
+        let mut archive = ZipArchive::new(Cursor::new(bytes)).unwrap();
+        let mut entry = archive.by_name(name).unwrap();
+        let mut data = Vec::new();
+        entry.read_to_end(&mut data).unwrap();
+        data
+    }
+
+    /// Ids of the extra fields in the local header of an entry
+    fn local_extra_field_ids(bytes: &[u8], name: &str) -> Vec<u16> {
+        let le16 = |pos: usize| u16::from_le_bytes([bytes[pos], bytes[pos + 1]]) as usize;
+        // local headers are 30 bytes followed by the name and then the extra fields
+        let start = (0..bytes.len() - 30)
+            .filter(|&pos| &bytes[pos..pos + 4] == b"PK\x03\x04")
+            .find(|&pos| le16(pos + 26) == name.len() && bytes[pos + 30..].starts_with(name.as_bytes()))
+            .unwrap();
+        let extra_start = start + 30 + name.len();
+        let extra_end = extra_start + le16(start + 28);
+        let mut ids = Vec::new();
+        let mut pos = extra_start;
+        while pos + 4 <= extra_end {
+            ids.push(le16(pos) as u16);
+            pos += 4 + le16(pos + 2);
+        }
+        ids
+    }
+
+    #[test]
+    fn streamed_layers_have_zip64_records() {
+        let mut bytes = Cursor::new(Vec::new());
+        let mut sink = ZipOraSink::new(&mut bytes, WriteOptions::default());
+        sink.begin(&Ora::new()).unwrap();
+        sink.layer(&Layer::from_png_bytes(0, blank_png(2, 2).unwrap(), 1.0, 0, 0).unwrap()).unwrap();
+        sink.finish().unwrap();
+        drop(sink);
+
+        assert!(local_extra_field_ids(bytes.get_ref(), "data/layer0.png").contains(&0x0001));
+    }
+
+    #[test]
+    fn from_png_bytes_reads_the_size() {
+        let mut image = RgbaImage::new(5, 3);
+        image.put_pixel(4, 2, image::Rgba([1, 2, 3, 4]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(image)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+
+        let layer = Layer::from_png_bytes(2, png, 0.5, 1, 2).unwrap();
+        assert_eq!((layer.layer_number, layer.x_pos, layer.y_pos), (2, 1, 2));
+        assert_eq!((layer.width, layer.height), (5, 3));
+        assert_eq!(layer.decoded().unwrap().get_pixel(4, 2).0, [1, 2, 3, 4]);
+        assert!(Layer::from_png_bytes(2, b"not a png".to_vec(), 0.5, 1, 2).is_err());
+    }
+
+    #[test]
+    fn add_layer_from_file_then_write() {
+        let mut image = RgbaImage::new(2, 2);
+        image.put_pixel(0, 0, image::Rgba([9, 8, 7, 255]));
+        let dir = std::env::temp_dir();
+        let png_path = dir.join(format!("skora_add_layer_{}.png", std::process::id()));
+        let ora_path = dir.join(format!("skora_add_layer_{}.ora", std::process::id()));
+        image.save(&png_path).unwrap();
+
+        let mut ora = Ora::new();
+        ora.set_canvas_size(4, 3);
+        ora.add_layer(Layer::new(0, blank_png(4, 3).unwrap(), 1.0, 0, 0, 4, 3));
+        assert_eq!(ora.add_layer_from_file(&png_path, 0.5, 1, 1).unwrap(), 1);
+        // a layer hanging off the canvas is rejected and the image is left as it was
+        assert!(matches!(
+            ora.add_layer_from_file(&png_path, 1.0, 3, 0),
+            Err(SkoraError::TooLarge(_))
+        ));
+        assert_eq!(ora.flat_layers().len(), 2);
+
+        ora.write_to_file(&ora_path).unwrap();
+        let bytes = std::fs::read(&ora_path).unwrap();
+        std::fs::remove_file(&png_path).unwrap();
+        std::fs::remove_file(&ora_path).unwrap();
+
+        // the new layer is on top
+        let stack = xml::parse(&String::from_utf8(read_entry(&bytes, "stack.xml")).unwrap()).unwrap();
+        let layers = &stack.children[0].children;
+        assert_eq!(layers.len(), 2);
+        assert_eq!(layers[0].get_attr("src"), Some("data/layer1.png"));
+        assert_eq!(layers[0].get_attr("opacity"), Some("0.500000"));
+        assert_eq!(layers[0].get_attr("x"), Some("1"));
+        // y is measured from the bottom in the layer and from the top in stack.xml
+        assert_eq!(layers[0].get_attr("y"), Some("0"));
+        let png = image::load_from_memory(&read_entry(&bytes, "data/layer1.png")).unwrap().into_rgba8();
+        assert_eq!(png.get_pixel(0, 0).0, [9, 8, 7, 255]);
+    }
+
+    #[test]
+    fn non_png_entries_are_rejected() {
+        let error = check_png_entry("data/layer0.png", b"GIF89a\x01\x00\x01\x00").unwrap_err();
+        assert!(error.to_string().contains("data/layer0.png is not a png image"));
+        assert!(check_png_entry("data/layer0.png", &blank_png(1, 1).unwrap()).is_ok());
+
+        let mut ora = Ora::new();
+        ora.set_canvas_size(1, 1);
+        ora.add_layer(Layer::new(0, b"not a png".to_vec(), 1.0, 0, 0, 1, 1));
+        assert!(ora.to_bytes().is_err());
+    }
+
+    #[test]
+    fn canvas_larger_than_the_layer() {
+        let mut image = RgbaImage::new(100, 100);
+        image.put_pixel(0, 0, image::Rgba([255, 0, 0, 255]));
+        let mut png = Vec::new();
+        DynamicImage::ImageRgba8(image)
+            .write_to(&mut png, image::ImageOutputFormat::Png)
+            .unwrap();
+
+        let mut ora = Ora::new();
+        ora.set_canvas_size(200, 200);
+        // 30 pixels up from the bottom of the canvas, so 70 down from the top
+        ora.add_layer(Layer::from_png_bytes(0, png.clone(), 1.0, 50, 30).unwrap());
+        let stack = xml::parse(&ora.stack_xml()).unwrap();
+        assert_eq!(stack.get_attr("w"), Some("200"));
+        assert_eq!(stack.get_attr("h"), Some("200"));
+        let layer = &stack.children[0].children[0];
+        assert_eq!((layer.get_attr("x"), layer.get_attr("y")), (Some("50"), Some("70")));
+
+        let flattened = ora.flatten().unwrap();
+        assert_eq!(flattened.dimensions(), (200, 200));
+        assert_eq!(flattened.get_pixel(50, 70).0, [255, 0, 0, 255]);
+        assert_eq!(flattened.get_pixel(49, 70).0, [0, 0, 0, 0]);
+        assert_eq!(flattened.get_pixel(50, 69).0, [0, 0, 0, 0]);
+
+        // growing the canvas keeps the layer the same distance from the top
+        let mut ora = Ora::new();
+        ora.set_canvas_size(100, 100);
+        ora.add_layer(Layer::from_png_bytes(0, png, 1.0, 0, 0).unwrap());
+        ora.set_canvas_size(200, 200);
+        assert_eq!(ora.flat_layers()[0].y_pos, 100);
+        let stack = xml::parse(&ora.stack_xml()).unwrap();
+        assert_eq!(stack.children[0].children[0].get_attr("y"), Some("0"));
+        assert_eq!(ora.flatten().unwrap().get_pixel(0, 0).0, [255, 0, 0, 255]);
+    }
+
+    #[test]
+    #[ignore = "allocates 4GB"]
+    fn needs_zip64_past_4gb() {
+        let mut ora = Ora::new();
+        assert!(!ora.needs_zip64());
+        ora.source = Some(vec![0u8; u32::MAX as usize + 1]);
+        assert!(ora.needs_zip64());
+    }
+
+    #[test]
+    fn opacity_has_six_decimals() {
+        assert_eq!(xml_opacity(0.5), "0.500000");
+        let mut ora = Ora::new();
+        ora.set_canvas_size(4, 4);
+        ora.add_layer(Layer::new(0, Vec::new(), 0.5, 0, 0, 4, 4));
+        assert!(ora.stack_xml().contains("opacity=\"0.500000\""));
+    }
+
+    #[test]
+    fn non_finite_opacity_is_opaque() {
+        let mut ora = Ora::new();
+        ora.set_canvas_size(4, 4);
+        ora.add_layer(Layer::new(0, Vec::new(), f32::NAN, 0, 0, 4, 4));
+        ora.add_layer(Layer::new(1, Vec::new(), f32::INFINITY, 0, 2, 4, 4));
+
+        let image = xml::parse(&ora.stack_xml()).unwrap();
+        let layers = &image.children[0].children;
+        assert_eq!(layers[0].get_attr("opacity"), Some("1.000000"));
+        assert_eq!(layers[1].get_attr("opacity"), Some("1.000000"));
+        // the second layer hangs off the top of the canvas
+        assert_eq!(layers[1].get_attr("y"), Some("-2"));
+    }
+
+    #[test]
+    fn renumber_after_removing_a_layer() {
+        let mut ora = Ora::new();
+        for layer_number in (1..4).rev() {
+            ora.add_layer(Layer::new(layer_number, Vec::new(), 1.0, 0, 0, 1, 1));
+        }
+        ora.background = Some(Layer::new(0, Vec::new(), 1.0, 0, 0, 1, 1));
+        ora.layers.remove(1);
+        ora.renumber_layers().unwrap();
+
+        let numbers: Vec<u8> = ora.flat_layers().iter().map(|layer| layer.layer_number).collect();
+        assert_eq!(numbers, vec![2, 1, 0]);
+        assert_eq!(ora.flat_layers()[0].display_name(), "Paint Layer 2");
+    }
+
+    #[test]
+    fn write_to_file_without_layers() {
+        let mut ora = Ora::new();
+        ora.set_canvas_size(4, 3);
+        let path = std::env::temp_dir().join(format!("skora_no_layers_{}.ora", std::process::id()));
+        ora.write_to_file(&path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let stack = xml::parse(&String::from_utf8(read_entry(&bytes, "stack.xml")).unwrap()).unwrap();
+        assert_eq!(stack.get_attr("w"), Some("4"));
+        assert_eq!(stack.get_attr("h"), Some("3"));
+        assert!(stack.children[0].children.is_empty());
+        assert_eq!(png_dimensions(&read_entry(&bytes, "mergedimage.png")).unwrap(), (4, 3));
+        assert_eq!(png_dimensions(&read_entry(&bytes, "Thumbnails/thumbnail.png")).unwrap(), (4, 3));
+    }
+}
@@ -0,0 +1,230 @@
+//! Photoshop PSD File Format
+//!
+//! Functions to write an open raster image as a layered Photoshop PSD file.  The merged image is
+//! written as the PSD composite and each ORA layer becomes a PSD layer with its name, opacity,
+//! visibility, blend mode, and bounding box (groups are flattened into the list of layers).  All
+//! image data is written uncompressed with 8 bits per channel.  The format is described in the
+//! Adobe Photoshop File Formats Specification.
+//!
+
+use std::convert::TryFrom;
+use std::path::Path;
+
+use image::{GenericImageView, RgbaImage};
+
+use crate::endian_rw::{order_write, Endian};
+use crate::error::SkoraError;
+use crate::ora::{BlendMode, Layer, Ora};
+
+/// Channel ids of the channels written for each layer (-1 is the transparency mask)
+const CHANNEL_IDS: [i16; 4] = [0, 1, 2, -1];
+/// Index of each channel id in an RGBA pixel
+const CHANNEL_INDEX: [usize; 4] = [0, 1, 2, 3];
+/// Color mode for RGB images
+const COLOR_MODE_RGB: u16 = 3;
+/// Compression value for raw (uncompressed) image data
+const COMPRESSION_RAW: u16 = 0;
+/// Flag bit marking a layer as hidden
+const FLAG_HIDDEN: u8 = 0x02;
+
+impl BlendMode {
+    /// Four character key Photoshop uses for the blend mode
+    pub fn psd_key(&self) -> &'static [u8; 4] {
+        match self {
+            BlendMode::Normal => b"norm",
+        }
+    }
+}
+
+impl Ora {
+    /// Write the image to a layered Photoshop PSD file
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Path of the file to write
+    ///
+    /// # Example
+    /// ```rust
+    /// let ora = skora::tiff_to_ora(&file, skora::ConvertOptions::default())?;
+    /// ora.write_psd(Path::new("test.psd"))?;
+    /// ```
+    pub fn write_psd(&self, path: &Path) -> Result<(), SkoraError> {
+        std::fs::write(path, self.to_psd()?)?;
+        Ok(())
+    }
+
+    /// Write the image to a layered Photoshop PSD file in memory
+    ///
+    /// The merged image is used as the composite when it matches the canvas size, otherwise the
+    /// layers are flattened to make one.
+    ///
+    /// # Returns
+    ///
+    /// * The bytes of the psd file
+    ///
+    /// # Example
+    /// ```rust
+    /// let bytes = ora.to_psd()?;
+    /// ```
+    pub fn to_psd(&self) -> Result<Vec<u8>, SkoraError> {
+        let composite = match image::load_from_memory(&self.merged_image) {
+            Ok(image) if image.dimensions() == (self.width, self.height) => image.into_rgba8(),
+            _ => self.flatten()?,
+        };
+
+        let mut buf: Vec<u8> = Vec::new();
+
+        // file header
+        buf.extend_from_slice(b"8BPS");
+        write_u16(&mut buf, 1);
+        buf.extend_from_slice(&[0; 6]);
+        write_u16(&mut buf, 4);
+        write_u32(&mut buf, self.height);
+        write_u32(&mut buf, self.width);
+        write_u16(&mut buf, 8);
+        write_u16(&mut buf, COLOR_MODE_RGB);
+
+        // empty color mode data and image resources sections
+        write_u32(&mut buf, 0);
+        write_u32(&mut buf, 0);
+
+        let layer_info = self.psd_layer_info()?;
+        write_u32(&mut buf, section_length(layer_info.len() + 4 + 4)?);
+        write_u32(&mut buf, section_length(layer_info.len())?);
+        buf.extend_from_slice(&layer_info);
+        // empty global layer mask info
+        write_u32(&mut buf, 0);
+
+        // composite image data is stored a whole channel at a time
+        write_u16(&mut buf, COMPRESSION_RAW);
+        for channel in CHANNEL_INDEX.iter() {
+            buf.extend(composite.pixels().map(|pixel| pixel[*channel]));
+        }
+        Ok(buf)
+    }
+
+    /// Build the layer info section, which holds the layer records followed by their channel data
+    fn psd_layer_info(&self) -> Result<Vec<u8>, SkoraError> {
+        // psd layers are listed from the bottom up
+        let layers: Vec<&Layer> = self.flat_layers().into_iter().rev().collect();
+        let mut images: Vec<RgbaImage> = Vec::with_capacity(layers.len());
+        for layer in &layers {
+            images.push(layer.decoded()?);
+        }
+
+        let mut buf: Vec<u8> = Vec::new();
+        write_u16(&mut buf, u16::try_from(layers.len()).map_err(|_| SkoraError::TooManyLayers)?);
+
+        for (layer, pixels) in layers.iter().zip(images.iter()) {
+            let (width, height) = pixels.dimensions();
+            // ora y positions are measured from the bottom of the layer, psd bounds from the top
+            let left = layer.x_pos as i32;
+            let top = self.height as i32 - layer.y_pos as i32 - layer.height as i32;
+            write_i32(&mut buf, top);
+            write_i32(&mut buf, left);
+            write_i32(&mut buf, top + height as i32);
+            write_i32(&mut buf, left + width as i32);
+
+            write_u16(&mut buf, CHANNEL_IDS.len() as u16);
+            for id in CHANNEL_IDS.iter() {
+                order_write(Endian::Big, &mut buf, *id);
+                write_u32(&mut buf, section_length(2 + width as usize * height as usize)?);
+            }
+
+            buf.extend_from_slice(b"8BIM");
+            buf.extend_from_slice(BlendMode::Normal.psd_key());
+            buf.push((layer.opacity.clamp(0.0, 1.0) * 255.0).round() as u8);
+            buf.push(0); // clipping
+            buf.push(if layer.visible { 0 } else { FLAG_HIDDEN });
+            buf.push(0); // filler
+
+            let name = pascal_string(&layer.display_name());
+            // no layer mask data or blending ranges
+            write_u32(&mut buf, 4 + 4 + name.len() as u32);
+            write_u32(&mut buf, 0);
+            write_u32(&mut buf, 0);
+            buf.extend_from_slice(&name);
+        }
+
+        // channel image data in the same order as the layer records
+        for pixels in &images {
+            for channel in CHANNEL_INDEX.iter() {
+                write_u16(&mut buf, COMPRESSION_RAW);
+                buf.extend(pixels.pixels().map(|pixel| pixel[*channel]));
+            }
+        }
+
+        // the section length must be even
+        if buf.len() % 2 == 1 {
+            buf.push(0);
+        }
+        Ok(buf)
+    }
+}
+
+/// Encode a layer name as a pascal string (length byte then up to 255 bytes) padded to a multiple of 4 bytes
+fn pascal_string(text: &str) -> Vec<u8> {
+    let mut end = text.len().min(255);
+    while !text.is_char_boundary(end) {
+        end -= 1;
+    }
+    let mut output = vec![end as u8];
+    output.extend_from_slice(&text.as_bytes()[..end]);
+    while output.len() % 4 != 0 {
+        output.push(0);
+    }
+    output
+}
+
+/// Convert the length of a section (or channel) to the u32 it is written as, which limits it to 4GB
+fn section_length(length: usize) -> Result<u32, SkoraError> {
+    u32::try_from(length).map_err(|_| SkoraError::TooLarge("Image is too large for a psd file (4GB)".to_string()))
+}
+
+/// Write a big endian u16
+fn write_u16(buf: &mut Vec<u8>, value: u16) {
+    order_write(Endian::Big, buf, value);
+}
+
+/// Write a big endian u32
+fn write_u32(buf: &mut Vec<u8>, value: u32) {
+    order_write(Endian::Big, buf, value);
+}
+
+/// Write a big endian i32
+fn write_i32(buf: &mut Vec<u8>, value: i32) {
+    order_write(Endian::Big, buf, value);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::{image_to_buf, PngOptions};
+
+    /// Read the big endian i32 at a position
+    fn read_i32(buf: &[u8], position: usize) -> i32 {
+        i32::from_be_bytes([buf[position], buf[position + 1], buf[position + 2], buf[position + 3]])
+    }
+
+    #[test]
+    fn layer_bounds_from_the_top() {
+        let mut ora = Ora::new();
+        ora.set_canvas_size(4, 4);
+        let png = image_to_buf(RgbaImage::new(2, 1), PngOptions::default()).unwrap();
+        ora.add_layer(Layer::from_png_bytes(0, png, 1.0, 1, 1).unwrap());
+        let psd = ora.to_psd().unwrap();
+
+        // header (26 bytes) and the empty color mode data and image resources sections come first
+        let layer_info_length = read_i32(&psd, 38) as usize;
+        assert_eq!(read_i32(&psd, 34) as usize, layer_info_length + 8);
+        assert_eq!(u16::from_be_bytes([psd[42], psd[43]]), 1);
+        let bounds: Vec<i32> = (0..4).map(|idx| read_i32(&psd, 44 + idx * 4)).collect();
+        assert_eq!(bounds, vec![2, 1, 3, 3]);
+    }
+
+    #[test]
+    fn section_past_4gb_is_too_large() {
+        assert_eq!(section_length(8).unwrap(), 8);
+        assert!(matches!(section_length(u32::MAX as usize + 1), Err(SkoraError::TooLarge(_))));
+    }
+}
@@ -0,0 +1,214 @@
+//! Test Tiff
+//!
+//! Helpers used by the unit tests to build tiff data without needing sample files.
+//!
+
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::vec;
+use alloc::vec::Vec;
+
+use crate::endian_rw::{order_write_16, order_write_32, order_write_64, Endian};
+use crate::tiff_types::{Data, Ifd, Tag};
+
+/// Build a tag holding the given data
+pub fn tag(data: Data) -> Tag {
+    let datatype = data.datatype();
+    let count = match &data {
+        Data::Ascii(val) => val.len() as u64,
+        Data::Rational(val) => val.len() as u64,
+        _ => data.to_vec_u8(Endian::Little).len() as u64 / datatype.element_size_in_bytes(),
+    };
+    Tag {
+        count,
+        data,
+        datapos: 0,
+        datatype,
+        ifds: None,
+        offset: None,
+    }
+}
+
+/// Build a little endian classic tiff ifd holding the given tags
+pub fn ifd(tags: Vec<(u64, Data)>) -> Ifd {
+    let tags: BTreeMap<u64, Tag> = tags.into_iter().map(|(num, data)| (num, tag(data))).collect();
+    Ifd {
+        endian: Endian::Little,
+        big_tiff: false,
+        offset: 8,
+        size: 0,
+        tag_count: tags.len() as u64,
+        tags,
+    }
+}
+
+/// Ascii tag data with the terminating null
+pub fn ascii(text: &str) -> Data {
+    Data::Ascii(format!("{}\u{0}", text))
+}
+
+/// Ifd written by `tiff_file`
+#[derive(Clone, Debug, Default)]
+pub struct TestIfd {
+    /// Tags other than the StripOffsets (273), StripByteCounts (279), and SubIFDs (330) tags
+    pub tags: Vec<(u16, Data)>,
+    /// Image data.  Each strip is written to the file and pointed to by tags 273 and 279.
+    pub strips: Vec<Vec<u8>>,
+    /// Write StripByteCounts (279) as Short rather than Long
+    pub short_byte_counts: bool,
+    /// Ifds written before this one and pointed to by the SubIFDs tag (330)
+    pub sub_ifds: Vec<TestIfd>,
+    /// Tiff data type of the SubIFDs tag.  When None it is Long in a classic tiff and Long8 in a BigTIFF.
+    pub sub_ifd_type: Option<u16>,
+}
+
+/// Tiff file being written by `tiff_file`
+struct TestWriter {
+    endian: Endian,
+    big_tiff: bool,
+    buf: Vec<u8>,
+}
+
+impl TestWriter {
+    /// Append data on a word boundary and return its offset
+    fn append(&mut self, data: &[u8]) -> u64 {
+        if self.buf.len() % 2 == 1 {
+            self.buf.push(0);
+        }
+        let offset = self.buf.len() as u64;
+        self.buf.extend_from_slice(data);
+        offset
+    }
+
+    /// Write an offset or count in the size used by the file
+    fn offset_bytes(&self, value: u64) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        match self.big_tiff {
+            true => order_write_64(self.endian, &mut bytes, value),
+            false => order_write_32(self.endian, &mut bytes, value as u32),
+        }
+        bytes
+    }
+
+    /// Overwrite an offset already in the file
+    fn patch(&mut self, position: usize, value: u64) {
+        let bytes = self.offset_bytes(value);
+        self.buf[position..position + bytes.len()].copy_from_slice(&bytes);
+    }
+
+    /// Write the strips, sub-ifds, and tag data of an ifd followed by the ifd itself
+    ///
+    /// # Returns
+    ///
+    /// * Offset of the ifd and the position of its next ifd offset
+    fn write_ifd(&mut self, ifd: &TestIfd) -> (u64, usize) {
+        let mut tags: BTreeMap<u16, (u16, u64, Vec<u8>)> = BTreeMap::new();
+        for (num, data) in &ifd.tags {
+            let count = tag(data.clone()).count;
+            tags.insert(*num, (data.datatype().type_tiff_id() as u16, count, data.to_vec_u8(self.endian)));
+        }
+
+        if !ifd.strips.is_empty() {
+            let offsets: Vec<u32> = ifd.strips.iter().map(|strip| self.append(strip) as u32).collect();
+            let byte_counts = match ifd.short_byte_counts {
+                true => Data::Short(ifd.strips.iter().map(|strip| strip.len() as u16).collect()),
+                false => Data::Long(ifd.strips.iter().map(|strip| strip.len() as u32).collect()),
+            };
+            for (num, data) in [(273, Data::Long(offsets)), (279, byte_counts)].iter() {
+                let count = ifd.strips.len() as u64;
+                tags.insert(*num, (data.datatype().type_tiff_id() as u16, count, data.to_vec_u8(self.endian)));
+            }
+        }
+
+        if !ifd.sub_ifds.is_empty() {
+            let offsets: Vec<u64> = ifd.sub_ifds.iter().map(|sub_ifd| self.write_ifd(sub_ifd).0).collect();
+            let data = match self.big_tiff {
+                true => Data::Long8(offsets.clone()),
+                false => Data::Long(offsets.iter().map(|offset| *offset as u32).collect()),
+            };
+            let type_id = ifd.sub_ifd_type.unwrap_or(data.datatype().type_tiff_id() as u16);
+            tags.insert(330, (type_id, offsets.len() as u64, data.to_vec_u8(self.endian)));
+        }
+
+        let inline = match self.big_tiff {
+            true => 8,
+            false => 4,
+        };
+        let mut record: Vec<u8> = Vec::new();
+        match self.big_tiff {
+            true => order_write_64(self.endian, &mut record, tags.len() as u64),
+            false => order_write_16(self.endian, &mut record, tags.len() as u16),
+        }
+        for (num, (type_id, count, mut bytes)) in tags {
+            order_write_16(self.endian, &mut record, num);
+            order_write_16(self.endian, &mut record, type_id);
+            record.extend(self.offset_bytes(count));
+            if bytes.len() <= inline {
+                bytes.resize(inline, 0);
+                record.extend(bytes);
+            } else {
+                let offset = self.append(&bytes);
+                record.extend(self.offset_bytes(offset));
+            }
+        }
+        record.extend(self.offset_bytes(0));
+
+        let offset = self.append(&record);
+        (offset, offset as usize + record.len() - inline)
+    }
+}
+
+/// Write a tiff file holding a chain of ifds
+///
+/// # Arguments
+///
+/// * `endian` - Byte order of the file
+/// * `big_tiff` - Write a BigTIFF rather than a classic tiff
+/// * `ifds` - Top level ifds, in the order they are chained together
+///
+/// # Returns
+///
+/// * The bytes of the tiff file
+pub fn tiff_file(endian: Endian, big_tiff: bool, ifds: &[TestIfd]) -> Vec<u8> {
+    let mut writer = TestWriter {
+        endian,
+        big_tiff,
+        buf: match endian {
+            Endian::Big => b"MM".to_vec(),
+            Endian::Little => b"II".to_vec(),
+        },
+    };
+    match big_tiff {
+        true => {
+            order_write_16(endian, &mut writer.buf, 43);
+            order_write_16(endian, &mut writer.buf, 8);
+            order_write_16(endian, &mut writer.buf, 0);
+            order_write_64(endian, &mut writer.buf, 0);
+        }
+        false => {
+            order_write_16(endian, &mut writer.buf, 42);
+            order_write_32(endian, &mut writer.buf, 0);
+        }
+    }
+
+    let mut pointer = writer.buf.len() - if big_tiff { 8 } else { 4 };
+    for ifd in ifds {
+        let (offset, next) = writer.write_ifd(ifd);
+        writer.patch(pointer, offset);
+        pointer = next;
+    }
+    writer.buf
+}
+
+/// Tags of an uncompressed 8 bit RGBA image
+pub fn rgba_tags(width: u32, height: u32) -> Vec<(u16, Data)> {
+    vec![
+        (256, Data::Long(vec![width])),
+        (257, Data::Long(vec![height])),
+        (258, Data::Short(vec![8, 8, 8, 8])),
+        (259, Data::Short(vec![1])),
+        (262, Data::Short(vec![2])),
+        (277, Data::Short(vec![4])),
+        (278, Data::Long(vec![height])),
+    ]
+}
@@ -10,12 +10,18 @@
 //! was referenced when creating parts of this library.
 //!
 
-use std::collections::BTreeMap;
-use std::error::Error;
-use log::{error};
+use alloc::collections::{BTreeMap, BTreeSet};
+use alloc::format;
+use alloc::string::ToString;
+use alloc::vec;
+use alloc::vec::Vec;
+use core::convert::TryFrom;
+use log::{debug, error};
 
 use crate::endian_rw::{order_read, order_write_16, order_write_32, order_write_64, Endian};
 
+use crate::error::SkoraError;
+use crate::tiff_tags::tag_name;
 use crate::tiff_types::{Data, DataType, Ifd, Info, Tag};
 
 /// Read the non-image data from a TIFF.
@@ -28,11 +34,14 @@ use crate::tiff_types::{Data, DataType, Ifd, Info, Tag};
 ///
 /// a dictionary of information on the tiff file & a vector of IFDs
 ///
-pub fn read_tiff(file: &[u8]) -> Result<(Info, Vec<Ifd>), String> {
+pub fn read_tiff(file: &[u8]) -> Result<(Info, Vec<Ifd>), SkoraError> {
     let file_size = file.len();
+    if file_size < 8 {
+        return Err(SkoraError::InvalidTiff(format!("File is too short to be a tiff ({} bytes)", file_size)));
+    }
 
     // read the file header
-    let header: Vec<u8> = file[0..4].into();
+    let header: Vec<u8> = read_slice(file, 0, 4)?.into();
 
     // Verify this is a tiff image
     match header[..] {
@@ -40,7 +49,7 @@ pub fn read_tiff(file: &[u8]) -> Result<(Info, Vec<Ifd>), String> {
         [0x4D, 0x4D, 0x00, 0x2a] => {} //b'MM\x00\x2a'
         [0x49, 0x49, 0x2b, 0x00] => {} //b'II\x2b\x00'
         [0x4D, 0x4D, 0x00, 0x2b] => {} //b'MM\x00\x2b'
-        _ => return Err("The header is not valid".to_string()),
+        _ => return Err(SkoraError::InvalidTiff("The header is not valid".to_string())),
     }
 
     // Read which endian encoding is used
@@ -49,21 +58,25 @@ pub fn read_tiff(file: &[u8]) -> Result<(Info, Vec<Ifd>), String> {
         false => Endian::Little,
     };
 
-    // Read if this is a 'big tiff' image
-    let big_tiff = header[2] == 0x2B && header[4] == 0x08;
+    // Read if this is a 'big tiff' image from the version number (42 for classic tiff, 43 for big tiff)
+    // The constant offset size (8) that follows the version in a big tiff header is checked below
+    let big_tiff = order_read(endian, &header[2..4], 2) == 43;
 
     // Get the first ifd location of the source tiff image
-    // Big tiff sets the first offset to 8 then writes the actual offset to the next 4 bytes
-    // Regular tiff just puts the offset in the 4 bytes of the header
+    // Big tiff writes the offset size (8) and 2 reserved bytes after the header then the 8 byte offset
+    // Regular tiff just puts the 4 byte offset after the header
     let first_ifd = match big_tiff {
         true => {
-            let offset_size = order_read(endian, &file[4..6], 2);
-            match offset_size == 8 {
-                true => order_read(endian, &file[8..12], 4) as usize,
-                false => return Err("Unexpected big tiff offset size".to_string()),
+            let offset_size = order_read(endian, read_slice(file, 4, 2)?, 2);
+            match (offset_size == 8, read_slice(file, 8, 8)) {
+                (true, Ok(bytes)) => usize::try_from(order_read(endian, bytes, 8)).map_err(|_| {
+                    SkoraError::InvalidTiff("First ifd offset is too large for this platform".to_string())
+                })?,
+                (true, Err(_)) => return Err(SkoraError::InvalidTiff("Big tiff header is truncated".to_string())),
+                (false, _) => return Err(SkoraError::InvalidTiff("Unexpected big tiff offset size".to_string())),
             }
         }
-        false => order_read(endian, &file[4..8], 4) as usize,
+        false => order_read(endian, read_slice(file, 4, 4)?, 4) as usize,
     };
 
     // Define the file Info struct
@@ -73,21 +86,127 @@ pub fn read_tiff(file: &[u8]) -> Result<(Info, Vec<Ifd>), String> {
         first_ifd,
         header,
         size: file_size,
+        ifd_count: 0,
     };
 
     // Initialize a list of IFDs to store the main ifd and all sub ifds (layers) from the original tiff image
     let mut ifd_list: Vec<Ifd> = [].to_vec();
 
-    // Recursively read all IDFs in the image
+    // Recursively read all IDFs in the image, stopping if the chain (or a sub-ifd) loops back on itself
+    let mut visited: BTreeSet<usize> = BTreeSet::new();
     let mut next_ifd = first_ifd;
     while next_ifd > 0 {
-        next_ifd = read_ifd(file, &mut info, next_ifd, &mut ifd_list).unwrap();
+        next_ifd = read_ifd_tracked(file, &mut info, next_ifd, &mut ifd_list, &mut visited)?;
     }
+    info.ifd_count = ifd_list.len();
 
     // Return info
     Ok((info, ifd_list))
 }
 
+/// Check that an ifd with the given number of tags fits in the file
+///
+/// An offset that points into garbage (for example bytes appended after the tiff data) usually reads
+/// as a huge or zero tag count, so this catches it before any of the entries are read.
+///
+/// # Arguments
+///
+/// * `info` - Info about the tiff file
+/// * `ifd_offset` - Offset of the start of the ifd
+/// * `tag_count` - Number of tags read from the start of the ifd
+fn check_ifd_size(info: &Info, ifd_offset: usize, tag_count: u64) -> Result<(), SkoraError> {
+    // tag count, then the entries, then the offset of the next ifd
+    let (count_size, entry_size, next_size) = match info.big_tiff {
+        true => (8, 20, 8),
+        false => (2, 12, 4),
+    };
+    let corrupt = SkoraError::CorruptTagCount {
+        offset: ifd_offset,
+        count: tag_count,
+    };
+    if tag_count == 0 {
+        return Err(corrupt);
+    }
+    let end = tag_count
+        .checked_mul(entry_size)
+        .and_then(|val| val.checked_add(count_size + next_size))
+        .and_then(|val| val.checked_add(ifd_offset as u64));
+    match end {
+        Some(end) if end <= info.size as u64 => Ok(()),
+        _ => Err(corrupt),
+    }
+}
+
+/// Entry point for fuzzing the tiff parser
+///
+/// `cargo-fuzz` targets call this with arbitrary bytes.  Parsing must return an error for malformed
+/// input rather than panicking, so any panic found through this function is a bug in `read_tiff`.
+///
+/// # Arguments
+///
+/// * `data` - Bytes to parse as a tiff file
+///
+/// # Example
+/// ```rust
+/// // fuzz/fuzz_targets/read_tiff.rs
+/// libfuzzer_sys::fuzz_target!(|data: &[u8]| skora::tiff::fuzz_read_tiff(data));
+/// ```
+#[cfg(feature = "fuzzing")]
+pub fn fuzz_read_tiff(data: &[u8]) {
+    let _ = read_tiff(data);
+}
+
+/// Regression inputs for `fuzz_read_tiff` that used to panic, suitable for seeding a fuzzing corpus
+#[cfg(feature = "fuzzing")]
+pub const FUZZ_SEEDS: &[&[u8]] = &[
+    // header only
+    b"II\x2a\x00",
+    // big tiff header cut off before the first ifd offset
+    b"II\x2b\x00\x08\x00\x00\x00",
+    // first ifd offset points past the end of the file
+    b"II\x2a\x00\xff\x00\x00\x00",
+    // ifd claims one tag but is cut off in the middle of the tag entry
+    b"MM\x00\x2a\x00\x00\x00\x08\x00\x01\x01\x00\x00\x03",
+    // tag with an unknown data type (99) and no next ifd offset
+    b"II\x2a\x00\x08\x00\x00\x00\x01\x00\x00\x01\x63\x00\x01\x00\x00\x00\x00\x00\x00\x00",
+    // rational tag whose count needs far more data than the file holds
+    b"II\x2a\x00\x08\x00\x00\x00\x01\x00\x1a\x01\x05\x00\xff\xff\xff\xff\x08\x00\x00\x00\x00\x00\x00\x00",
+    // ifd whose next ifd offset points back to itself
+    b"II\x2a\x00\x08\x00\x00\x00\x00\x00\x08\x00\x00\x00",
+    // ifd with an empty SubIFDs tag (330)
+    b"II\x2a\x00\x08\x00\x00\x00\x02\x00\x00\x01\x03\x00\x01\x00\x00\x00\x01\x00\x00\x00\x4a\x01\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00",
+    // ifd whose SubIFDs tag (330) points back to itself
+    b"II\x2a\x00\x08\x00\x00\x00\x01\x00\x4a\x01\x04\x00\x01\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00",
+    // valid ifd whose next ifd offset points into junk appended after the tiff data
+    b"II\x2a\x00\x08\x00\x00\x00\x01\x00\x00\x01\x03\x00\x01\x00\x00\x00\x01\x00\x00\x00\x1a\x00\x00\x00\xff\xff\xff\xff",
+];
+
+/// Get a range of bytes from the file, returning an error rather than panicking if it is out of bounds
+///
+/// Every read the parser makes from the file goes through this so a truncated or corrupt file
+/// gives an error instead of a panic.
+///
+/// # Arguments
+///
+/// * `file` - Reference to the bytes of the tiff file
+/// * `offset` - Offset of the first byte to read
+/// * `length` - Number of bytes to read
+///
+/// # Returns
+///
+/// * The bytes or an error if any of them are past the end of the file
+///
+/// # Example
+/// ```rust
+/// let header = read_slice(&file, 0, 4)?;
+/// ```
+pub fn read_slice(file: &[u8], offset: usize, length: usize) -> Result<&[u8], SkoraError> {
+    offset
+        .checked_add(length)
+        .and_then(|end| file.get(offset..end))
+        .ok_or_else(|| SkoraError::InvalidTiff(format!("Cannot read {} bytes at offset {}", length, offset)))
+}
+
 /// Read an IFD and any subIFDs.
 ///
 /// # Arguments
@@ -97,18 +216,58 @@ pub fn read_tiff(file: &[u8]) -> Result<(Info, Vec<Ifd>), String> {
 /// * `ifd_offset` - Offset of the ifd to read (from the start of the file vector)
 /// * `ifd_list` - Reference to a Ifd vector used to store the image data
 ///
+/// # Returns
+///
+/// * The offset of the next ifd (0 if this is the last one) or an error if the ifd can't be read.  Errors
+///   are wrapped in `SkoraError::ParseAt` with the offset of the failing ifd and tag.
+///
 pub fn read_ifd(
     file: &[u8],
     info: &mut Info,
     ifd_offset: usize,
     ifd_list: &mut Vec<Ifd>,
-) -> Option<usize> {
+) -> Result<usize, SkoraError> {
+    read_ifd_tracked(file, info, ifd_offset, ifd_list, &mut BTreeSet::new())
+}
+
+/// Read an IFD and any subIFDs, skipping any ifd that has already been read
+///
+/// A malformed file can point the next ifd offset or a SubIFDs tag (330) back at an ifd that is
+/// already being read, which would otherwise recurse forever.  Sub-ifds are read to any depth.
+///
+/// # Arguments
+///
+/// * `file` - Rerference to the bytes of the tiff file
+/// * `info` - Info about the tiff file
+/// * `ifd_offset` - Offset of the ifd to read (from the start of the file vector)
+/// * `ifd_list` - Reference to a Ifd vector used to store the image data
+/// * `visited` - Offsets of every ifd read so far
+///
+/// # Returns
+///
+/// * The offset of the next ifd (0 if this is the last one or was already read) or an error if the
+///   ifd can't be read
+fn read_ifd_tracked(
+    file: &[u8],
+    info: &mut Info,
+    ifd_offset: usize,
+    ifd_list: &mut Vec<Ifd>,
+    visited: &mut BTreeSet<usize>,
+) -> Result<usize, SkoraError> {
+    if !visited.insert(ifd_offset) {
+        error!("Ifd at {} was already read, skipping it", ifd_offset);
+        return Ok(0);
+    }
+
+    // errors are returned with the offset of the ifd so a bad file can be tracked down
+    let at_ifd = |error: SkoraError| error.parse_at(ifd_offset, None);
+
     let length = match info.big_tiff {
         true => 16,
         false => 6,
     };
     if !check_offset(info.size, ifd_offset, length) {
-        return None;
+        return Err(at_ifd(SkoraError::InvalidTiff(format!("Ifd offset {} is outside the file", ifd_offset))));
     }
     let mut offset: usize = ifd_offset;
 
@@ -123,18 +282,19 @@ pub fn read_ifd(
 
     match info.big_tiff {
         true => {
-            ifd.tag_count = order_read(info.endian, &file[offset..offset + 8], 8);
+            ifd.tag_count = order_read(info.endian, read_slice(file, offset, 8).map_err(at_ifd)?, 8);
             offset += 8;
         }
         false => {
-            ifd.tag_count = order_read(info.endian, &file[offset..offset + 2], 2);
+            ifd.tag_count = order_read(info.endian, read_slice(file, offset, 2).map_err(at_ifd)?, 2);
             offset += 2;
         }
     }
+    check_ifd_size(info, ifd_offset, ifd.tag_count).map_err(at_ifd)?;
 
     for _entry in 0..(ifd.tag_count) {
         let tag: u64;
-        let datatype: DataType;
+        let type_id: u16;
         let count: u64;
         let data: Data;
         let data_tmp: u64;
@@ -142,46 +302,44 @@ pub fn read_ifd(
 
         match info.big_tiff {
             true => {
-                tag = order_read(info.endian, &file[offset..offset + 2], 2);
-                offset += 2;
-
-                datatype = (order_read(info.endian, &file[offset..offset + 2], 2) as u16).into();
-                offset += 2;
-
-                count = order_read(info.endian, &file[offset..offset + 8], 8);
-                offset += 8;
-
-                data_tmp = order_read(info.endian, &file[offset..offset + 8], 8);
+                let entry = read_slice(file, offset, 20).map_err(at_ifd)?;
+                tag = order_read(info.endian, &entry[0..2], 2);
+                type_id = order_read(info.endian, &entry[2..4], 2) as u16;
+                count = order_read(info.endian, &entry[4..12], 8);
+                data_tmp = order_read(info.endian, &entry[12..20], 8);
                 data = match info.endian {
                     Endian::Big => Data::Byte(data_tmp.to_be_bytes().into()),
                     Endian::Little => Data::Byte(data_tmp.to_le_bytes().into()),
                 };
-
-                offset += 8;
+                offset += 20;
 
                 data_length = 8;
             }
             false => {
-                tag = order_read(info.endian, &file[offset..offset + 2], 2);
-                offset += 2;
-
-                datatype = (order_read(info.endian, &file[offset..offset + 2], 2) as u16).into();
-                offset += 2;
-
-                count = order_read(info.endian, &file[offset..offset + 4], 4);
-                offset += 4;
-
-                data_tmp = order_read(info.endian, &file[offset..offset + 4], 4); //always returns 8 bytes (u64)
+                let entry = read_slice(file, offset, 12).map_err(at_ifd)?;
+                tag = order_read(info.endian, &entry[0..2], 2);
+                type_id = order_read(info.endian, &entry[2..4], 2) as u16;
+                count = order_read(info.endian, &entry[4..8], 4);
+                data_tmp = order_read(info.endian, &entry[8..12], 4); //always returns 8 bytes (u64)
                 data = match info.endian {
                     Endian::Big => Data::Byte(data_tmp.to_be_bytes().into()),
                     Endian::Little => Data::Byte(data_tmp.to_le_bytes().into()),
                 };
-                offset += 4;
+                offset += 12;
 
                 data_length = 4;
             }
         }
 
+        // readers are expected to skip tags with types they don't understand
+        let datatype = match DataType::from_tiff_id(type_id) {
+            Some(val) => val,
+            None => {
+                error!("Skipping tag {} with unknown data type {}", tag, type_id);
+                continue;
+            }
+        };
+
         let data_element_size = datatype.element_size_in_bytes();
         let mut tag_info: Tag = Tag {
             count,
@@ -192,14 +350,22 @@ pub fn read_ifd(
             offset: None,
         };
 
-        if (count * data_element_size) as usize > data_length {
+        let byte_count = count
+            .checked_mul(data_element_size)
+            .ok_or_else(|| {
+                SkoraError::InvalidTiff(format!("Tag {} count {} is too large", tag, count)).parse_at(ifd_offset, Some(tag))
+            })?;
+        if byte_count > data_length as u64 {
             tag_info.offset = Some(data_tmp as usize);
         }
 
         if ifd.tags.contains_key(&tag) {
             error!(
-                "Duplicate tag {:?}: data at {:?} and {:?}",
-                tag, ifd.tags[&tag].datapos, tag_info.datapos
+                "Duplicate tag {:?} ({}): data at {:?} and {:?}",
+                tag,
+                tag_name(tag).unwrap_or("unknown"),
+                ifd.tags[&tag].datapos,
+                tag_info.datapos
             );
         }
 
@@ -207,14 +373,14 @@ pub fn read_ifd(
     }
 
     let next_ifd = match info.big_tiff {
-        true => order_read(info.endian, &file[offset..offset + 8], 8) as usize,
-        false => order_read(info.endian, &file[offset..offset + 4], 4) as usize,
+        true => order_read(info.endian, read_slice(file, offset, 8).map_err(at_ifd)?, 8) as usize,
+        false => order_read(info.endian, read_slice(file, offset, 4).map_err(at_ifd)?, 4) as usize,
     };
 
-    read_ifd_tag_data(file, info, &mut ifd, ifd_list);
+    read_ifd_tag_data_tracked(file, info, &mut ifd, ifd_list, visited)?;
     ifd_list.push(ifd);
 
-    Some(next_ifd)
+    Ok(next_ifd)
 }
 
 /// Read all data from the tags of an IFD; read subifds.
@@ -226,7 +392,26 @@ pub fn read_ifd(
 /// * `ifd` - Reference to an Idf to read the tag data from
 /// * `ifd_list` - Reference to an Ifd vector used to store the image data
 ///
-pub fn read_ifd_tag_data(file: &[u8], info: &mut Info, ifd: &mut Ifd, ifd_list: &mut Vec<Ifd>) {
+/// # Returns
+///
+/// * Ok or an error if the data of a tag is outside the file.  Errors are wrapped in
+///   `SkoraError::ParseAt` with the offset of the failing ifd and tag.
+///
+pub fn read_ifd_tag_data(file: &[u8], info: &mut Info, ifd: &mut Ifd, ifd_list: &mut Vec<Ifd>) -> Result<(), SkoraError> {
+    let mut visited = BTreeSet::new();
+    visited.insert(ifd.offset);
+    read_ifd_tag_data_tracked(file, info, ifd, ifd_list, &mut visited)
+}
+
+/// Read all data from the tags of an IFD; read subifds that have not already been read
+fn read_ifd_tag_data_tracked(
+    file: &[u8],
+    info: &mut Info,
+    ifd: &mut Ifd,
+    ifd_list: &mut Vec<Ifd>,
+    visited: &mut BTreeSet<usize>,
+) -> Result<(), SkoraError> {
+    let ifd_offset = ifd.offset;
     for (tag_num, tag_info) in ifd.tags.iter_mut() {
         let tag = *tag_num;
         let type_size = tag_info.datatype.element_size_in_bytes();
@@ -234,33 +419,53 @@ pub fn read_ifd_tag_data(file: &[u8], info: &mut Info, ifd: &mut Ifd, ifd_list:
         // second param is the default value in case offset doesnt exist
         let pos = tag_info.offset.unwrap_or(tag_info.datapos);
 
-        let offset = pos;
-
-        let byte_count = (tag_info.count * type_size) as usize;
-
-        if !check_offset(info.size, pos, byte_count as usize) {
-            error!(
-                "OMG Its gone wrong - size {:?} offset {:?} length {:?}",
-                info.size, pos, byte_count
-            );
+        let byte_count = tag_info
+            .count
+            .checked_mul(type_size)
+            .and_then(|val| usize::try_from(val).ok())
+            .ok_or_else(|| {
+                SkoraError::InvalidTiff(format!("Tag {} has an implausible count of {}", tag, tag_info.count))
+                    .parse_at(ifd_offset, Some(tag))
+            })?;
+
+        if !check_offset(info.size, pos, byte_count) {
+            return Err(SkoraError::InvalidTiff(format!(
+                "Data of tag {} ({} bytes at offset {}) is outside the file",
+                tag, byte_count, pos
+            ))
+            .parse_at(ifd_offset, Some(tag)));
         }
 
-        let raw_data = file[offset..(offset + byte_count)].to_vec();
+        let raw_data = read_slice(file, pos, byte_count)
+            .map_err(|error| error.parse_at(ifd_offset, Some(tag)))?
+            .to_vec();
 
         tag_info.data = Data::new(raw_data, tag_info.datatype, info.endian, tag_info.count);
 
+        // SubIFDs are read the same whether the offsets are inline (a single sub-IFD) or stored
+        // elsewhere in the file, and whether they are typed as Short, Long, Long8, IFD, or IFD8.  A tag
+        // with no offsets (or only zero offsets) adds nothing to the ifd list.
         if tag == 330 {
             tag_info.ifds = Some(Vec::new());
-            if let Data::Long(sub_ifd_offsets) = tag_info.data.clone() {
-                for (_sub_idx, sud_ifd_offset) in sub_ifd_offsets.iter().enumerate() {
-                    let mut next_ifd = *sud_ifd_offset as usize;
-                    while next_ifd > 0 {
-                        next_ifd = read_ifd(file, info, next_ifd, ifd_list).unwrap();
-                    }
+            let sub_ifd_offsets: Vec<usize> = match &tag_info.data {
+                Data::Short(val) => val.iter().map(|offset| *offset as usize).collect(),
+                Data::Long(val) => val.iter().map(|offset| *offset as usize).collect(),
+                Data::Long8(val) => val.iter().map(|offset| *offset as usize).collect(),
+                _ => Vec::new(),
+            };
+            if sub_ifd_offsets.iter().all(|offset| *offset == 0) {
+                debug!("Ifd at {} has an empty SubIFDs tag", ifd.offset);
+            }
+            for sub_ifd_offset in sub_ifd_offsets {
+                let mut next_ifd = sub_ifd_offset;
+                while next_ifd > 0 {
+                    // errors from the sub-ifd already carry its location
+                    next_ifd = read_ifd_tracked(file, info, next_ifd, ifd_list, visited)?;
                 }
             }
         }
     }
+    Ok(())
 }
 
 /// Check if a specific number of bytes can be read from a file at a given offset.
@@ -278,7 +483,7 @@ pub fn check_offset(source_length: usize, offset: usize, length: usize) -> bool
     // def check_offset(filelen, offset, length):
     //     # The minimum offset is the length of the tiff header
     //     allowed = offset >= 8 and length >= 0 and offset + length <= filelen
-    let allowed = (offset >= 8) & (offset + length <= source_length);
+    let allowed = (offset >= 8) & offset.checked_add(length).map_or(false, |end| end <= source_length);
 
     if !allowed {
         error!(
@@ -290,8 +495,232 @@ pub fn check_offset(source_length: usize, offset: usize, length: usize) -> bool
     allowed
 }
 
+/// Start of every Alias marker, used to recognize markers from versions not listed in `ALIAS_MARKERS`
+pub const ALIAS_MARKER_PREFIX: &str = "Alias MultiLayer TIFF";
+/// Alias marker written by older versions of Sketchbook
+pub const ALIAS_MARKER_V1_0: &str = "Alias MultiLayer TIFF V1.0";
+/// Alias marker written by current versions of Sketchbook
+pub const ALIAS_MARKER_V1_1: &str = "Alias MultiLayer TIFF V1.1";
+/// Alias markers known to be written by Sketchbook (without the trailing null)
+pub const ALIAS_MARKERS: &[&str] = &[ALIAS_MARKER_V1_0, ALIAS_MARKER_V1_1];
+
+/// Check if a Software tag value is an Alias marker
+///
+/// The trailing null is optional and runs of whitespace are treated as a single space.  Along with
+/// the versions in `ALIAS_MARKERS`, any version is accepted as long as the text starts with
+/// `ALIAS_MARKER_PREFIX`.
+///
+/// # Arguments
+///
+/// * `text` - Value of the Software tag (305)
+///
+/// # Returns
+///
+/// * True if the text is an Alias marker
+///
+/// # Example
+/// ```rust
+/// assert!(is_alias_marker("Alias MultiLayer TIFF V1.1\u{0}"));
+/// assert!(is_alias_marker("Alias  MultiLayer TIFF V1.0"));
+/// ```
+pub fn is_alias_marker(text: &str) -> bool {
+    let words: Vec<&str> = text.trim_end_matches('\u{0}').split_whitespace().collect();
+    words.join(" ").starts_with(ALIAS_MARKER_PREFIX)
+}
+
+/// Check if an ifd holds the composite (merged) image
+///
+/// Sketchbook marks the composite image by writing the Alias marker to tag 305.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd data
+///
+/// # Returns
+///
+/// * True if this is the composite image ifd
+pub fn is_composite_ifd(ifd: &Ifd) -> bool {
+    match ifd.tags.get(&305).and_then(|tag| tag.data.as_string()) {
+        Some(val) => is_alias_marker(val),
+        None => false,
+    }
+}
+
+/// Bit of the NewSubfileType tag (254) marking a reduced resolution version of another image
+pub const SUBFILE_REDUCED_RESOLUTION: u32 = 1;
+/// Bit of the NewSubfileType tag (254) marking a single page of a multi-page image
+pub const SUBFILE_PAGE: u32 = 2;
+/// Bit of the NewSubfileType tag (254) marking a transparency mask for another image
+pub const SUBFILE_MASK: u32 = 4;
+
+/// Read the NewSubfileType tag (254) of an ifd
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd data
+///
+/// # Returns
+///
+/// * The bitfield, or 0 (a full resolution image) when the tag is missing
+pub fn subfile_type(ifd: &Ifd) -> u32 {
+    ifd.tag_u32s(254)
+        .and_then(|val| val.first().copied())
+        .unwrap_or(0)
+}
+
+/// Check if an ifd holds a reduced resolution image (thumbnail)
+///
+/// Reduced resolution images are marked by setting bit 0 of the NewSubfileType tag (254).  The other
+/// bits may be set as well, for example on a reduced resolution page of a multi-page tiff.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd data
+///
+/// # Returns
+///
+/// * True if this is a thumbnail ifd
+pub fn is_thumbnail_ifd(ifd: &Ifd) -> bool {
+    subfile_type(ifd) & SUBFILE_REDUCED_RESOLUTION != 0
+}
+
+/// Check if an ifd holds a transparency mask
+///
+/// Masks are marked by setting bit 2 of the NewSubfileType tag (254).
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd data
+///
+/// # Returns
+///
+/// * True if this is a mask ifd
+pub fn is_mask_ifd(ifd: &Ifd) -> bool {
+    subfile_type(ifd) & SUBFILE_MASK != 0
+}
+
+/// The role an ifd plays in a Sketchbook tiff
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum IfdKind {
+    /// Composite (merged) image of all the layers
+    Composite,
+    /// Reduced resolution image
+    Thumbnail,
+    /// Transparency mask for another image.  Open Raster has no masks so these are not converted.
+    Mask,
+    /// Single paint layer
+    Layer,
+}
+
+/// Classify an ifd without decoding its image
+///
+/// The composite is marked by the Alias marker in tag 305 and takes precedence over the NewSubfileType
+/// tag (254).  A mask bit in tag 254 takes precedence over the reduced resolution bit, so a reduced
+/// resolution mask isn't used as the thumbnail.  Anything else is a layer.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd data
+///
+/// # Returns
+///
+/// * The kind of ifd
+pub fn classify_ifd(ifd: &Ifd) -> IfdKind {
+    if is_composite_ifd(ifd) {
+        IfdKind::Composite
+    } else if is_mask_ifd(ifd) {
+        IfdKind::Mask
+    } else if is_thumbnail_ifd(ifd) {
+        IfdKind::Thumbnail
+    } else {
+        IfdKind::Layer
+    }
+}
+
+/// Classify every ifd in a tiff without decoding any images
+///
+/// # Arguments
+///
+/// * `ifds` - A list of IFDs
+///
+/// # Returns
+///
+/// * The kind of each ifd, in the same order as `ifds`
+///
+/// # Example
+/// ```rust
+/// let (info, ifds) = read_tiff(&file)?;
+/// for (ifd, kind) in ifds.iter().zip(classify_ifds(&ifds)) {
+///     println!("{:?} at {}", kind, ifd.offset);
+/// }
+/// ```
+pub fn classify_ifds(ifds: &[Ifd]) -> Vec<IfdKind> {
+    ifds.iter().map(classify_ifd).collect()
+}
+
+/// Get the main ifd of a Sketchbook tiff, which holds the composite (merged) image
+///
+/// # Arguments
+///
+/// * `ifds` - A list of IFDs
+///
+/// # Returns
+///
+/// * The composite image ifd or None if there isn't one
+///
+/// # Example
+/// ```rust
+/// let (info, ifds) = read_tiff(&file)?;
+/// let main = get_main_ifd(&ifds).expect("not a Sketchbook tiff");
+/// ```
+pub fn get_main_ifd(ifds: &[Ifd]) -> Option<&Ifd> {
+    ifds.iter().find(|ifd| is_composite_ifd(ifd))
+}
+
+/// Get the data of a tag in one of the ifds
+///
+/// # Arguments
+///
+/// * `ifds` - A list of IFDs
+/// * `ifd_index` - Index of the ifd in the list
+/// * `tag` - Integer tag value
+///
+/// # Returns
+///
+/// * The tag data or None if there is no such ifd or the ifd doesn't have the tag
+///
+/// # Example
+/// ```rust
+/// let (info, ifds) = read_tiff(&file)?;
+/// let software = get_tag(&ifds, 0, 305).and_then(|data| data.as_string());
+/// ```
+pub fn get_tag(ifds: &[Ifd], ifd_index: usize, tag: u64) -> Option<&Data> {
+    ifds.get(ifd_index)?.tags.get(&tag).map(|tag| &tag.data)
+}
+
+/// Check if an ifd holds image data
+///
+/// An image ifd needs its size (ImageWidth 256 and ImageLength 257) and the location of its pixels
+/// (StripOffsets 273 or TileOffsets 324).  Anything else only holds metadata.
+///
+/// # Arguments
+///
+/// * `ifd` - Reference to the ifd data
+///
+/// # Returns
+///
+/// * True if the ifd has the tags of an image
+pub fn has_image_data(ifd: &Ifd) -> bool {
+    ifd.tags.contains_key(&256)
+        && ifd.tags.contains_key(&257)
+        && (ifd.tags.contains_key(&273) || ifd.tags.contains_key(&324))
+}
+
 /// Get the layers embedded in the tiff file based on data in a list of ifds
 ///
+/// Ifds without image data (see `has_image_data`) are skipped and given an empty vector, so the output
+/// still lines up with the list of ifds.
+///
 /// # Arguments
 ///
 /// * `ifds` - A list of IFDs
@@ -301,38 +730,42 @@ pub fn check_offset(source_length: usize, offset: usize, length: usize) -> bool
 ///
 /// Vector of layers where each layer is a vector of bytes describing a tiff file
 ///
-pub fn get_layers(ifds: Vec<Ifd>, source: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn Error>> {
+pub fn get_layers(ifds: Vec<Ifd>, source: &[u8]) -> Result<Vec<Vec<u8>>, SkoraError> {
     // Initialize and output vector
     let mut layers: Vec<Vec<u8>> = Vec::new();
 
     // Loop through each IDF in the input and create an image for it
     for ifd in ifds {
+        if !has_image_data(&ifd) {
+            debug!("Ifd at {} has no image data, skipping it", ifd.offset);
+            layers.push(Vec::new());
+            continue;
+        }
+
         let mut image: Vec<u8> = Vec::new();
 
         let endian = ifd.endian;
         let big_tiff = ifd.big_tiff;
 
-        // Initialize the image header
-        let mut header = match big_tiff {
+        // Initialize the image header, starting with the byte order mark (b'II' or b'MM')
+        let mut header = match endian {
+            Endian::Little => vec![0x49, 0x49],
+            Endian::Big => vec![0x4D, 0x4D],
+        };
+        match big_tiff {
             true => {
-                // header = b'MM'
-                let mut hdr = vec![0x4D, 0x4D];
-                order_write_16(endian, &mut hdr, 0x2B_u16);
-                order_write_16(endian, &mut hdr, 8_u16);
-                order_write_16(endian, &mut hdr, 0_u16);
-                order_write_64(endian, &mut hdr, 0_u64);
+                order_write_16(endian, &mut header, 0x2B_u16);
+                order_write_16(endian, &mut header, 8_u16);
+                order_write_16(endian, &mut header, 0_u16);
+                order_write_64(endian, &mut header, 0_u64);
                 // Cut off the last 8 bytes which are the pointer to the first IFD.  These will be added back in from copy_ifd
-                hdr
             }
             false => {
-                // header = b'II'
-                let mut hdr = vec![0x49, 0x49];
-                order_write_16(endian, &mut hdr, 0x2A_u16);
-                order_write_32(endian, &mut hdr, 0_u32);
+                order_write_16(endian, &mut header, 0x2A_u16);
+                order_write_32(endian, &mut header, 0_u32);
                 // Cut off the last 4 bytes which are the pointer to the first IFD.  These will be added back in from copy_ifd
-                hdr
             }
-        };
+        }
 
         // Get a pointer to the ifd location in the header
         let ifd_pointer = match big_tiff {
@@ -344,7 +777,13 @@ pub fn get_layers(ifds: Vec<Ifd>, source: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn
         image.append(&mut header);
 
         // Add the ifds to the image
-        copy_ifd(&mut image, ifd, ifd_pointer, source);
+        // an ifd whose data can't be copied is given an empty vector so the remaining layers still convert
+        let ifd_offset = ifd.offset;
+        if let Err(val) = copy_ifd(&mut image, ifd, ifd_pointer, source) {
+            error!("Unable to copy ifd at {}: {}", ifd_offset, val);
+            layers.push(Vec::new());
+            continue;
+        }
 
         // Add this image to the vector of layer images
         layers.push(image);
@@ -352,6 +791,121 @@ pub fn get_layers(ifds: Vec<Ifd>, source: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn
     Ok(layers)
 }
 
+/// Check that the image data in a reconstructed tiff matches the source tiff
+///
+/// The reconstructed tiff is re-read and the bytes of each strip (or tile) are compared against
+/// the bytes of the matching strip in the source.  Any mismatch is logged.
+///
+/// # Arguments
+///
+/// * `ifd` - The ifd record the image was reconstructed from
+/// * `source` - The bytes of the original tiff image
+/// * `image` - The bytes of the reconstructed tiff image
+///
+/// # Returns
+///
+/// True if all of the image data matches, false if not
+///
+pub fn verify_layer(ifd: &Ifd, source: &[u8], image: &[u8]) -> bool {
+    let new_ifd = match read_tiff(image) {
+        Ok((_info, ifds)) => match ifds.into_iter().next() {
+            Some(val) => val,
+            None => {
+                error!("Reconstructed tiff for ifd at {} has no ifds", ifd.offset);
+                return false;
+            }
+        },
+        Err(val) => {
+            error!("Unable to read reconstructed tiff for ifd at {}: {}", ifd.offset, val);
+            return false;
+        }
+    };
+
+    let mut matches = true;
+    // StripOffsets / StripByteCounts and TileOffsets / TileByteCounts
+    for (offset_tag, length_tag) in [(273, 279), (324, 325)].iter() {
+        let (source_offsets, source_lengths) = match (ifd.tag_u32s(*offset_tag), ifd.tag_u32s(*length_tag)) {
+            (Some(offsets), Some(lengths)) => (offsets, lengths),
+            _ => continue,
+        };
+        let new_offsets = new_ifd.tag_u32s(*offset_tag).unwrap_or_default();
+
+        if new_offsets.len() != source_offsets.len() {
+            error!(
+                "Ifd at {}: {} {} offsets in source but {} in reconstructed tiff",
+                ifd.offset,
+                tag_name(*offset_tag).unwrap_or("unknown"),
+                source_offsets.len(),
+                new_offsets.len()
+            );
+            matches = false;
+            continue;
+        }
+
+        let chunks = source_offsets.iter().zip(new_offsets.iter()).zip(source_lengths.iter());
+        for (idx, ((source_offset, new_offset), length)) in chunks.enumerate() {
+            let source_start = *source_offset as usize;
+            let new_start = *new_offset as usize;
+            let length = *length as usize;
+            let source_data = source.get(source_start..source_start + length);
+            let new_data = image.get(new_start..new_start + length);
+            if source_data.is_none() || source_data != new_data {
+                error!(
+                    "Ifd at {}: {} {} of reconstructed tiff does not match the source",
+                    ifd.offset,
+                    tag_name(*offset_tag).unwrap_or("unknown"),
+                    idx
+                );
+                matches = false;
+            }
+        }
+    }
+    matches
+}
+
+/// Software tag (305) value written to tiffs created by this library
+pub const SOFTWARE: &str = concat!("skora ", env!("CARGO_PKG_VERSION"));
+
+/// Add Software (305) and DateTime (306) tags to an ifd so files written from it can be told apart
+/// from files written by Sketchbook
+///
+/// Tags that are already present are left alone so the Alias marker in the Software tag of the
+/// composite is kept.
+///
+/// # Arguments
+///
+/// * `ifd` - The ifd to add the tags to
+/// * `software` - Name of the program writing the file
+/// * `date_time` - Date and time the file is written as `YYYY:MM:DD HH:MM:SS`
+///
+/// # Example
+/// ```rust
+/// let mut ifd = ifds[idx].clone();
+/// add_provenance_tags(&mut ifd, SOFTWARE, "2021:06:01 12:00:00");
+/// ```
+pub fn add_provenance_tags(ifd: &mut Ifd, software: &str, date_time: &str) {
+    for (tag, text) in [(305, software), (306, date_time)].iter() {
+        if ifd.tags.contains_key(tag) {
+            continue;
+        }
+        // ascii values are written with a terminating null
+        let mut value = text.to_string();
+        value.push('\u{0}');
+        ifd.tags.insert(
+            *tag,
+            Tag {
+                count: value.len() as u64,
+                data: Data::Ascii(value),
+                datapos: 0,
+                datatype: DataType::Ascii,
+                ifds: None,
+                offset: None,
+            },
+        );
+        ifd.tag_count = ifd.tags.len() as u64;
+    }
+}
+
 /// Write an IFD to a TIFF file.  This copies image data from other tiff files.
 ///
 /// # Arguments
@@ -361,7 +915,12 @@ pub fn get_layers(ifds: Vec<Ifd>, source: &[u8]) -> Result<Vec<Vec<u8>>, Box<dyn
 /// `ifd_pointer` - a location to write the value of this ifd's start
 /// `source` - Reference to the byte slice containing the original tiff
 ///
-pub fn copy_ifd(image: &mut Vec<u8>, ifd: Ifd, ifd_pointer: usize, source: &[u8]) {
+/// # Returns
+///
+/// An error if the image data referenced by the ifd is outside of the source or ends up past 4GB
+/// in a classic tiff
+///
+pub fn copy_ifd(image: &mut Vec<u8>, ifd: Ifd, ifd_pointer: usize, source: &[u8]) -> Result<(), SkoraError> {
     let tag_data_length = match ifd.big_tiff {
         true => 8,
         false => 4,
@@ -394,56 +953,44 @@ pub fn copy_ifd(image: &mut Vec<u8>, ifd: Ifd, ifd_pointer: usize, source: &[u8]
         // Tag 521:JPEGACTables ref lengths is vec of size count where each value is 4 (Long)
         match tag_num {
             273 | 288 | 324 | 513 | 519 | 520 | 521 => {
+                // byte counts and offsets may legally be stored as either Short or Long
+                // a missing or non-integer list (including a count of 0) is treated as empty rather than panicking
                 let ref_lengths = match tag_num {
-                    273 => {
-                        if let Data::Long(val) = ifd.tags[&279].data.clone() {
-                            val
-                        } else {
-                            panic!()
-                        }
-                    }
-                    288 => {
-                        if let Data::Long(val) = ifd.tags[&289].data.clone() {
-                            val
-                        } else {
-                            panic!()
-                        }
-                    }
-                    324 => {
-                        if let Data::Long(val) = ifd.tags[&325].data.clone() {
-                            val
-                        } else {
-                            panic!()
-                        }
-                    }
-                    513 => {
-                        if let Data::Long(val) = ifd.tags[&514].data.clone() {
-                            val
-                        } else {
-                            panic!()
-                        }
-                    }
-                    _ => vec![tag_info.datatype.element_size_in_bytes() as u32; count as usize],
+                    273 => ifd.tag_u64s(279),
+                    288 => ifd.tag_u64s(289),
+                    324 => ifd.tag_u64s(325),
+                    513 => ifd.tag_u64s(514),
+                    _ => Some(vec![tag_info.datatype.element_size_in_bytes(); count as usize]),
                 };
+                let ref_lengths = ref_lengths.unwrap_or_else(|| {
+                    error!("Byte counts for {} are missing or not integers", tag_name(*tag_num).unwrap_or("unknown"));
+                    Vec::new()
+                });
 
-                let offsets_list = match data {
-                    Data::Long(val) => val, // all the data types in isOffsetData are Long
-                    _ => panic!(),
-                };
+                let offsets_list = ifd.tag_u64s(*tag_num).unwrap_or_else(|| {
+                    error!("{} offsets are not integers", tag_name(*tag_num).unwrap_or("unknown"));
+                    Vec::new()
+                });
 
                 // data = write_tag_data(dest, src, data, ifd['tags'][int(tagSet[tag.bytecounts])]['data'], ifd['size'])
                 // or
                 // data = write_tag_data(dest, src, data, [tag.bytecounts] * count, ifd['size'])
                 // depending on if bytecounts is a string (278, 288, 324, 513)
-                data = Data::Long(copy_tag_data(
-                    image,
-                    source,
-                    offsets_list,
-                    ref_lengths,
-                    ifd.size,
-                ));
-
-                tag_info.datatype = DataType::Long;
+                let dest_offsets = copy_tag_data(image, source, offsets_list, ref_lengths, ifd.size)?;
+
+                data = offsets_data(*tag_num, dest_offsets, ifd.big_tiff)?;
+                tag_info.datatype = data.datatype();
+            }
+            // Tag 321:HalftoneHints (2 Short) and 336:DotRange (Byte or Short) hold values rather than
+            // offsets even though they sit among the offset tags, so they are always copied as they are
+            321 | 336 => {
+                if !matches!(tag_info.datatype, DataType::Byte | DataType::Short) {
+                    debug!(
+                        "{} is {:?} rather than Byte or Short, copying it unchanged",
+                        tag_name(*tag_num).unwrap_or("unknown"),
+                        tag_info.datatype
+                    );
+                }
             }
             _ => {}
         }
@@ -531,6 +1078,31 @@ pub fn copy_ifd(image: &mut Vec<u8>, ifd: Ifd, ifd_pointer: usize, source: &[u8]
         _ => panic!(),
     }
     image.append(&mut temp);
+    Ok(())
+}
+
+/// Store the destination offsets of a tag as Long unless the data ends up past 4GB, which only a
+/// BigTIFF can point to
+///
+/// # Arguments
+///
+/// * `tag_num` - Integer tag value, used in the error message
+/// * `offsets` - Offsets of the data in the destination tiff
+/// * `big_tiff` - True if the destination is a BigTIFF
+///
+/// # Returns
+///
+/// * The offsets as tag data or an error if a classic tiff can't hold them
+fn offsets_data(tag_num: u64, offsets: Vec<u64>, big_tiff: bool) -> Result<Data, SkoraError> {
+    let narrow: Option<Vec<u32>> = offsets.iter().map(|val| u32::try_from(*val).ok()).collect();
+    match narrow {
+        Some(val) => Ok(Data::Long(val)),
+        None if big_tiff => Ok(Data::Long8(offsets)),
+        None => Err(SkoraError::TooLarge(format!(
+            "{} points past 4GB which can't be written to a classic tiff",
+            tag_name(tag_num).unwrap_or("unknown")
+        ))),
+    }
 }
 
 /// Copy data from a source tiff to a destination tiff, return a list of offsets where data was written.
@@ -545,22 +1117,29 @@ pub fn copy_ifd(image: &mut Vec<u8>, ifd: Ifd, ifd_pointer: usize, source: &[u8]
 ///
 /// # Returns
 ///
-/// The offsets in the destination file corresponding to the data copied
+/// The offsets in the destination file corresponding to the data copied.  These are u64 so data past 4GB
+/// in a BigTiff can be addressed, narrowing them to the tag's datatype is left to the caller.  An error is
+/// returned if any of the data is outside of the source, as the offsets written would then point at
+/// the wrong data.
 ///
 pub fn copy_tag_data(
     image: &mut Vec<u8>,
     source: &[u8],
-    offsets: Vec<u32>,
-    lengths: Vec<u32>,
+    offsets: Vec<u64>,
+    lengths: Vec<u64>,
     source_length: usize,
-) -> Vec<u32> {
+) -> Result<Vec<u64>, SkoraError> {
     if offsets.len() != lengths.len() {
         error!("Offsets and byte counts do not correspond.");
     }
 
-    let mut dest_offsets = vec![0; offsets.len()];
-    // because we are doing things are bit differently we need to init the first location with the current dest length
-    dest_offsets[0] = image.len() as u32;
+    // a tag with a count of 0 has no data to copy
+    if offsets.is_empty() {
+        return Ok(Vec::new());
+    }
+
+    // because we are doing things are bit differently we need to start from the current dest length
+    let dest_offsets = destination_offsets(image.len() as u64, &lengths, offsets.len());
 
     // # We preserve the order of the chunks from the original file
     let offset_list = offsets.clone();
@@ -572,24 +1151,405 @@ pub fn copy_tag_data(
         let offset = offset_list[olidx];
         let idx = idx_list[olidx];
 
-        let length = lengths[idx];
+        // a missing byte count copies nothing rather than reading past the list
+        let length = lengths.get(idx).copied().unwrap_or(0);
+
+        let (start, length) = match (usize::try_from(offset), usize::try_from(length)) {
+            (Ok(start), Ok(length)) => (start, length),
+            _ => (usize::MAX, 0),
+        };
 
         // if offset and check_offset(srclen, offset, length):
-        if check_offset(source_length, offset as usize, length as usize) {
-            let start = offset as usize;
-            let end = (offset + length) as usize;
-            let source_data = &source[start..end];
-            image.append(&mut source_data.to_vec());
+        if !check_offset(source_length, start, length) {
+            return Err(SkoraError::InvalidTiff(format!(
+                "Chunk {} of {} bytes at offset {} is outside of the source",
+                idx, length, offset
+            )));
         }
+        let end = start + length;
+        let source_data = &source[start..end];
+        image.append(&mut source_data.to_vec());
 
         olidx += 1;
     }
 
-    for (index, _val) in lengths.iter().enumerate() {
-        if index > 0 {
-            dest_offsets[index] = dest_offsets[index - 1] + lengths[index - 1]
+    Ok(dest_offsets)
+}
+
+/// Offsets in the destination file of chunks copied back to back
+///
+/// # Arguments
+///
+/// `start` - Offset of the first chunk
+/// `lengths` - The length of each chunk, a missing length is treated as 0
+/// `count` - The number of chunks
+///
+/// # Returns
+///
+/// The offset of each chunk
+///
+fn destination_offsets(start: u64, lengths: &[u64], count: usize) -> Vec<u64> {
+    let mut dest_offsets = vec![0; count];
+    if let Some(first) = dest_offsets.first_mut() {
+        *first = start;
+    }
+    for index in 1..count {
+        // accumulate as u64 so strips that add up past 4GB don't wrap around
+        dest_offsets[index] = dest_offsets[index - 1].saturating_add(lengths.get(index - 1).copied().unwrap_or(0))
+    }
+    dest_offsets
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_tiff::{ascii, ifd, rgba_tags, tiff_file, TestIfd};
+
+    #[test]
+    fn copy_short_strip_byte_counts() {
+        let strip: Vec<u8> = (0..16).collect();
+        let layer = TestIfd {
+            tags: rgba_tags(2, 2),
+            strips: vec![strip.clone()],
+            short_byte_counts: true,
+            ..TestIfd::default()
+        };
+        let file = tiff_file(Endian::Little, false, &[layer]);
+        let (_info, ifds) = read_tiff(&file).unwrap();
+        assert!(matches!(ifds[0].tags[&279].data, Data::Short(_)));
+
+        let layers = get_layers(ifds.clone(), &file).unwrap();
+        assert!(verify_layer(&ifds[0], &file, &layers[0]));
+        let (_info, copied) = read_tiff(&layers[0]).unwrap();
+        let offset = copied[0].tag_u32s(273).unwrap()[0] as usize;
+        assert_eq!(&layers[0][offset..offset + 16], &strip[..]);
+    }
+
+    #[test]
+    fn copy_dot_range_and_halftone_hints() {
+        let mut tags = rgba_tags(2, 2);
+        tags.push((321, Data::Short(vec![10, 240])));
+        // one range per sample so the values don't fit in the entry and are written after it
+        tags.push((336, Data::Short(vec![0, 255, 0, 255, 0, 255, 0, 255])));
+        let layer = TestIfd {
+            tags,
+            strips: vec![vec![0; 16]],
+            ..TestIfd::default()
+        };
+        let file = tiff_file(Endian::Little, false, &[layer]);
+        let (_info, ifds) = read_tiff(&file).unwrap();
+
+        let layers = get_layers(ifds, &file).unwrap();
+        let (_info, copied) = read_tiff(&layers[0]).unwrap();
+        assert_eq!(copied[0].tag_u32s(321), Some(vec![10, 240]));
+        assert_eq!(copied[0].tag_u32s(336), Some(vec![0, 255, 0, 255, 0, 255, 0, 255]));
+        assert!(matches!(copied[0].tags[&336].data, Data::Short(_)));
+    }
+
+    #[test]
+    fn copied_layers_keep_the_byte_order() {
+        for endian in [Endian::Little, Endian::Big].iter() {
+            for big_tiff in [false, true].iter() {
+                let file = small_tiff(*endian, *big_tiff);
+                let (_info, ifds) = read_tiff(&file).unwrap();
+                let layers = get_layers(ifds, &file).unwrap();
+                assert_eq!(&layers[0][..2], &file[..2]);
+                let (info, copied) = read_tiff(&layers[0]).unwrap();
+                assert_eq!(info.big_tiff, *big_tiff);
+                assert_eq!(copied[0].tag_u32s(256), Some(vec![2]));
+            }
         }
     }
 
-    dest_offsets
+    #[test]
+    fn ifd_without_image_data_is_skipped() {
+        assert!(has_image_data(&ifd(vec![(256, Data::Long(vec![2])), (257, Data::Long(vec![2])), (273, Data::Long(vec![8]))])));
+        assert!(has_image_data(&ifd(vec![(256, Data::Long(vec![2])), (257, Data::Long(vec![2])), (324, Data::Long(vec![8]))])));
+        assert!(!has_image_data(&ifd(vec![(256, Data::Long(vec![2])), (257, Data::Long(vec![2]))])));
+        assert!(!has_image_data(&ifd(vec![(305, ascii("SketchBook"))])));
+
+        let layer = |size: u32| TestIfd {
+            tags: rgba_tags(size, size),
+            strips: vec![vec![0; (size * size * 4) as usize]],
+            ..TestIfd::default()
+        };
+        let metadata = TestIfd {
+            tags: vec![(256, Data::Long(vec![2])), (257, Data::Long(vec![2])), (305, ascii("SketchBook"))],
+            ..TestIfd::default()
+        };
+        let file = tiff_file(Endian::Little, false, &[layer(2), metadata, layer(1)]);
+        let (_info, ifds) = read_tiff(&file).unwrap();
+        assert_eq!(ifds.iter().map(has_image_data).collect::<Vec<bool>>(), vec![true, false, true]);
+
+        // the metadata ifd gets an empty vector so the layers still line up with the ifds
+        let layers = get_layers(ifds, &file).unwrap();
+        assert_eq!(layers.len(), 3);
+        assert!(layers[1].is_empty());
+        assert_eq!(read_tiff(&layers[0]).unwrap().1[0].tag_u32s(256), Some(vec![2]));
+        assert_eq!(read_tiff(&layers[2]).unwrap().1[0].tag_u32s(256), Some(vec![1]));
+    }
+
+    #[test]
+    fn destination_offsets_past_4gb() {
+        let max = u32::MAX as u64;
+        assert_eq!(destination_offsets(8, &[max, max], 2), vec![8, 8 + max]);
+        assert_eq!(destination_offsets(8, &[max, max], 3), vec![8, 8 + max, 8 + 2 * max]);
+        assert!(destination_offsets(8, &[], 0).is_empty());
+    }
+
+    #[test]
+    fn offsets_past_4gb_in_a_classic_tiff() {
+        let past = u32::MAX as u64 + 8;
+        assert!(matches!(offsets_data(273, vec![8, 16], false), Ok(Data::Long(val)) if val == vec![8, 16]));
+        assert!(matches!(offsets_data(273, vec![8, past], true), Ok(Data::Long8(val)) if val == vec![8, past]));
+        assert!(matches!(offsets_data(273, vec![8, past], false), Err(SkoraError::TooLarge(_))));
+    }
+
+    #[test]
+    fn strip_outside_source_is_skipped() {
+        let layer = TestIfd {
+            tags: rgba_tags(2, 2),
+            strips: vec![vec![1; 16]],
+            ..TestIfd::default()
+        };
+        let file = tiff_file(Endian::Little, false, &[layer.clone(), layer]);
+        let (_info, mut ifds) = read_tiff(&file).unwrap();
+        ifds[0].tags.get_mut(&273).unwrap().data = Data::Long(vec![file.len() as u32]);
+
+        let layers = get_layers(ifds.clone(), &file).unwrap();
+        assert!(layers[0].is_empty());
+        assert!(verify_layer(&ifds[1], &file, &layers[1]));
+    }
+
+    #[test]
+    fn classify_subfile_types() {
+        assert_eq!(subfile_type(&ifd(Vec::new())), 0);
+        let kinds = [(0, IfdKind::Layer), (1, IfdKind::Thumbnail), (2, IfdKind::Layer), (4, IfdKind::Mask)];
+        for (value, kind) in kinds.iter() {
+            let image = ifd(vec![(254, Data::Long(vec![*value]))]);
+            assert_eq!(subfile_type(&image), *value);
+            assert_eq!(classify_ifd(&image), *kind, "NewSubfileType {}", value);
+        }
+        // a reduced resolution page is still a thumbnail and a reduced resolution mask is a mask
+        assert_eq!(classify_ifd(&ifd(vec![(254, Data::Long(vec![3]))])), IfdKind::Thumbnail);
+        assert_eq!(classify_ifd(&ifd(vec![(254, Data::Long(vec![5]))])), IfdKind::Mask);
+    }
+
+    #[test]
+    fn huge_ifd_tag_count() {
+        // BigTIFF header pointing at an ifd at 16 that claims 0xFFFFFFFF tags
+        let mut file = b"MM\x00\x2b\x00\x08\x00\x00".to_vec();
+        file.extend_from_slice(&16_u64.to_be_bytes());
+        file.extend_from_slice(&0xFFFF_FFFF_u64.to_be_bytes());
+        file.extend_from_slice(&[0; 28]);
+        match read_tiff(&file) {
+            Err(SkoraError::ParseAt { ifd_offset: 16, source, .. }) => {
+                assert!(matches!(*source, SkoraError::CorruptTagCount { offset: 16, count: 0xFFFF_FFFF }))
+            }
+            other => panic!("expected a corrupt tag count, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn next_ifd_offset_into_appended_junk() {
+        let mut file = small_tiff(Endian::Little, false);
+        let (_info, ifds) = read_tiff(&file).unwrap();
+        let junk_start = file.len();
+        file.extend_from_slice(&[0xFF; 32]);
+        // junk after the tiff data on its own is ignored
+        assert_eq!(read_tiff(&file).unwrap().1.len(), 1);
+
+        // point the next ifd offset at the junk, which reads as 0xFFFF tags
+        let next = ifds[0].offset + 2 + 12 * ifds[0].tag_count as usize;
+        file[next..next + 4].copy_from_slice(&(junk_start as u32).to_le_bytes());
+        match read_tiff(&file) {
+            Err(SkoraError::ParseAt { ifd_offset, source, .. }) => {
+                assert_eq!(ifd_offset, junk_start);
+                assert!(matches!(*source, SkoraError::CorruptTagCount { offset, count: 0xFFFF } if offset == junk_start))
+            }
+            other => panic!("expected a corrupt tag count, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn huge_tag_value_count() {
+        // classic ifd with a single ImageWidth entry that claims 0xFFFFFFFF values
+        let mut file = b"II\x2a\x00\x08\x00\x00\x00\x01\x00\x00\x01\x04\x00".to_vec();
+        file.extend_from_slice(&0xFFFF_FFFF_u32.to_le_bytes());
+        file.extend_from_slice(&[0; 8]);
+        assert!(read_tiff(&file).is_err());
+    }
+
+    #[test]
+    fn self_referencing_sub_ifd() {
+        // ifd whose SubIFDs tag (330) points back to itself
+        let file = b"II\x2a\x00\x08\x00\x00\x00\x01\x00\x4a\x01\x04\x00\x01\x00\x00\x00\x08\x00\x00\x00\x00\x00\x00\x00";
+        let (_info, ifds) = read_tiff(file).unwrap();
+        assert_eq!(ifds.len(), 1);
+    }
+
+    #[test]
+    fn sub_ifd_referencing_its_parent() {
+        // ifd at 8 has a sub-ifd at 26 whose SubIFDs tag points back at 8
+        let mut file = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+        for sub_ifd in [26_u32, 8].iter() {
+            file.extend_from_slice(b"\x01\x00\x4a\x01\x04\x00\x01\x00\x00\x00");
+            file.extend_from_slice(&sub_ifd.to_le_bytes());
+            file.extend_from_slice(&[0; 4]);
+        }
+        let (_info, ifds) = read_tiff(&file).unwrap();
+        let mut offsets: Vec<usize> = ifds.iter().map(|ifd| ifd.offset).collect();
+        offsets.sort_unstable();
+        assert_eq!(offsets, vec![8, 26]);
+    }
+
+    #[test]
+    fn empty_sub_ifds() {
+        // ifd with a SubIFDs tag (330) that has a count of 0
+        let file = b"II\x2a\x00\x08\x00\x00\x00\x02\x00\x00\x01\x03\x00\x01\x00\x00\x00\x01\x00\x00\x00\x4a\x01\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00\x00";
+        let (_info, ifds) = read_tiff(file).unwrap();
+        assert_eq!(ifds.len(), 1);
+        assert_eq!(ifds[0].tags[&330].count, 0);
+    }
+
+    /// Read a tiff whose composite has a single sub-ifd with the SubIFDs tag (330) written as the given type
+    fn read_sub_ifd_typed(big_tiff: bool, type_id: u16) -> Vec<Ifd> {
+        let composite = TestIfd {
+            tags: rgba_tags(2, 2),
+            sub_ifds: vec![TestIfd {
+                tags: rgba_tags(1, 1),
+                ..TestIfd::default()
+            }],
+            sub_ifd_type: Some(type_id),
+            ..TestIfd::default()
+        };
+        let (_info, ifds) = read_tiff(&tiff_file(Endian::Little, big_tiff, &[composite])).unwrap();
+        ifds
+    }
+
+    #[test]
+    fn ifd8_sub_ifds() {
+        let ifds = read_sub_ifd_typed(true, 18);
+        assert_eq!(ifds.len(), 2);
+        let composite = ifds.iter().find(|ifd| ifd.tags.contains_key(&330)).unwrap();
+        assert_eq!(composite.tags[&330].datatype, DataType::Ifd8);
+        assert!(matches!(composite.tags[&330].data, Data::Long8(_)));
+    }
+
+    #[test]
+    fn short_sub_ifds() {
+        let ifds = read_sub_ifd_typed(false, 3);
+        assert_eq!(ifds.len(), 2);
+        assert!(ifds.iter().any(|ifd| ifd.tag_u32s(256) == Some(vec![1])));
+    }
+
+    /// Single 2x2 layer tiff
+    fn small_tiff(endian: Endian, big_tiff: bool) -> Vec<u8> {
+        let layer = TestIfd {
+            tags: rgba_tags(2, 2),
+            strips: vec![vec![0; 16]],
+            ..TestIfd::default()
+        };
+        tiff_file(endian, big_tiff, &[layer])
+    }
+
+    #[test]
+    fn big_tiff_first_ifd_offset_is_8_bytes() {
+        // the high half of a big endian offset comes first so reading 4 bytes would give 0
+        let file = small_tiff(Endian::Big, true);
+        assert_eq!(&file[8..12], &[0; 4]);
+        let (info, ifds) = read_tiff(&file).unwrap();
+        assert_eq!(info.first_ifd as u64, order_read(Endian::Big, &file[8..16], 8));
+        assert_eq!(ifds.len(), 1);
+
+        // setting the high half of a little endian offset moves it past the end of the file
+        let mut file = small_tiff(Endian::Little, true);
+        file[12] = 1;
+        assert!(read_tiff(&file).is_err());
+    }
+
+    #[test]
+    fn big_tiff_header_truncated() {
+        assert!(read_tiff(b"II\x2b\x00\x08\x00\x00\x00").is_err());
+        assert!(read_tiff(b"II\x2b\x00\x08\x00\x00\x00\x10\x00\x00\x00").is_err());
+        assert!(read_tiff(b"MM\x00\x2b\x00\x04\x00\x00\x00\x00\x00\x00\x00\x00\x00\x10").is_err());
+    }
+
+    #[test]
+    fn big_tiff_from_version() {
+        for endian in [Endian::Little, Endian::Big].iter() {
+            for big_tiff in [false, true].iter() {
+                let (info, ifds) = read_tiff(&small_tiff(*endian, *big_tiff)).unwrap();
+                assert_eq!(info.big_tiff, *big_tiff);
+                assert_eq!(ifds[0].tag_u32s(256), Some(vec![2]));
+            }
+        }
+    }
+
+    #[test]
+    fn truncated_files_are_errors() {
+        for endian in [Endian::Little, Endian::Big].iter() {
+            for big_tiff in [false, true].iter() {
+                let file = small_tiff(*endian, *big_tiff);
+                assert!(read_tiff(&file).is_ok());
+                // the ifd is written last so every truncation cuts into it or the data it points to
+                for length in 0..file.len() {
+                    assert!(read_tiff(&file[..length]).is_err(), "{} byte truncation was read", length);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn read_slice_bounds() {
+        let file = [1, 2, 3, 4];
+        assert_eq!(read_slice(&file, 1, 3).unwrap(), &[2, 3, 4]);
+        assert!(read_slice(&file, 2, 3).is_err());
+        assert!(read_slice(&file, usize::MAX, 2).is_err());
+    }
+
+    /// Classic ifd with a single StripOffsets tag (273) whose 4 values are stored past the end of the file
+    fn bad_strip_offsets() -> Vec<u8> {
+        b"\x01\x00\x11\x01\x04\x00\x04\x00\x00\x00\x00\x10\x00\x00\x00\x00\x00\x00".to_vec()
+    }
+
+    #[test]
+    fn parse_error_names_the_ifd_and_tag() {
+        let mut file = b"II\x2a\x00\x08\x00\x00\x00".to_vec();
+        file.extend(bad_strip_offsets());
+        let error = read_tiff(&file).unwrap_err();
+        assert!(matches!(error, SkoraError::ParseAt { ifd_offset: 8, tag: Some(273), .. }));
+        assert!(error.to_string().starts_with("Tag 273 in ifd at offset 0x8: "));
+    }
+
+    #[test]
+    fn parse_error_names_the_sub_ifd() {
+        // ifd at 8 with a SubIFDs tag (330) pointing at a bad ifd at 26
+        let mut file = b"II\x2a\x00\x08\x00\x00\x00\x01\x00\x4a\x01\x04\x00\x01\x00\x00\x00\x1a\x00\x00\x00\x00\x00\x00\x00".to_vec();
+        file.extend(bad_strip_offsets());
+        let error = read_tiff(&file).unwrap_err();
+        assert!(matches!(error, SkoraError::ParseAt { ifd_offset: 26, tag: Some(273), .. }));
+        assert!(error.to_string().starts_with("Tag 273 in ifd at offset 0x1A: "));
+    }
+
+    #[test]
+    fn alias_marker_variants() {
+        assert!(is_alias_marker("Alias MultiLayer TIFF V1.1\u{0}"));
+        assert!(is_alias_marker("Alias MultiLayer TIFF V1.1"));
+        assert!(is_alias_marker("Alias MultiLayer TIFF V1.0\u{0}"));
+        assert!(is_alias_marker("Alias MultiLayer TIFF V1.0"));
+        assert!(is_alias_marker("Alias  MultiLayer\tTIFF V1.1"));
+        assert!(is_alias_marker("Alias MultiLayer TIFF V2.0\u{0}\u{0}"));
+        for marker in ALIAS_MARKERS {
+            assert!(is_alias_marker(marker));
+        }
+    }
+
+    #[test]
+    fn not_alias_markers() {
+        assert!(!is_alias_marker(""));
+        assert!(!is_alias_marker("Adobe Photoshop"));
+        assert!(!is_alias_marker("Alias MultiLayer"));
+        assert!(!is_alias_marker(SOFTWARE));
+    }
 }
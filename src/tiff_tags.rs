@@ -0,0 +1,144 @@
+//! Tiff Tag Names
+//!
+//! Human readable names for the numeric tags used in tiff files, covering the
+//! tags defined in the tiff spec along with the private tags Sketchbook uses.
+//! <https://www.awaresystems.be/imaging/tiff/tifftags.html>
+//!
+
+use alloc::vec::Vec;
+
+/// Every tag this library knows about along with its name, sorted by tag value
+const TAGS: &[(u64, &str)] = &[
+    (254, "NewSubfileType"),
+    (255, "SubfileType"),
+    (256, "ImageWidth"),
+    (257, "ImageLength"),
+    (258, "BitsPerSample"),
+    (259, "Compression"),
+    (262, "PhotometricInterpretation"),
+    (263, "Threshholding"),
+    (264, "CellWidth"),
+    (265, "CellLength"),
+    (266, "FillOrder"),
+    (269, "DocumentName"),
+    (270, "ImageDescription"),
+    (271, "Make"),
+    (272, "Model"),
+    (273, "StripOffsets"),
+    (274, "Orientation"),
+    (277, "SamplesPerPixel"),
+    (278, "RowsPerStrip"),
+    (279, "StripByteCounts"),
+    (280, "MinSampleValue"),
+    (281, "MaxSampleValue"),
+    (282, "XResolution"),
+    (283, "YResolution"),
+    (284, "PlanarConfiguration"),
+    (285, "PageName"),
+    (286, "XPosition"),
+    (287, "YPosition"),
+    (288, "FreeOffsets"),
+    (289, "FreeByteCounts"),
+    (290, "GrayResponseUnit"),
+    (291, "GrayResponseCurve"),
+    (292, "T4Options"),
+    (293, "T6Options"),
+    (296, "ResolutionUnit"),
+    (297, "PageNumber"),
+    (301, "TransferFunction"),
+    (305, "Software"),
+    (306, "DateTime"),
+    (315, "Artist"),
+    (316, "HostComputer"),
+    (317, "Predictor"),
+    (318, "WhitePoint"),
+    (319, "PrimaryChromaticities"),
+    (320, "ColorMap"),
+    (321, "HalftoneHints"),
+    (322, "TileWidth"),
+    (323, "TileLength"),
+    (324, "TileOffsets"),
+    (325, "TileByteCounts"),
+    (330, "SubIFDs"),
+    (332, "InkSet"),
+    (333, "InkNames"),
+    (334, "NumberOfInks"),
+    (336, "DotRange"),
+    (337, "TargetPrinter"),
+    (338, "ExtraSamples"),
+    (339, "SampleFormat"),
+    (340, "SMinSampleValue"),
+    (341, "SMaxSampleValue"),
+    (342, "TransferRange"),
+    (512, "JPEGProc"),
+    (513, "JPEGInterchangeFormat"),
+    (514, "JPEGInterchangeFormatLength"),
+    (515, "JPEGRestartInterval"),
+    (517, "JPEGLosslessPredictors"),
+    (518, "JPEGPointTransforms"),
+    (519, "JPEGQTables"),
+    (520, "JPEGDCTables"),
+    (521, "JPEGACTables"),
+    (529, "YCbCrCoefficients"),
+    (530, "YCbCrSubSampling"),
+    (531, "YCbCrPositioning"),
+    (532, "ReferenceBlackWhite"),
+    (700, "XMP"),
+    (33432, "Copyright"),
+    (33723, "IPTC"),
+    (34377, "Photoshop"),
+    (34665, "ExifIFD"),
+    (34675, "ICCProfile"),
+    // Sketchbook private tag
+    (50784, "Alias Layer Metadata"),
+];
+
+/// Get the name of a tag from its integer value
+///
+/// # Arguments
+///
+/// * `id` - Integer tag value
+///
+/// # Returns
+///
+/// * Name of the tag or None if the tag is not known
+///
+/// # Example
+/// ```rust
+/// assert_eq!(tag_name(305), Some("Software"));
+/// ```
+pub fn tag_name(id: u64) -> Option<&'static str> {
+    TAGS.binary_search_by_key(&id, |(tag, _)| *tag)
+        .ok()
+        .map(|idx| TAGS[idx].1)
+}
+
+/// Get the list of tags this library knows about
+///
+/// These are the tags defined in the tiff spec along with the private tags Sketchbook uses.
+/// Add new tags to the table in this module so they are both named and known.
+///
+/// # Returns
+///
+/// * The known integer tag values in ascending order
+///
+/// # Example
+/// ```rust
+/// assert!(known_tags().contains(&50784));
+/// ```
+pub fn known_tags() -> Vec<u64> {
+    TAGS.iter().map(|(tag, _)| *tag).collect()
+}
+
+/// Check if a tag is one this library knows about
+///
+/// # Arguments
+///
+/// * `id` - Integer tag value
+///
+/// # Returns
+///
+/// * True if the tag is known
+pub fn is_known_tag(id: u64) -> bool {
+    tag_name(id).is_some()
+}
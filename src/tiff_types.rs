@@ -3,9 +3,14 @@
 //! Object types and functions used to hold the data from a tiff file.
 //!
 
-use std::collections::BTreeMap;
+use alloc::collections::BTreeMap;
+use alloc::format;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use core::convert::TryFrom;
 
-use crate::endian_rw::{order_read, order_write_16, order_write_32, order_write_8, Endian};
+use crate::tiff_tags::tag_name;
+use crate::endian_rw::{order_read, order_write_16, order_write_32, order_write_64, order_write_8, Endian};
 
 /// Top level meta data about the Sketchbook tiff image file.
 #[derive(Clone, Debug)]
@@ -20,6 +25,24 @@ pub struct Info {
     pub header: Vec<u8>,
     /// The total length of the tiff file in bytes
     pub size: usize,
+    /// The number of IFDs (including sub-IFDs) read from the file
+    pub ifd_count: usize,
+}
+
+impl Info {
+    /// One line summary of the file for logging
+    ///
+    /// # Example
+    /// ```rust
+    /// let (info, ifds) = read_tiff(&file)?;
+    /// debug!("{}", info.summary());
+    /// ```
+    pub fn summary(&self) -> String {
+        format!(
+            "{:?} endian, big tiff: {}, size: {} bytes, ifds: {}",
+            self.endian, self.big_tiff, self.size, self.ifd_count
+        )
+    }
 }
 
 /// Meta data about an individual ifd (image file directory)
@@ -39,6 +62,72 @@ pub struct Ifd {
     pub tags: BTreeMap<u64, Tag>,
 }
 
+impl Ifd {
+    /// Read the values of a numeric tag as a vector of u32
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - Integer tag value
+    ///
+    /// # Returns
+    ///
+    /// * The tag values or None if the tag is not present, is not an integer type, or has a value
+    ///   that does not fit in a u32
+    pub fn tag_u32s(&self, tag: u64) -> Option<Vec<u32>> {
+        match &self.tags.get(&tag)?.data {
+            Data::Byte(val) => Some(val.iter().map(|v| *v as u32).collect()),
+            Data::Short(val) => Some(val.iter().map(|v| *v as u32).collect()),
+            Data::Long(val) => Some(val.clone()),
+            Data::Long8(val) => val.iter().map(|v| u32::try_from(*v).ok()).collect(),
+            _ => None,
+        }
+    }
+
+    /// Read the values of a numeric tag as a vector of u64
+    ///
+    /// # Arguments
+    ///
+    /// * `tag` - Integer tag value
+    ///
+    /// # Returns
+    ///
+    /// * The tag values or None if the tag is not present or is not an integer type
+    pub fn tag_u64s(&self, tag: u64) -> Option<Vec<u64>> {
+        match &self.tags.get(&tag)?.data {
+            Data::Byte(val) => Some(val.iter().map(|v| *v as u64).collect()),
+            Data::Short(val) => Some(val.iter().map(|v| *v as u64).collect()),
+            Data::Long(val) => Some(val.iter().map(|v| *v as u64).collect()),
+            Data::Long8(val) => Some(val.clone()),
+            _ => None,
+        }
+    }
+
+    /// One line summary of the ifd for logging
+    ///
+    /// # Example
+    /// ```rust
+    /// for ifd in &ifds {
+    ///     debug!("{}", ifd.summary());
+    /// }
+    /// ```
+    pub fn summary(&self) -> String {
+        let tags: Vec<String> = self
+            .tags
+            .keys()
+            .map(|tag| match tag_name(*tag) {
+                Some(name) => name.to_string(),
+                None => tag.to_string(),
+            })
+            .collect();
+        format!(
+            "ifd at offset {}, {} tags: {}",
+            self.offset,
+            self.tag_count,
+            tags.join(", ")
+        )
+    }
+}
+
 /// Piece of information / data about the ifd.
 ///
 /// Data in tiff files is organized by tags.  There are a bunch of tags defined in the Tiff standard to encode various bits of info about the image.
@@ -68,18 +157,41 @@ pub enum DataType {
     Short,
     Long,
     Rational,
+    /// Offset to an IFD.  Stored the same as a Long.
+    Ifd,
+    /// Bytes that can contain anything depending on the tag.  Stored the same as a Byte.
+    Undefined,
+    /// 64 bit unsigned integer.  Only valid in BigTiff files.
+    Long8,
+    /// Offset to an IFD in a BigTiff file.  Stored the same as a Long8.
+    Ifd8,
 }
 
 impl From<u16> for DataType {
-    /// Return a datatype from an integer per the mapping in the tiff spec
+    /// Return a datatype from an integer per the mapping in the tiff spec.  Panics if the type is not supported.
     fn from(n: u16) -> DataType {
+        DataType::from_tiff_id(n).expect("unsupported tiff data type")
+    }
+}
+
+impl DataType {
+    /// Return a datatype from an integer per the mapping in the tiff spec
+    ///
+    /// # Returns
+    ///
+    /// * The datatype or None if the type is not supported
+    pub fn from_tiff_id(n: u16) -> Option<DataType> {
         match n {
-            1 => DataType::Byte,
-            2 => DataType::Ascii,
-            3 => DataType::Short,
-            4 => DataType::Long,
-            5 => DataType::Rational,
-            _ => panic!(),
+            1 => Some(DataType::Byte),
+            2 => Some(DataType::Ascii),
+            3 => Some(DataType::Short),
+            4 => Some(DataType::Long),
+            5 => Some(DataType::Rational),
+            7 => Some(DataType::Undefined),
+            13 => Some(DataType::Ifd),
+            16 => Some(DataType::Long8),
+            18 => Some(DataType::Ifd8),
+            _ => None,
         }
     }
 }
@@ -93,6 +205,10 @@ impl DataType {
             DataType::Short => 2,
             DataType::Long => 4,
             DataType::Rational => 8,
+            DataType::Ifd => 4,
+            DataType::Undefined => 1,
+            DataType::Long8 => 8,
+            DataType::Ifd8 => 8,
         }
     }
 }
@@ -106,6 +222,10 @@ impl DataType {
             DataType::Short => 3,
             DataType::Long => 4,
             DataType::Rational => 5,
+            DataType::Ifd => 13,
+            DataType::Undefined => 7,
+            DataType::Long8 => 16,
+            DataType::Ifd8 => 18,
         }
     }
 }
@@ -115,12 +235,16 @@ impl DataType {
 pub enum Data {
     /// Bytes are u8
     Byte(Vec<u8>),
-    /// Ascii data is converted to string
+    /// Ascii data is converted to string.  Tiff ascii is 8 bit rather than UTF-8 so each byte is decoded
+    /// as a Latin-1 character, which never fails or shifts the positions of characters and writes back
+    /// out as the same bytes.
     Ascii(String),
     /// Shorts are u16
     Short(Vec<u16>),
     /// Longs are u32
     Long(Vec<u32>),
+    /// Long8s are u64
+    Long8(Vec<u64>),
     /// Rational numbers are stored as two u32 in the raw data.  Here the f64 (rational) value is stored along with the two u32's (numerator then denominator) that define the rational.
     Rational(Vec<(f64, u32, u32)>),
 }
@@ -129,7 +253,7 @@ impl Data {
     /// Create a new Data object of the specified DataType
     pub fn new(raw_data: Vec<u8>, data_type: DataType, endian: Endian, count: u64) -> Self {
         match data_type {
-            DataType::Byte => {
+            DataType::Byte | DataType::Undefined => {
                 let mut tag_data = Vec::new();
                 for n in 0..(count) {
                     let start = (n) as usize;
@@ -139,8 +263,8 @@ impl Data {
                 Data::Byte(tag_data)
             }
             DataType::Ascii => {
-                let tag_data = String::from_utf8_lossy(&raw_data);
-                Data::Ascii(tag_data.to_string())
+                let tag_data: String = raw_data.iter().map(|byte| *byte as char).collect();
+                Data::Ascii(tag_data)
             }
             DataType::Short => {
                 let mut tag_data = Vec::new();
@@ -151,7 +275,7 @@ impl Data {
                 }
                 Data::Short(tag_data)
             }
-            DataType::Long => {
+            DataType::Long | DataType::Ifd => {
                 let mut tag_data = Vec::new();
                 for n in 0..(count) {
                     let start = (n * 4) as usize;
@@ -160,6 +284,15 @@ impl Data {
                 }
                 Data::Long(tag_data)
             }
+            DataType::Long8 | DataType::Ifd8 => {
+                let mut tag_data = Vec::new();
+                for n in 0..(count) {
+                    let start = (n * 8) as usize;
+                    let end = (n * 8 + 8) as usize;
+                    tag_data.push(order_read(endian, &raw_data[start..end], 8));
+                }
+                Data::Long8(tag_data)
+            }
             DataType::Rational => {
                 let mut tag_data_raw = Vec::new();
                 for n in 0..(count * 2) {
@@ -185,6 +318,97 @@ impl Data {
 }
 
 impl Data {
+    /// Get the values of Long data
+    ///
+    /// # Returns
+    ///
+    /// * The values or None if the data is not Long
+    ///
+    /// # Example
+    /// ```rust
+    /// let offsets = ifd.tags[&273].data.as_u32_slice();
+    /// ```
+    pub fn as_u32_slice(&self) -> Option<&[u32]> {
+        match self {
+            Data::Long(val) => Some(val),
+            _ => None,
+        }
+    }
+
+    /// Get the first value of numeric data as a float
+    ///
+    /// # Returns
+    ///
+    /// * The first value or None if the data is empty or is ascii
+    ///
+    /// # Example
+    /// ```rust
+    /// let x_pos = ifd.tags[&286].data.as_f64().unwrap_or(0.0);
+    /// ```
+    pub fn as_f64(&self) -> Option<f64> {
+        match self {
+            Data::Byte(val) => val.first().map(|v| *v as f64),
+            Data::Ascii(_) => None,
+            Data::Short(val) => val.first().map(|v| *v as f64),
+            Data::Long(val) => val.first().map(|v| *v as f64),
+            Data::Long8(val) => val.first().map(|v| *v as f64),
+            Data::Rational(val) => val.first().map(|v| v.0),
+        }
+    }
+
+    /// Get the text of Ascii data without the terminating null
+    ///
+    /// # Returns
+    ///
+    /// * The text or None if the data is not Ascii
+    ///
+    /// # Example
+    /// ```rust
+    /// let software = ifd.tags[&305].data.as_string();
+    /// ```
+    pub fn as_string(&self) -> Option<&str> {
+        match self {
+            Data::Ascii(val) => Some(val.trim_end_matches('\u{0}')),
+            _ => None,
+        }
+    }
+
+    /// Get the first value of Rational data
+    ///
+    /// # Returns
+    ///
+    /// * The value, numerator, and denominator or None if the data is empty or not Rational
+    ///
+    /// # Example
+    /// ```rust
+    /// let (value, numerator, denominator) = ifd.tags[&282].data.first_rational()?;
+    /// ```
+    pub fn first_rational(&self) -> Option<(f64, u32, u32)> {
+        match self {
+            Data::Rational(val) => val.first().copied(),
+            _ => None,
+        }
+    }
+}
+
+impl Data {
+    /// Get the tiff data type used to write the data
+    ///
+    /// # Example
+    /// ```rust
+    /// assert_eq!(Data::Short(vec![8, 8, 8, 8]).datatype(), DataType::Short);
+    /// ```
+    pub fn datatype(&self) -> DataType {
+        match self {
+            Data::Byte(_) => DataType::Byte,
+            Data::Ascii(_) => DataType::Ascii,
+            Data::Short(_) => DataType::Short,
+            Data::Long(_) => DataType::Long,
+            Data::Long8(_) => DataType::Long8,
+            Data::Rational(_) => DataType::Rational,
+        }
+    }
+
     /// Convert the values in the data to a vector of u8 bytes
     pub fn to_vec_u8(&self, endian: Endian) -> Vec<u8> {
         match self {
@@ -195,7 +419,7 @@ impl Data {
                 }
                 buf
             }
-            Data::Ascii(val) => val.clone().into_bytes(),
+            Data::Ascii(val) => val.chars().map(|character| character as u32 as u8).collect(),
             Data::Short(val) => {
                 let mut buf: Vec<u8> = Vec::new();
                 for number in val {
@@ -210,6 +434,13 @@ impl Data {
                 }
                 buf
             }
+            Data::Long8(val) => {
+                let mut buf: Vec<u8> = Vec::new();
+                for number in val {
+                    order_write_64(endian, &mut buf, *number);
+                }
+                buf
+            }
             Data::Rational(val) => {
                 let mut buf: Vec<u8> = Vec::new();
                 for number in val {
@@ -221,3 +452,98 @@ impl Data {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_tiff::ifd;
+    use alloc::vec;
+
+    /// One value of every variant of tag data
+    fn every_variant() -> Vec<Data> {
+        vec![
+            Data::Byte(vec![7]),
+            Data::Ascii("Skora\u{0}".to_string()),
+            Data::Short(vec![300]),
+            Data::Long(vec![70_000, 1]),
+            Data::Long8(vec![5_000_000_000]),
+            Data::Rational(vec![(0.5, 1, 2)]),
+        ]
+    }
+
+    #[test]
+    fn as_u32_slice_of_each_variant() {
+        for data in every_variant() {
+            match &data {
+                Data::Long(_) => assert_eq!(data.as_u32_slice(), Some(&[70_000, 1][..])),
+                _ => assert_eq!(data.as_u32_slice(), None),
+            }
+        }
+    }
+
+    #[test]
+    fn as_f64_of_each_variant() {
+        let values: Vec<Option<f64>> = every_variant().iter().map(Data::as_f64).collect();
+        assert_eq!(
+            values,
+            vec![Some(7.0), None, Some(300.0), Some(70_000.0), Some(5_000_000_000.0), Some(0.5)]
+        );
+        assert_eq!(Data::Short(Vec::new()).as_f64(), None);
+    }
+
+    #[test]
+    fn as_string_of_each_variant() {
+        let values: Vec<Option<String>> = every_variant()
+            .iter()
+            .map(|data| data.as_string().map(|val| val.to_string()))
+            .collect();
+        assert_eq!(values, vec![None, Some("Skora".to_string()), None, None, None, None]);
+    }
+
+    #[test]
+    fn first_rational_of_each_variant() {
+        let values: Vec<Option<(f64, u32, u32)>> = every_variant().iter().map(Data::first_rational).collect();
+        assert_eq!(values, vec![None, None, None, None, None, Some((0.5, 1, 2))]);
+    }
+
+    #[test]
+    fn tag_integers_of_each_variant() {
+        let tags: Vec<(u64, Data)> = every_variant().into_iter().enumerate().map(|(idx, data)| (idx as u64, data)).collect();
+        let image = ifd(tags);
+        let u32s: Vec<Option<Vec<u32>>> = (0..7).map(|tag| image.tag_u32s(tag)).collect();
+        assert_eq!(
+            u32s,
+            vec![Some(vec![7]), None, Some(vec![300]), Some(vec![70_000, 1]), None, None, None]
+        );
+        let u64s: Vec<Option<Vec<u64>>> = (0..7).map(|tag| image.tag_u64s(tag)).collect();
+        assert_eq!(
+            u64s,
+            vec![
+                Some(vec![7]),
+                None,
+                Some(vec![300]),
+                Some(vec![70_000, 1]),
+                Some(vec![5_000_000_000]),
+                None,
+                None
+            ]
+        );
+    }
+
+    #[test]
+    fn rational_with_count_0() {
+        let data = Data::new(Vec::new(), DataType::Rational, Endian::Little, 0);
+        assert!(matches!(&data, Data::Rational(val) if val.is_empty()));
+        assert_eq!(data.first_rational(), None);
+        assert_eq!(data.as_f64(), None);
+        assert!(data.to_vec_u8(Endian::Little).is_empty());
+    }
+
+    #[test]
+    fn get_tag_of_missing_ifd_or_tag() {
+        let ifds = vec![ifd(vec![(305, Data::Ascii("Skora".to_string()))])];
+        assert_eq!(crate::tiff::get_tag(&ifds, 0, 305).and_then(Data::as_string), Some("Skora"));
+        assert!(crate::tiff::get_tag(&ifds, 0, 306).is_none());
+        assert!(crate::tiff::get_tag(&ifds, 1, 305).is_none());
+    }
+}
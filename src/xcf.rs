@@ -0,0 +1,237 @@
+//! GIMP XCF File Format
+//!
+//! Functions to write an open raster image as a GIMP XCF file so it can be opened in GIMP without
+//! going through ORA.  Only what is needed to hold the layers is written: an RGB image with one
+//! RGBA layer per ORA layer (groups are flattened into the list of layers), stored as uncompressed
+//! 64x64 tiles.  The format is described in the GIMP source tree (`devel-docs/xcf.txt`).
+//!
+
+use std::convert::TryFrom;
+use std::path::Path;
+
+use crate::endian_rw::{order_write, Endian};
+use crate::error::SkoraError;
+use crate::ora::{Layer, Ora};
+
+/// File signature and version.  Version 3 is the oldest version that GIMP 2.8 and later write.
+const SIGNATURE: &[u8] = b"gimp xcf v003\0";
+/// Width and height (in pixels) of a tile
+const TILE_SIZE: u32 = 64;
+
+/// XCF property types used by this writer
+const PROP_END: u32 = 0;
+const PROP_OPACITY: u32 = 6;
+const PROP_VISIBLE: u32 = 8;
+const PROP_OFFSETS: u32 = 15;
+const PROP_COMPRESSION: u32 = 17;
+
+/// Image base type for RGB images
+const BASE_TYPE_RGB: u32 = 0;
+/// Layer type for RGB images with an alpha channel
+const LAYER_TYPE_RGBA: u32 = 1;
+/// Compression property value for uncompressed tiles
+const COMPRESSION_NONE: u8 = 0;
+
+impl Ora {
+    /// Write the image to a GIMP XCF file
+    ///
+    /// # Arguments
+    ///
+    /// `path` - Path of the file to write
+    ///
+    /// # Example
+    /// ```rust
+    /// let ora = skora::tiff_to_ora(&file, skora::ConvertOptions::default())?;
+    /// ora.write_xcf(Path::new("test.xcf"))?;
+    /// ```
+    pub fn write_xcf(&self, path: &Path) -> Result<(), SkoraError> {
+        std::fs::write(path, self.to_xcf()?)?;
+        Ok(())
+    }
+
+    /// Write the image to a GIMP XCF file in memory
+    ///
+    /// # Returns
+    ///
+    /// * The bytes of the xcf file
+    ///
+    /// # Example
+    /// ```rust
+    /// let bytes = ora.to_xcf()?;
+    /// ```
+    pub fn to_xcf(&self) -> Result<Vec<u8>, SkoraError> {
+        let mut buf: Vec<u8> = Vec::new();
+        buf.extend_from_slice(SIGNATURE);
+        write_u32(&mut buf, self.width);
+        write_u32(&mut buf, self.height);
+        write_u32(&mut buf, BASE_TYPE_RGB);
+
+        write_property(&mut buf, PROP_COMPRESSION, &[COMPRESSION_NONE]);
+        write_property(&mut buf, PROP_END, &[]);
+
+        // layer pointers (top most first) followed by a zero, then an empty list of channel pointers
+        let layers = self.flat_layers();
+        let pointer_table = buf.len();
+        for _ in 0..(layers.len() + 2) {
+            write_u32(&mut buf, 0);
+        }
+
+        for (idx, layer) in layers.iter().enumerate() {
+            let pointer = offset_u32(buf.len())?;
+            patch_u32(&mut buf, pointer_table + idx * 4, pointer);
+            self.write_layer(&mut buf, layer)?;
+        }
+        Ok(buf)
+    }
+
+    /// Write a single layer along with its pixel data
+    fn write_layer(&self, buf: &mut Vec<u8>, layer: &Layer) -> Result<(), SkoraError> {
+        let pixels = layer.decoded()?;
+        let (width, height) = pixels.dimensions();
+
+        write_u32(buf, width);
+        write_u32(buf, height);
+        write_u32(buf, LAYER_TYPE_RGBA);
+        write_string(buf, &layer.display_name());
+
+        let opacity = (layer.opacity.clamp(0.0, 1.0) * 255.0).round() as u32;
+        write_property(buf, PROP_OPACITY, &opacity.to_be_bytes());
+        write_property(buf, PROP_VISIBLE, &(layer.visible as u32).to_be_bytes());
+        // ora y positions are measured from the bottom, xcf offsets from the top
+        let top = self.height as i64 - layer.y_pos as i64 - layer.height as i64;
+        let mut offsets: Vec<u8> = Vec::new();
+        order_write(Endian::Big, &mut offsets, layer.x_pos as i32);
+        order_write(Endian::Big, &mut offsets, top as i32);
+        write_property(buf, PROP_OFFSETS, &offsets);
+        write_property(buf, PROP_END, &[]);
+
+        // hierarchy pointer then layer mask pointer (no mask)
+        let hierarchy_pointer = buf.len();
+        write_u32(buf, 0);
+        write_u32(buf, 0);
+
+        // hierarchy: size, bytes per pixel, then a single level followed by a zero
+        let hierarchy = offset_u32(buf.len())?;
+        patch_u32(buf, hierarchy_pointer, hierarchy);
+        write_u32(buf, width);
+        write_u32(buf, height);
+        write_u32(buf, 4);
+        let level_pointer = buf.len();
+        write_u32(buf, 0);
+        write_u32(buf, 0);
+
+        // level: size then one pointer per tile (left to right, top to bottom) followed by a zero
+        let level = offset_u32(buf.len())?;
+        patch_u32(buf, level_pointer, level);
+        write_u32(buf, width);
+        write_u32(buf, height);
+        let tiles_x = (width + TILE_SIZE - 1) / TILE_SIZE;
+        let tiles_y = (height + TILE_SIZE - 1) / TILE_SIZE;
+        let tile_table = buf.len();
+        for _ in 0..(tiles_x * tiles_y + 1) {
+            write_u32(buf, 0);
+        }
+
+        // uncompressed tiles hold the pixels of the tile row by row with the channels interleaved
+        for tile_y in 0..tiles_y {
+            for tile_x in 0..tiles_x {
+                let tile = offset_u32(buf.len())?;
+                patch_u32(buf, tile_table + ((tile_y * tiles_x + tile_x) * 4) as usize, tile);
+                let right = ((tile_x + 1) * TILE_SIZE).min(width);
+                let bottom = ((tile_y + 1) * TILE_SIZE).min(height);
+                for y in (tile_y * TILE_SIZE)..bottom {
+                    for x in (tile_x * TILE_SIZE)..right {
+                        buf.extend_from_slice(&pixels.get_pixel(x, y).0);
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Write a big endian u32
+fn write_u32(buf: &mut Vec<u8>, value: u32) {
+    order_write(Endian::Big, buf, value);
+}
+
+/// Overwrite a big endian u32 written earlier (used to fill in pointers)
+fn patch_u32(buf: &mut [u8], position: usize, value: u32) {
+    buf[position..position + 4].copy_from_slice(&value.to_be_bytes());
+}
+
+/// Convert a position in the file to a pointer, which is limited to 4GB in this version of the format
+fn offset_u32(position: usize) -> Result<u32, SkoraError> {
+    u32::try_from(position).map_err(|_| SkoraError::TooLarge("Image is too large for an xcf file (4GB)".to_string()))
+}
+
+/// Write a property as its type, the length of its payload, and the payload
+fn write_property(buf: &mut Vec<u8>, property: u32, payload: &[u8]) {
+    write_u32(buf, property);
+    write_u32(buf, payload.len() as u32);
+    buf.extend_from_slice(payload);
+}
+
+/// Write a string as its length (including the terminating null), the UTF-8 bytes, and a null
+fn write_string(buf: &mut Vec<u8>, text: &str) {
+    write_u32(buf, text.len() as u32 + 1);
+    buf.extend_from_slice(text.as_bytes());
+    buf.push(0);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::convert::{image_to_buf, PngOptions};
+    use image::RgbaImage;
+
+    /// Read the big endian u32 at a position
+    fn read_u32(buf: &[u8], position: usize) -> u32 {
+        u32::from_be_bytes([buf[position], buf[position + 1], buf[position + 2], buf[position + 3]])
+    }
+
+    #[test]
+    fn header_and_layer_pointers() {
+        let mut ora = Ora::new();
+        ora.set_canvas_size(4, 3);
+        for (idx, (width, height)) in [(4, 3), (2, 1)].iter().enumerate() {
+            let png = image_to_buf(RgbaImage::new(*width, *height), PngOptions::default()).unwrap();
+            ora.add_layer(Layer::from_png_bytes(idx as u8, png, 1.0, 0, 0).unwrap());
+        }
+        let xcf = ora.to_xcf().unwrap();
+
+        assert!(xcf.starts_with(SIGNATURE));
+        let mut position = SIGNATURE.len();
+        assert_eq!(read_u32(&xcf, position), 4);
+        assert_eq!(read_u32(&xcf, position + 4), 3);
+        assert_eq!(read_u32(&xcf, position + 8), BASE_TYPE_RGB);
+        position += 12;
+
+        // properties run until PROP_END
+        loop {
+            let property = read_u32(&xcf, position);
+            position += 8 + read_u32(&xcf, position + 4) as usize;
+            if property == PROP_END {
+                break;
+            }
+        }
+
+        // two layer pointers, the zero ending them, and the zero ending the (empty) channel pointers
+        let pointers: Vec<usize> = (0..4).map(|idx| read_u32(&xcf, position + idx * 4) as usize).collect();
+        assert_eq!(&pointers[2..], &[0, 0]);
+        let expected = [(4, 3, "Paint Layer 0"), (2, 1, "Paint Layer 1")];
+        for (pointer, (width, height, name)) in pointers.iter().zip(expected.iter()) {
+            assert_eq!(read_u32(&xcf, *pointer), *width);
+            assert_eq!(read_u32(&xcf, pointer + 4), *height);
+            assert_eq!(read_u32(&xcf, pointer + 8), LAYER_TYPE_RGBA);
+            let name_length = read_u32(&xcf, pointer + 12) as usize;
+            assert_eq!(&xcf[pointer + 16..pointer + 16 + name_length], format!("{}\0", name).as_bytes());
+        }
+    }
+
+    #[test]
+    fn offset_past_4gb_is_too_large() {
+        assert_eq!(offset_u32(u32::MAX as usize).unwrap(), u32::MAX);
+        assert!(matches!(offset_u32(u32::MAX as usize + 1), Err(SkoraError::TooLarge(_))));
+    }
+}
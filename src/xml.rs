@@ -0,0 +1,350 @@
+//! XML Builder
+//!
+//! Minimal builder used to generate the `stack.xml` of an open raster image.  Attributes are
+//! escaped as they are written so callers can pass any text.  A matching minimal parser reads a
+//! `stack.xml` back into elements so ora files can be checked.
+//!
+
+use std::fmt;
+
+/// Single xml element with its attributes and child elements
+#[derive(Clone, Debug, PartialEq)]
+pub struct XmlElement {
+    /// Tag name of the element
+    pub name: String,
+    /// Attribute names and (unescaped) values in the order they are written
+    pub attributes: Vec<(String, String)>,
+    /// Child elements in the order they are written
+    pub children: Vec<XmlElement>,
+}
+
+impl XmlElement {
+    /// Create a new element without any attributes or children
+    ///
+    /// # Example
+    /// ```rust
+    /// let stack = XmlElement::new("stack");
+    /// ```
+    pub fn new(name: &str) -> XmlElement {
+        XmlElement {
+            name: name.to_string(),
+            attributes: Vec::new(),
+            children: Vec::new(),
+        }
+    }
+
+    /// Get the (unescaped) value of an attribute
+    ///
+    /// # Example
+    /// ```rust
+    /// let layer = XmlElement::new("layer").attr("x", 10);
+    /// assert_eq!(layer.get_attr("x"), Some("10"));
+    /// ```
+    pub fn get_attr(&self, name: &str) -> Option<&str> {
+        self.attributes
+            .iter()
+            .find(|(attribute, _)| attribute == name)
+            .map(|(_, value)| value.as_str())
+    }
+
+    /// Add an attribute to the element
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the attribute
+    /// * `value` - Value of the attribute.  This is escaped when the element is written.
+    ///
+    /// # Example
+    /// ```rust
+    /// let layer = XmlElement::new("layer").attr("x", 10).attr("name", "Ink & Paint");
+    /// ```
+    pub fn attr<T: ToString>(mut self, name: &str, value: T) -> XmlElement {
+        self.attributes.push((name.to_string(), value.to_string()));
+        self
+    }
+
+    /// Add an attribute to the element only if it has a value
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the attribute
+    /// * `value` - Value of the attribute or None to leave the attribute out
+    ///
+    /// # Example
+    /// ```rust
+    /// let stack = XmlElement::new("stack").attr_opt("name", ora.document_name.as_ref());
+    /// ```
+    pub fn attr_opt<T: ToString>(self, name: &str, value: Option<T>) -> XmlElement {
+        match value {
+            Some(value) => self.attr(name, value),
+            None => self,
+        }
+    }
+
+    /// Add a child element to the end of the element's children
+    ///
+    /// # Arguments
+    ///
+    /// * `child` - Element to add
+    pub fn push(&mut self, child: XmlElement) {
+        self.children.push(child);
+    }
+
+    /// Write the element and its children, indenting each level by two spaces
+    fn write(&self, f: &mut fmt::Formatter<'_>, depth: usize) -> fmt::Result {
+        let indent = "  ".repeat(depth);
+        write!(f, "{}<{}", indent, self.name)?;
+        for (name, value) in &self.attributes {
+            write!(f, " {}=\"{}\"", name, escape(value))?;
+        }
+        if self.children.is_empty() {
+            return writeln!(f, "/>");
+        }
+        writeln!(f, ">")?;
+        for child in &self.children {
+            child.write(f, depth + 1)?;
+        }
+        writeln!(f, "{}</{}>", indent, self.name)
+    }
+}
+
+impl fmt::Display for XmlElement {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.write(f, 0)
+    }
+}
+
+/// Write a complete xml document with the given root element
+///
+/// # Arguments
+///
+/// * `root` - Root element of the document
+///
+/// # Returns
+///
+/// * The document text including the xml declaration
+pub fn document(root: &XmlElement) -> String {
+    format!("<?xml version='1.0' encoding='UTF-8'?>\n{}", root)
+}
+
+/// Escape the characters that are not allowed in an xml attribute value
+///
+/// # Arguments
+///
+/// * `value` - Text to escape
+///
+/// # Returns
+///
+/// * The escaped text
+pub fn escape(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for character in value.chars() {
+        match character {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&apos;"),
+            _ => escaped.push(character),
+        }
+    }
+    escaped
+}
+
+/// Replace the entity and character references in an xml attribute value with the characters they stand for
+///
+/// # Arguments
+///
+/// * `value` - Escaped text
+///
+/// # Returns
+///
+/// * The unescaped text or an error if a reference is not recognized
+pub fn unescape(value: &str) -> Result<String, String> {
+    let mut unescaped = String::with_capacity(value.len());
+    let mut rest = value;
+    while let Some(start) = rest.find('&') {
+        unescaped.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find(';')
+            .ok_or_else(|| format!("Unterminated reference in {:?}", value))?;
+        let reference = &rest[start + 1..start + end];
+        let character = match reference {
+            "amp" => Some('&'),
+            "lt" => Some('<'),
+            "gt" => Some('>'),
+            "quot" => Some('"'),
+            "apos" => Some('\''),
+            _ => match (reference.strip_prefix("#x"), reference.strip_prefix('#')) {
+                (Some(hex), _) => u32::from_str_radix(hex, 16).ok().and_then(char::from_u32),
+                (None, Some(decimal)) => decimal.parse().ok().and_then(char::from_u32),
+                (None, None) => None,
+            },
+        };
+        unescaped.push(character.ok_or_else(|| format!("Unknown reference &{};", reference))?);
+        rest = &rest[start + end + 1..];
+    }
+    unescaped.push_str(rest);
+    Ok(unescaped)
+}
+
+/// Parse an xml document into its root element
+///
+/// Only what `stack.xml` uses is supported: elements and attributes along with the xml declaration,
+/// comments, and a doctype, which are skipped.  Text between elements is ignored.
+///
+/// # Arguments
+///
+/// * `text` - The xml document
+///
+/// # Returns
+///
+/// * The root element or an error describing where the document is not well formed
+///
+/// # Example
+/// ```rust
+/// let root = xml::parse("<image w='10' h='10'><stack/></image>")?;
+/// assert_eq!(root.children[0].name, "stack");
+/// ```
+pub fn parse(text: &str) -> Result<XmlElement, String> {
+    let mut parser = Parser { text, pos: 0 };
+    parser.skip_misc()?;
+    let root = parser.element()?;
+    parser.skip_misc()?;
+    if parser.pos != text.len() {
+        return Err(format!("Unexpected content after the root element at {}", parser.pos));
+    }
+    Ok(root)
+}
+
+/// Position in an xml document being parsed
+struct Parser<'a> {
+    text: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    /// Text that hasn't been parsed yet
+    fn rest(&self) -> &'a str {
+        &self.text[self.pos..]
+    }
+
+    /// Skip any whitespace
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Skip past the next occurrence of `end`
+    fn skip_past(&mut self, end: &str) -> Result<(), String> {
+        match self.rest().find(end) {
+            Some(idx) => {
+                self.pos += idx + end.len();
+                Ok(())
+            }
+            None => Err(format!("Missing {:?} after {}", end, self.pos)),
+        }
+    }
+
+    /// Skip whitespace, the xml declaration, comments, and doctypes
+    fn skip_misc(&mut self) -> Result<(), String> {
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("<?") {
+                self.skip_past("?>")?;
+            } else if self.rest().starts_with("<!--") {
+                self.skip_past("-->")?;
+            } else if self.rest().starts_with("<!") {
+                self.skip_past(">")?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Skip past `token`, which must be next
+    fn expect(&mut self, token: &str) -> Result<(), String> {
+        match self.rest().starts_with(token) {
+            true => {
+                self.pos += token.len();
+                Ok(())
+            }
+            false => Err(format!("Expected {:?} at {}", token, self.pos)),
+        }
+    }
+
+    /// Read an element or attribute name
+    fn name(&mut self) -> Result<&'a str, String> {
+        let rest = self.rest();
+        let end = rest
+            .find(|character: char| character.is_whitespace() || "/>=<\"'".contains(character))
+            .unwrap_or(rest.len());
+        if end == 0 {
+            return Err(format!("Expected a name at {}", self.pos));
+        }
+        self.pos += end;
+        Ok(&rest[..end])
+    }
+
+    /// Read a quoted attribute value
+    fn value(&mut self) -> Result<String, String> {
+        let quote = match self.rest().chars().next() {
+            Some(quote) if quote == '"' || quote == '\'' => quote,
+            _ => return Err(format!("Expected a quoted value at {}", self.pos)),
+        };
+        self.pos += 1;
+        let end = self
+            .rest()
+            .find(quote)
+            .ok_or_else(|| format!("Unterminated value at {}", self.pos))?;
+        let value = unescape(&self.rest()[..end])?;
+        self.pos += end + 1;
+        Ok(value)
+    }
+
+    /// Read an element along with its attributes and children
+    fn element(&mut self) -> Result<XmlElement, String> {
+        self.expect("<")?;
+        let mut element = XmlElement::new(self.name()?);
+        loop {
+            self.skip_whitespace();
+            if self.rest().starts_with("/>") {
+                self.pos += 2;
+                return Ok(element);
+            }
+            if self.rest().starts_with('>') {
+                self.pos += 1;
+                break;
+            }
+            let name = self.name()?;
+            self.skip_whitespace();
+            self.expect("=")?;
+            self.skip_whitespace();
+            let value = self.value()?;
+            element.attributes.push((name.to_string(), value));
+        }
+
+        loop {
+            // text content is skipped
+            match self.rest().find('<') {
+                Some(idx) => self.pos += idx,
+                None => return Err(format!("Missing </{}>", element.name)),
+            }
+            if self.rest().starts_with("</") {
+                self.pos += 2;
+                let name = self.name()?;
+                if name != element.name {
+                    return Err(format!("Expected </{}> but found </{}>", element.name, name));
+                }
+                self.skip_whitespace();
+                self.expect(">")?;
+                return Ok(element);
+            }
+            if self.rest().starts_with("<!--") {
+                self.skip_past("-->")?;
+                continue;
+            }
+            element.children.push(self.element()?);
+        }
+    }
+}
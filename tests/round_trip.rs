@@ -0,0 +1,180 @@
+//! Convert the sample Sketchbook tiffs in `resources` and check the structure of the ora files
+//!
+//! The samples are written by `resources/make_fixtures.py`.  Each has an 8x6 composite, a 4x3
+//! thumbnail, and three layers, with the top layer at half opacity.
+//!
+
+#![cfg(feature = "std")]
+
+use std::io::{Cursor, Read};
+
+use image::GenericImageView;
+use zip::ZipArchive;
+
+use skora::ora::{self, WriteOptions, ZipOraSink};
+use skora::{convert_bytes, convert_streaming, tiff_to_ora, xml, ConvertOptions};
+
+/// Classic little endian tiff
+const LITTLE_ENDIAN: &[u8] = include_bytes!("../resources/three_layers_le.tiff");
+/// Big endian BigTIFF
+const BIG_TIFF: &[u8] = include_bytes!("../resources/three_layers_bigtiff.tiff");
+
+/// Read an entry of an ora file
+fn entry(archive: &mut ZipArchive<Cursor<Vec<u8>>>, name: &str) -> Vec<u8> {
+    let mut data = Vec::new();
+    archive.by_name(name).unwrap().read_to_end(&mut data).unwrap();
+    data
+}
+
+/// Decode a png entry of an ora file
+fn png_entry(archive: &mut ZipArchive<Cursor<Vec<u8>>>, name: &str) -> image::DynamicImage {
+    image::load_from_memory(&entry(archive, name)).unwrap()
+}
+
+/// Convert a sample tiff and check the ora file it makes
+fn check_round_trip(tiff: &[u8]) {
+    let ora = convert_bytes(tiff, ConvertOptions::default()).unwrap();
+    let mut archive = ZipArchive::new(Cursor::new(ora)).unwrap();
+
+    assert_eq!(entry(&mut archive, "mimetype"), b"image/openraster");
+
+    let stack_xml = String::from_utf8(entry(&mut archive, "stack.xml")).unwrap();
+    let image = xml::parse(&stack_xml).unwrap();
+    assert_eq!(image.name, "image");
+    assert_eq!(image.get_attr("w"), Some("8"));
+    assert_eq!(image.get_attr("h"), Some("6"));
+    assert_eq!(image.children.len(), 1);
+    let stack = &image.children[0];
+    assert_eq!(stack.name, "stack");
+
+    // layers from the top down followed by the background made from the composite
+    let expected = [
+        ("data/layer0.png", "0.500000", "5", "0", (3, 2)),
+        ("data/layer1.png", "1.000000", "2", "2", (4, 3)),
+        ("data/layer2.png", "1.000000", "0", "0", (8, 6)),
+        ("data/layer3.png", "1.000000", "0", "0", (8, 6)),
+    ];
+    assert_eq!(stack.children.len(), expected.len());
+    for (layer, (src, opacity, x, y, size)) in stack.children.iter().zip(expected.iter()) {
+        assert_eq!(layer.name, "layer");
+        assert_eq!(layer.get_attr("src"), Some(*src));
+        assert_eq!(layer.get_attr("opacity"), Some(*opacity));
+        assert_eq!(layer.get_attr("x"), Some(*x));
+        assert_eq!(layer.get_attr("y"), Some(*y));
+        assert_eq!(png_entry(&mut archive, src).dimensions(), *size);
+    }
+
+    // the top layer is flipped right side up and its premultiplied alpha removed
+    let top = png_entry(&mut archive, "data/layer0.png").into_rgba8();
+    assert_eq!(top.get_pixel(0, 1).0, [153, 102, 51, 170]);
+
+    assert_eq!(png_entry(&mut archive, "mergedimage.png").dimensions(), (8, 6));
+    assert_eq!(png_entry(&mut archive, "Thumbnails/thumbnail.png").dimensions(), (4, 3));
+}
+
+#[test]
+fn little_endian_round_trip() {
+    check_round_trip(LITTLE_ENDIAN);
+}
+
+#[test]
+fn big_tiff_round_trip() {
+    check_round_trip(BIG_TIFF);
+}
+
+#[test]
+fn converted_files_are_valid() {
+    for tiff in [LITTLE_ENDIAN, BIG_TIFF].iter() {
+        let bytes = tiff_to_ora(tiff, ConvertOptions::default()).unwrap().to_bytes().unwrap();
+        assert_eq!(ora::validate(&bytes), Ok(()));
+    }
+}
+
+#[test]
+fn streamed_files_are_valid() {
+    for tiff in [LITTLE_ENDIAN, BIG_TIFF].iter() {
+        let mut bytes = Cursor::new(Vec::new());
+        let mut sink = ZipOraSink::new(&mut bytes, WriteOptions::default());
+        convert_streaming(tiff, ConvertOptions::default(), &mut sink).unwrap();
+        drop(sink);
+        assert_eq!(ora::validate(bytes.get_ref()), Ok(()));
+    }
+}
+
+#[test]
+fn layer_filter_keeps_layer_numbers() {
+    let options = ConvertOptions {
+        layer_filter: Some(vec![0, 2]),
+        ..ConvertOptions::default()
+    };
+    let image = tiff_to_ora(LITTLE_ENDIAN, options).unwrap();
+    let layers: Vec<(u8, u32, u32)> = image
+        .flat_layers()
+        .iter()
+        .map(|layer| (layer.layer_number, layer.width, layer.height))
+        .collect();
+    // the background made from the composite is always kept
+    assert_eq!(layers, vec![(0, 3, 2), (2, 8, 6), (3, 8, 6)]);
+    assert_eq!(ora::validate(&image.to_bytes().unwrap()), Ok(()));
+}
+
+#[test]
+fn layer_filter_selecting_one_layer() {
+    // the composite's CurrentLayer is 2, counting from the bottom, which is the top layer
+    for filter in [None, Some(vec![0])].iter() {
+        let options = ConvertOptions {
+            layer_filter: filter.clone(),
+            ..ConvertOptions::default()
+        };
+        let image = tiff_to_ora(LITTLE_ENDIAN, options).unwrap();
+        let selected: Vec<u8> = image
+            .flat_layers()
+            .iter()
+            .filter(|layer| layer.selected)
+            .map(|layer| layer.layer_number)
+            .collect();
+        assert_eq!(selected, vec![0]);
+
+        let stack = xml::parse(&image.stack_xml()).unwrap();
+        let marked: Vec<Option<&str>> = stack.children[0]
+            .children
+            .iter()
+            .filter(|layer| layer.get_attr("selected") == Some("true"))
+            .map(|layer| layer.get_attr("src"))
+            .collect();
+        assert_eq!(marked, vec![Some("data/layer0.png")]);
+    }
+
+    let options = ConvertOptions {
+        layer_filter: Some(vec![0]),
+        ..ConvertOptions::default()
+    };
+    let image = tiff_to_ora(LITTLE_ENDIAN, options).unwrap();
+    let layers: Vec<(u8, u32, u32)> = image
+        .flat_layers()
+        .iter()
+        .map(|layer| (layer.layer_number, layer.width, layer.height))
+        .collect();
+    assert_eq!(layers, vec![(0, 3, 2), (3, 8, 6)]);
+}
+
+#[test]
+fn embedded_source_round_trip() {
+    for tiff in [LITTLE_ENDIAN, BIG_TIFF].iter() {
+        let options = ConvertOptions {
+            write: WriteOptions {
+                embed_source: true,
+                ..WriteOptions::default()
+            },
+            ..ConvertOptions::default()
+        };
+        let bytes = convert_bytes(tiff, options).unwrap();
+        assert_eq!(ora::validate(&bytes), Ok(()));
+        let source = ora::Ora::read_embedded_source(Cursor::new(bytes)).unwrap();
+        assert_eq!(source.as_deref(), Some(*tiff));
+
+        // nothing is embedded by default
+        let bytes = convert_bytes(tiff, ConvertOptions::default()).unwrap();
+        assert_eq!(ora::Ora::read_embedded_source(Cursor::new(bytes)).unwrap(), None);
+    }
+}